@@ -0,0 +1,79 @@
+//! Reading 16 KiB and 1 MiB of plaintext into spare buffer capacity through
+//! the tokio 1.x stream; see `read_buf` for the futures 0.1 one.
+use criterion::{criterion_group, criterion_main, Criterion};
+use rust_native_rustls::tokio1::{TlsAcceptor, TlsConnector};
+use rustls::internal::pemfile::{certs, rsa_private_keys};
+use rustls::{ClientConfig, NoClientAuth, ServerConfig};
+use std::io::{BufReader, Cursor};
+use std::sync::Arc;
+use std::thread;
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::{Builder, Runtime};
+use webpki::DNSNameRef;
+
+const CHAIN: &[u8] = include_bytes!("../tests/end.chain");
+const RSA: &[u8] = include_bytes!("../tests/end.rsa");
+const CA: &[u8] = include_bytes!("../tests/ca.cert");
+
+fn configs() -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+    let chain = certs(&mut BufReader::new(Cursor::new(CHAIN))).unwrap();
+    let mut keys = rsa_private_keys(&mut BufReader::new(Cursor::new(RSA))).unwrap();
+
+    let mut server = ServerConfig::new(NoClientAuth::new());
+    server.set_single_cert(chain, keys.remove(0)).unwrap();
+
+    let mut client = ClientConfig::new();
+    client
+        .root_store
+        .add_pem_file(&mut BufReader::new(Cursor::new(CA)))
+        .unwrap();
+
+    (Arc::new(server), Arc::new(client))
+}
+
+fn runtime() -> Runtime {
+    Builder::new_current_thread().build().unwrap()
+}
+
+fn bench_read(c: &mut Criterion, name: &str, size: usize) {
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = duplex(64 * 1024);
+
+    // The server keeps sending `size` bytes at a time until the client goes
+    // away.
+    thread::spawn(move || {
+        runtime().block_on(async {
+            let acceptor = TlsAcceptor::from(server_config);
+            let mut stream = acceptor.accept(server_io).await.unwrap();
+            let payload = vec![0; size];
+            while stream.write_all(&payload).await.is_ok() {}
+        })
+    });
+
+    let runtime = runtime();
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let connector = TlsConnector::from(client_config);
+    let mut stream = runtime
+        .block_on(connector.connect(domain, client_io))
+        .unwrap();
+    let mut buf = Vec::with_capacity(size);
+
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                buf.clear();
+                while buf.len() < size {
+                    stream.read_buf(&mut buf).await.unwrap();
+                }
+            })
+        })
+    });
+}
+
+fn bench_reads(c: &mut Criterion) {
+    bench_read(c, "read 16 KiB", 16 * 1024);
+    bench_read(c, "read 1 MiB", 1024 * 1024);
+}
+
+criterion_group!(benches, bench_reads);
+criterion_main!(benches);
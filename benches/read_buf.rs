@@ -0,0 +1,94 @@
+//! Reading 16 KiB and 1 MiB of plaintext into spare `BytesMut` capacity
+//! through the futures 0.1 `read_buf`, which zeroes each chunk before the
+//! session copies into it.
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rust_native_rustls::bench::read_buf;
+use rustls::internal::pemfile::{certs, rsa_private_keys};
+use rustls::{ClientConfig, ClientSession, NoClientAuth, ServerConfig, ServerSession, Session};
+use std::io::{BufReader, Cursor, Write};
+use std::sync::Arc;
+use webpki::DNSNameRef;
+
+const CHAIN: &[u8] = include_bytes!("../tests/end.chain");
+const RSA: &[u8] = include_bytes!("../tests/end.rsa");
+const CA: &[u8] = include_bytes!("../tests/ca.cert");
+
+fn configs() -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+    let chain = certs(&mut BufReader::new(Cursor::new(CHAIN))).unwrap();
+    let mut keys = rsa_private_keys(&mut BufReader::new(Cursor::new(RSA))).unwrap();
+
+    let mut server = ServerConfig::new(NoClientAuth::new());
+    server.set_single_cert(chain, keys.remove(0)).unwrap();
+
+    let mut client = ClientConfig::new();
+    client
+        .root_store
+        .add_pem_file(&mut BufReader::new(Cursor::new(CA)))
+        .unwrap();
+
+    (Arc::new(server), Arc::new(client))
+}
+
+/// Moves all ciphertext `from` wants to send over to `to`.
+fn transfer(from: &mut dyn Session, to: &mut dyn Session) {
+    let mut buf = Vec::new();
+    while from.wants_write() {
+        from.write_tls(&mut buf).unwrap();
+    }
+    let mut rd = &buf[..];
+    while !rd.is_empty() {
+        to.read_tls(&mut rd).unwrap();
+        to.process_new_packets().unwrap();
+    }
+}
+
+/// A client session with `size` bytes of plaintext decrypted and waiting
+/// to be read.
+fn session_with_plaintext(
+    configs: &(Arc<ServerConfig>, Arc<ClientConfig>),
+    size: usize,
+) -> ClientSession {
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let mut client = ClientSession::new(&configs.1, domain);
+    let mut server = ServerSession::new(&configs.0);
+    while client.is_handshaking() || server.is_handshaking() {
+        transfer(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+    }
+
+    server.write_all(&vec![0; size]).unwrap();
+    transfer(&mut server, &mut client);
+    client
+}
+
+fn bench_read_buf(c: &mut Criterion, name: &str, size: usize) {
+    let configs = configs();
+
+    c.bench_function(name, |b| {
+        b.iter_batched(
+            || {
+                (
+                    session_with_plaintext(&configs, size),
+                    BytesMut::with_capacity(size),
+                )
+            },
+            |(mut session, mut buf)| {
+                let mut read_error = None;
+                while buf.len() < size {
+                    read_buf(&mut session, &mut read_error, &mut buf).unwrap();
+                }
+                buf
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+fn bench_read_bufs(c: &mut Criterion) {
+    bench_read_buf(c, "read_buf 16 KiB", 16 * 1024);
+    bench_read_buf(c, "read_buf 1 MiB", 1024 * 1024);
+}
+
+criterion_group!(benches, bench_read_bufs);
+criterion_main!(benches);
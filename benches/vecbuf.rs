@@ -0,0 +1,42 @@
+//! Draining large batches of small TLS records through `VecBuf`, the way
+//! `write_buf` does when the socket accepts a few kilobytes at a time.
+use bytes::Buf;
+use criterion::{criterion_group, criterion_main, Criterion};
+use iovec::IoVec;
+use rust_native_rustls::bench::vecbuf::VecBuf;
+
+const RECORD_LEN: usize = 64;
+const WRITE_LEN: usize = 4096;
+
+fn drain(records: &[&[u8]]) -> usize {
+    let mut buf = VecBuf::new(records);
+    let mut written = 0;
+
+    while buf.has_remaining() {
+        let b: &[u8] = &[0];
+        let mut dst: [&IoVec; 64] = [b.into(); 64];
+        let n = buf.bytes_vec(&mut dst);
+        let len = dst[..n]
+            .iter()
+            .map(|iovec| iovec.len())
+            .sum::<usize>()
+            .min(WRITE_LEN);
+        buf.advance(len);
+        written += len;
+    }
+    written
+}
+
+fn bench_vecbuf(c: &mut Criterion) {
+    for &count in &[64, 1024, 16384] {
+        let data = vec![0; RECORD_LEN * count];
+        let records = data.chunks(RECORD_LEN).collect::<Vec<_>>();
+
+        c.bench_function(&format!("drain {} records", count), |b| {
+            b.iter(|| drain(&records))
+        });
+    }
+}
+
+criterion_group!(benches, bench_vecbuf);
+criterion_main!(benches);
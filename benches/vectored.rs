@@ -0,0 +1,174 @@
+//! Writing a small header and body separately versus as one vectored write.
+//!
+//! The vectored write coalesces both buffers into a single TLS record and a
+//! single write to the socket. Besides the timings, each benchmark prints the
+//! writes to the socket and the records in them per iteration.
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures_executor::block_on;
+use futures_util::io::{AllowStdIo, AsyncReadExt, AsyncWriteExt};
+use rust_native_rustls::futures03::{TlsAcceptor, TlsConnector};
+use rustls::internal::pemfile::{certs, rsa_private_keys};
+use rustls::{ClientConfig, NoClientAuth, ServerConfig};
+use std::cell::Cell;
+use std::io::{self, BufReader, Cursor, IoSlice, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use webpki::DNSNameRef;
+
+const CHAIN: &[u8] = include_bytes!("../tests/end.chain");
+const RSA: &[u8] = include_bytes!("../tests/end.rsa");
+const CA: &[u8] = include_bytes!("../tests/ca.cert");
+
+const HEADER: &[u8] = &[0; 8];
+const BODY: &[u8] = &[0; 256];
+
+fn configs() -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+    let chain = certs(&mut BufReader::new(Cursor::new(CHAIN))).unwrap();
+    let mut keys = rsa_private_keys(&mut BufReader::new(Cursor::new(RSA))).unwrap();
+
+    let mut server = ServerConfig::new(NoClientAuth::new());
+    server.set_single_cert(chain, keys.remove(0)).unwrap();
+
+    let mut client = ClientConfig::new();
+    client
+        .root_store
+        .add_pem_file(&mut BufReader::new(Cursor::new(CA)))
+        .unwrap();
+
+    (Arc::new(server), Arc::new(client))
+}
+
+#[derive(Default)]
+struct Counts {
+    writes: Cell<usize>,
+    records: Cell<usize>,
+}
+
+/// Counts the writes to `io` and the TLS records they carry.
+struct Counting<IO> {
+    io: IO,
+    counts: Rc<Counts>,
+    // The start of a record header split across writes.
+    header: Vec<u8>,
+    // The bytes left of the current record's payload.
+    remaining: usize,
+}
+
+impl<IO> Counting<IO> {
+    fn scan(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(data.len());
+                self.remaining -= n;
+                data = &data[n..];
+                continue;
+            }
+
+            let n = (5 - self.header.len()).min(data.len());
+            self.header.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.header.len() == 5 {
+                self.remaining = u16::from_be_bytes([self.header[3], self.header[4]]) as usize;
+                self.header.clear();
+                self.counts.records.set(self.counts.records.get() + 1);
+            }
+        }
+    }
+}
+
+impl<IO: Read> Read for Counting<IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io.read(buf)
+    }
+}
+
+impl<IO: Write> Write for Counting<IO> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.io.write(buf)?;
+        self.counts.writes.set(self.counts.writes.get() + 1);
+        self.scan(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+/// Runs `f` as a benchmark and prints the writes and records per iteration.
+fn bench_counted<F>(c: &mut Criterion, name: &str, counts: &Counts, mut f: F)
+where
+    F: FnMut(),
+{
+    counts.writes.set(0);
+    counts.records.set(0);
+    let mut iterations = 0;
+
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            f();
+            iterations += 1;
+        })
+    });
+
+    println!(
+        "{}: {:.1} writes and {:.1} records per iteration",
+        name,
+        counts.writes.get() as f64 / iterations as f64,
+        counts.records.get() as f64 / iterations as f64,
+    );
+}
+
+fn bench_writes(c: &mut Criterion) {
+    let (server_config, client_config) = configs();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client_io = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server_io, _) = listener.accept().unwrap();
+
+    // The server discards everything until the client goes away.
+    thread::spawn(move || {
+        block_on(async {
+            let acceptor = TlsAcceptor::from(server_config);
+            let mut stream = acceptor.accept(AllowStdIo::new(server_io)).await.unwrap();
+            let mut buf = vec![0; 64 * 1024];
+            while let Ok(n) = stream.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        })
+    });
+
+    let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+    let connector = TlsConnector::from(client_config);
+    let counts = Rc::new(Counts::default());
+    let client_io = Counting {
+        io: client_io,
+        counts: counts.clone(),
+        header: Vec::new(),
+        remaining: 0,
+    };
+    let mut stream = block_on(connector.connect(domain, AllowStdIo::new(client_io))).unwrap();
+
+    bench_counted(c, "header and body as two writes", &counts, || {
+        block_on(async {
+            stream.write_all(HEADER).await.unwrap();
+            stream.write_all(BODY).await.unwrap();
+            stream.flush().await.unwrap();
+        })
+    });
+
+    bench_counted(c, "header and body as one vectored write", &counts, || {
+        block_on(async {
+            let bufs = [IoSlice::new(HEADER), IoSlice::new(BODY)];
+            let n = stream.write_vectored(&bufs).await.unwrap();
+            assert_eq!(n, HEADER.len() + BODY.len());
+            stream.flush().await.unwrap();
+        })
+    });
+}
+
+criterion_group!(benches, bench_writes);
+criterion_main!(benches);
@@ -1,22 +1,37 @@
 use super::tokio_tls;
+use super::tokio_tls::callback::{Verdict, Verification};
 use super::tokio_tls::client;
 use super::tokio_tls::common::Stream;
+use super::tokio_tls::cork::Cork;
 use super::tokio_tls::entry;
+use super::tokio_tls::expiry::CertExpiry;
+use super::tokio_tls::observer::Observer;
+use super::tokio_tls::pin::PinSet;
 use super::tokio_tls::server;
+use super::tokio_tls::stats::Stats;
+use super::tokio_tls::tofu::KnownHosts;
+use super::tokio_tls::trace::ConnSpan;
 use crate::tokio_tls::server::MidHandshake;
 use core::fmt::Pointer;
 use futures::{Async, Future, Poll};
-use rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession};
+use crate::ErrorKind;
+use rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession, TLSError};
 use std::error;
 use std::fmt;
-use std::io::{Error, ErrorKind};
 use std::result;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{io, mem};
 use webpki::DNSNameRef;
 
 pub struct Error(io::Error);
 
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        tokio_tls::error::kind(&self.0)
+    }
+}
+
 impl error::Error for Error {
     fn description(&self) -> &str {
         error::Error::description(&self.0)
@@ -25,6 +40,13 @@ impl error::Error for Error {
     fn cause(&self) -> Option<&error::Error> {
         error::Error::cause(&self.0)
     }
+
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match self.0.get_ref() {
+            Some(err) => Some(err),
+            None => Some(&self.0),
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -70,9 +92,12 @@ where
         match self {
             MidHandshakeTlsStream::Server(s) => {
                 if let MidHandshake::Handshaking(ref mut stream) = s {
+                    let _span = stream.span.enter();
                     let state = stream.state;
-                    let (io, session) = stream.get_mut();
-                    let mut stream = Stream::new(io, session).set_eof(!state.readable());
+                    let mut stream = Stream::new(&mut stream.io, &mut stream.session)
+                        .set_eof(!state.readable())
+                        .set_stats(&mut stream.stats)
+                        .set_observer(&stream.observer);
 
                     if stream.session.is_handshaking() {
                         match stream.complete_io() {
@@ -101,9 +126,12 @@ where
             }
             MidHandshakeTlsStream::Client(s) => {
                 if let client::MidHandshake::Handshaking(ref mut stream) = s {
+                    let _span = stream.span.enter();
                     let state = stream.state;
-                    let (io, session) = stream.get_mut();
-                    let mut stream = Stream::new(io, session).set_eof(!state.readable());
+                    let mut stream = Stream::new(&mut stream.io, &mut stream.session)
+                        .set_eof(!state.readable())
+                        .set_stats(&mut stream.stats)
+                        .set_observer(&stream.observer);
 
                     if stream.session.is_handshaking() {
                         match stream.complete_io() {
@@ -137,6 +165,36 @@ where
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Certificate(rustls::Certificate);
+
+impl Certificate {
+    pub fn from_der(der: &[u8]) -> Result<Certificate, Error> {
+        webpki::EndEntityCert::from(der)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, TLSError::WebPKIError(err)))?;
+        Ok(Certificate(rustls::Certificate(der.to_vec())))
+    }
+
+    pub fn from_pem(pem: &[u8]) -> Result<Certificate, Error> {
+        let certs = rustls::internal::pemfile::certs(&mut io::Cursor::new(pem))
+            .map_err(|()| io::Error::new(io::ErrorKind::InvalidData, "invalid PEM certificate"))?;
+        match certs.first() {
+            Some(cert) => Certificate::from_der(&cert.0),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "no PEM certificate found").into()),
+        }
+    }
+
+    pub fn to_der(&self) -> Result<Vec<u8>, Error> {
+        Ok((self.0).0.clone())
+    }
+}
+
+impl From<rustls::Certificate> for Certificate {
+    fn from(cert: rustls::Certificate) -> Certificate {
+        Certificate(cert)
+    }
+}
+
 pub struct TlsConnector {
     connector: entry::TlsConnector,
 }
@@ -149,12 +207,74 @@ impl TlsConnectorBuilder {
             Identity::Client(s) => Ok(TlsConnector {
                 connector: entry::TlsConnector::from(s),
             }),
-            _ => Err(io::Error::new(ErrorKind::Other, "oh no!")),
+            _ => Err(io::Error::new(io::ErrorKind::Other, "oh no!")),
         }
     }
 }
 
 impl TlsConnector {
+    pub fn handshake_timeout(self, timeout: Duration) -> TlsConnector {
+        TlsConnector {
+            connector: self.connector.handshake_timeout(timeout),
+        }
+    }
+
+    pub fn get_handshake_timeout(&self) -> Option<Duration> {
+        self.connector.handshake_timeout
+    }
+
+    pub fn buffer_limit(self, limit: usize) -> TlsConnector {
+        TlsConnector {
+            connector: self.connector.buffer_limit(limit),
+        }
+    }
+
+    pub fn observer(self, observer: Arc<dyn Observer>) -> TlsConnector {
+        TlsConnector {
+            connector: self.connector.observer(observer),
+        }
+    }
+
+    pub fn certificate(self, cert: &rustls::Certificate) -> Result<TlsConnector, Error> {
+        Ok(TlsConnector {
+            connector: self.connector.certificate(cert)?,
+        })
+    }
+
+    pub fn expiry_warning<F>(self, threshold: Duration, callback: F) -> TlsConnector
+    where
+        F: Fn(&CertExpiry) + Send + Sync + 'static,
+    {
+        TlsConnector {
+            connector: self.connector.expiry_warning(threshold, callback),
+        }
+    }
+
+    pub fn cert_expiry(&self) -> Option<CertExpiry> {
+        self.connector.cert_expiry
+    }
+
+    pub fn pins(self, pins: PinSet) -> TlsConnector {
+        TlsConnector {
+            connector: self.connector.pins(pins),
+        }
+    }
+
+    pub fn known_hosts(self, hosts: Arc<KnownHosts>) -> TlsConnector {
+        TlsConnector {
+            connector: self.connector.known_hosts(hosts),
+        }
+    }
+
+    pub fn verify_callback<F>(self, callback: F) -> TlsConnector
+    where
+        F: Fn(&Verification) -> Verdict + Send + Sync + 'static,
+    {
+        TlsConnector {
+            connector: self.connector.verify_callback(callback),
+        }
+    }
+
     pub fn connect<S>(
         &self,
         domain: &str,
@@ -170,10 +290,18 @@ impl TlsConnector {
             ))
         })?;
         let mut session = ClientSession::new(&self.connector.inner, domain);
+        self.connector.limit_buffers(&mut session);
+        self.connector.warn_expiry();
         MidHandshakeTlsStream::Client(client::MidHandshake::Handshaking(client::TlsStream {
             session,
             io: stream,
             state: entry::TlsState::Stream,
+            close: entry::PeerClose::new(self.connector.strict_close),
+            cork: Cork::new(self.connector.cork_limit, self.connector.cork_delay),
+            stats: Stats::new(),
+            observer: self.connector.observer.start_stream(),
+            span: ConnSpan::client(domain),
+            read_error: None,
         }))
         .handshake()
     }
@@ -184,16 +312,78 @@ pub struct TlsAcceptor {
 }
 
 impl TlsAcceptor {
+    pub fn handshake_timeout(self, timeout: Duration) -> TlsAcceptor {
+        TlsAcceptor {
+            acceptor: self.acceptor.handshake_timeout(timeout),
+        }
+    }
+
+    pub fn get_handshake_timeout(&self) -> Option<Duration> {
+        self.acceptor.handshake_timeout
+    }
+
+    pub fn buffer_limit(self, limit: usize) -> TlsAcceptor {
+        TlsAcceptor {
+            acceptor: self.acceptor.buffer_limit(limit),
+        }
+    }
+
+    pub fn observer(self, observer: Arc<dyn Observer>) -> TlsAcceptor {
+        TlsAcceptor {
+            acceptor: self.acceptor.observer(observer),
+        }
+    }
+
+    pub fn certificate_from_handshake(self) -> Result<TlsAcceptor, Error> {
+        Ok(TlsAcceptor {
+            acceptor: self.acceptor.certificate_from_handshake()?,
+        })
+    }
+
+    pub fn expiry_warning<F>(self, threshold: Duration, callback: F) -> TlsAcceptor
+    where
+        F: Fn(&CertExpiry) + Send + Sync + 'static,
+    {
+        TlsAcceptor {
+            acceptor: self.acceptor.expiry_warning(threshold, callback),
+        }
+    }
+
+    pub fn cert_expiry(&self) -> Option<CertExpiry> {
+        self.acceptor.cert_expiry
+    }
+
+    pub fn verify_callback<F>(self, callback: F) -> TlsAcceptor
+    where
+        F: Fn(&Verification) -> Verdict + Send + Sync + 'static,
+    {
+        TlsAcceptor {
+            acceptor: self.acceptor.verify_callback(callback),
+        }
+    }
+
+    pub fn check_expiry(&self) -> Result<(), Error> {
+        self.acceptor.check_expiry().map_err(Error)
+    }
+
     pub fn accept<S>(&self, stream: S) -> MidHandshakeTlsStream<S>
     where
         S: io::Read + io::Write,
     {
         let mut session = ServerSession::new(&self.acceptor.inner);
+        self.acceptor.limit_buffers(&mut session);
+        self.acceptor.warn_expiry();
 
         MidHandshakeTlsStream::Server(server::MidHandshake::Handshaking(server::TlsStream {
             session,
             io: stream,
             state: entry::TlsState::Stream,
+            close: entry::PeerClose::new(self.acceptor.strict_close),
+            cork: Cork::new(self.acceptor.cork_limit, self.acceptor.cork_delay),
+            stats: Stats::new(),
+            observer: self.acceptor.observer.start_stream(),
+            span: ConnSpan::server(),
+            read_error: None,
         }))
         .handshake()
     }
@@ -207,7 +397,7 @@ impl TlsAcceptorBuilder {
             Identity::Server(s) => Ok(TlsAcceptor {
                 acceptor: (entry::TlsAcceptor::from(s)),
             }),
-            _ => Err(io::Error::new(ErrorKind::Other, "oh no!")),
+            _ => Err(io::Error::new(io::ErrorKind::Other, "oh no!")),
         }
     }
 }
@@ -6,27 +6,132 @@ mod imple;
 
 mod tokio_tls;
 
+#[cfg(feature = "async-std")]
+pub use tokio_tls::async_std;
+pub use tokio_tls::callback;
+pub use tokio_tls::callback::Verdict;
+pub use tokio_tls::completion;
+pub use tokio_tls::engine;
+pub use tokio_tls::error::ErrorKind;
+#[cfg(feature = "mio")]
+pub use tokio_tls::evented;
+pub use tokio_tls::expiry;
+#[cfg(feature = "futures03")]
+pub use tokio_tls::futures03;
+pub use tokio_tls::metrics;
+pub use tokio_tls::observer;
+pub use tokio_tls::pin;
+pub use tokio_tls::tofu;
+#[cfg(feature = "tokio1")]
+pub use tokio_tls::tokio1;
+pub use tokio_tls::verify;
+
+/// Internals the benchmarks measure. Not part of the API.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::tokio_tls::common::read_buf;
+    pub use crate::tokio_tls::vecbuf;
+}
+
 extern crate tokio_io;
 use tokio_io::{try_nb, AsyncRead, AsyncWrite};
 
 use futures::{Future, Poll};
 use std::any::Any;
+use std::cmp;
 use std::error;
 use std::fmt;
 use std::io;
+use std::net::TcpStream;
 use std::result;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio_tls::client;
 use tokio_tls::server;
 use webpki::DNSNameRef;
 
 pub struct Identity(imp::Identity);
 
+/// An X509 certificate.
+#[derive(Debug, Clone)]
+pub struct Certificate(imp::Certificate);
+
+impl Certificate {
+    /// Parses a DER-formatted X509 certificate.
+    pub fn from_der(der: &[u8]) -> Result<Certificate> {
+        Ok(Certificate(imp::Certificate::from_der(der)?))
+    }
+
+    /// Parses the first PEM-formatted X509 certificate in `pem`.
+    pub fn from_pem(pem: &[u8]) -> Result<Certificate> {
+        Ok(Certificate(imp::Certificate::from_pem(pem)?))
+    }
+
+    /// Returns the DER-encoded representation of this certificate.
+    pub fn to_der(&self) -> Result<Vec<u8>> {
+        Ok(self.0.to_der()?)
+    }
+}
+
+/// A certificate chain presented by the peer, as passed to a verification
+/// callback.
+#[derive(Debug)]
+pub struct CertificateVerification<'a> {
+    /// The presented certificates, leaf first.
+    pub chain: &'a [Certificate],
+
+    /// The name the client connects to, or `None` when verifying a client
+    /// certificate.
+    pub server_name: Option<&'a str>,
+
+    /// The stapled OCSP response, empty if there is none.
+    pub ocsp_response: &'a [u8],
+
+    /// The result of the default verification.
+    pub default_result: &'a Result<()>,
+}
+
+/// Hands a chain to a facade verification callback, converting the
+/// certificates and the default result to the facade's types.
+fn verify_with<F>(callback: &F, verification: &callback::Verification) -> Verdict
+where
+    F: Fn(&CertificateVerification) -> Verdict,
+{
+    let chain: Vec<Certificate> = verification
+        .chain
+        .iter()
+        .map(|cert| Certificate(imp::Certificate::from(cert.clone())))
+        .collect();
+    let default_result = verification
+        .default_result
+        .clone()
+        .map_err(|err| Error::from(verify::peek_io_error(err)));
+    callback(&CertificateVerification {
+        chain: &chain,
+        server_name: verification.server_name,
+        ocsp_response: verification.ocsp_response,
+        default_result: &default_result,
+    })
+}
+
 /// A typedef of the result-type returned by many methods.
 pub type Result<T> = result::Result<T, Error>;
 
 /// An error returned from the TLS implementation.
 pub struct Error(imp::Error);
 
+impl Error {
+    /// Returns the category this error falls into.
+    ///
+    /// This allows callers to tell apart, for example, an expired
+    /// certificate from a hostname mismatch without inspecting the error
+    /// message.
+    pub fn kind(&self) -> ErrorKind {
+        self.0.kind()
+    }
+}
+
 impl error::Error for Error {
     fn description(&self) -> &str {
         error::Error::description(&self.0)
@@ -35,6 +140,10 @@ impl error::Error for Error {
     fn cause(&self) -> Option<&error::Error> {
         error::Error::cause(&self.0)
     }
+
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        error::Error::source(&self.0)
+    }
 }
 
 impl fmt::Display for Error {
@@ -55,6 +164,12 @@ impl From<imp::Error> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error(imp::Error::from(err))
+    }
+}
+
 /// An error returned from `ClientBuilder::handshake`.
 #[derive(Debug)]
 pub enum HandshakeError<S> {
@@ -103,20 +218,26 @@ impl<S> From<imp::HandshakeError<S>> for HandshakeError<S> {
         match e {
             imp::HandshakeError::Failure(e) => HandshakeError::Failure(Error(e)),
             imp::HandshakeError::WouldBlock(s) => {
-                HandshakeError::WouldBlock(MidHandshakeTlsStream(s))
+                HandshakeError::WouldBlock(MidHandshakeTlsStream {
+                    inner: s,
+                    deadline: None,
+                })
             }
         }
     }
 }
 
-pub struct MidHandshakeTlsStream<S>(imp::MidHandshakeTlsStream<S>);
+pub struct MidHandshakeTlsStream<S> {
+    inner: imp::MidHandshakeTlsStream<S>,
+    deadline: Option<Instant>,
+}
 
 impl<S> fmt::Debug for MidHandshakeTlsStream<S>
 where
     S: fmt::Debug,
 {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Debug::fmt(&self.0, fmt)
+        fmt::Debug::fmt(&self.inner, fmt)
     }
 }
 
@@ -131,14 +252,109 @@ where
     /// Note that the error may not be fatal. For example if the underlying
     /// stream is an asynchronous one then `HandshakeError::WouldBlock` may
     /// just mean to wait for more I/O to happen later.
+    ///
+    /// Once the configured handshake timeout has elapsed, a handshake that
+    /// would block again fails with a `TimedOut` error instead.
     pub fn handshake(self) -> result::Result<TlsStream<S>, HandshakeError<S>> {
-        match self.0.handshake() {
-            Ok(s) => Ok(TlsStream(s)),
-            Err(e) => Err(e.into()),
+        handshake_step(self.inner.handshake(), self.deadline)
+    }
+}
+
+/// Hands back a handshake that would block, unless `deadline` has passed,
+/// in which case it fails with a `TimedOut` error and the stream is
+/// dropped.
+fn handshake_step<S>(
+    result: result::Result<imp::TlsStream<S>, imp::HandshakeError<S>>,
+    deadline: Option<Instant>,
+) -> result::Result<TlsStream<S>, HandshakeError<S>> {
+    match result {
+        Ok(s) => Ok(TlsStream(s)),
+        Err(imp::HandshakeError::WouldBlock(inner)) => {
+            if deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                return Err(HandshakeError::Failure(
+                    tokio_tls::error::handshake_timed_out().into(),
+                ));
+            }
+            Err(HandshakeError::WouldBlock(MidHandshakeTlsStream {
+                inner,
+                deadline,
+            }))
         }
+        Err(e) => Err(e.into()),
     }
 }
 
+/// Drives a blocking handshake on a `TcpStream` to completion, giving up
+/// once `timeout` elapses.
+///
+/// Before each step of the handshake, the socket's read and write timeouts
+/// are set to the time left, so that a silent peer makes the step end with
+/// `WouldBlock` (or `TimedOut`, depending on the platform) around the
+/// deadline, which `handshake_step` then turns into a timeout. A peer that
+/// keeps sending a byte now and then can stretch a single step past the
+/// deadline; it is noticed once the step ends. The socket's own timeouts are
+/// restored once the handshake completes.
+fn handshake_timeout<F>(
+    stream: TcpStream,
+    timeout: Option<Duration>,
+    start: F,
+) -> result::Result<TlsStream<TcpStream>, HandshakeError<TcpStream>>
+where
+    F: FnOnce(TcpStream) -> result::Result<TlsStream<TcpStream>, HandshakeError<TcpStream>>,
+{
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return start(stream),
+    };
+
+    let socket = stream
+        .try_clone()
+        .map_err(|err| HandshakeError::Failure(err.into()))?;
+    let restore = (|| Ok((socket.read_timeout()?, socket.write_timeout()?)))()
+        .map_err(|err: io::Error| HandshakeError::Failure(err.into()))?;
+    set_timeouts(&socket, timeout)?;
+    let deadline = Instant::now() + timeout;
+
+    let mut result = start(stream);
+    loop {
+        result = match result {
+            Err(HandshakeError::WouldBlock(mid)) => {
+                // The step would have failed had the deadline passed, but
+                // it may do so before the timeouts are set; a zero timeout
+                // is invalid.
+                let left = mid
+                    .deadline
+                    .unwrap_or(deadline)
+                    .saturating_duration_since(Instant::now());
+                set_timeouts(&socket, cmp::max(left, Duration::from_millis(1)))?;
+                mid.handshake()
+            }
+            Err(HandshakeError::Failure(ref err))
+                if err.kind() == ErrorKind::Io && Instant::now() >= deadline =>
+            {
+                return Err(HandshakeError::Failure(
+                    tokio_tls::error::handshake_timed_out().into(),
+                ));
+            }
+            Ok(stream) => {
+                socket
+                    .set_read_timeout(restore.0)
+                    .and_then(|()| socket.set_write_timeout(restore.1))
+                    .map_err(|err| HandshakeError::Failure(err.into()))?;
+                return Ok(stream);
+            }
+            result => return result,
+        };
+    }
+}
+
+fn set_timeouts<S>(socket: &TcpStream, timeout: Duration) -> result::Result<(), HandshakeError<S>> {
+    socket
+        .set_read_timeout(Some(timeout))
+        .and_then(|()| socket.set_write_timeout(Some(timeout)))
+        .map_err(|err| HandshakeError::Failure(err.into()))
+}
+
 pub struct TlsConnector(imp::TlsConnector);
 
 impl TlsConnector {
@@ -148,6 +364,89 @@ impl TlsConnector {
         ))
     }
 
+    /// Limits how long the handshake may take.
+    ///
+    /// `connect` and `MidHandshakeTlsStream::handshake` check the deadline
+    /// whenever the handshake would block, failing with a `TimedOut` error
+    /// once it has passed; a blocking stream without timeouts of its own is
+    /// never interrupted. `connect_timeout` sets those timeouts on a
+    /// `TcpStream`.
+    pub fn handshake_timeout(self, timeout: Duration) -> TlsConnector {
+        TlsConnector(self.0.handshake_timeout(timeout))
+    }
+
+    /// Limits how much unsent data the stream buffers.
+    ///
+    /// Once the limit is reached, writes fail with `WouldBlock` until the
+    /// socket accepts more data, instead of queueing more in memory.
+    pub fn buffer_limit(self, limit: usize) -> TlsConnector {
+        TlsConnector(self.0.buffer_limit(limit))
+    }
+
+    /// Reports the lifecycle of each stream to `observer`.
+    pub fn observer(self, observer: Arc<dyn observer::Observer>) -> TlsConnector {
+        TlsConnector(self.0.observer(observer))
+    }
+
+    /// Gives the client certificate of the identity, for `cert_expiry` and
+    /// `expiry_warning`.
+    pub fn certificate(self, cert: &Certificate) -> Result<TlsConnector> {
+        let cert = rustls::Certificate(cert.to_der()?);
+        Ok(TlsConnector(self.0.certificate(&cert)?))
+    }
+
+    /// Calls `callback` once the client certificate is within `threshold` of
+    /// expiring. This is checked now and on every `connect`.
+    ///
+    /// # Panics
+    ///
+    /// If the certificate was not given with `certificate` first.
+    pub fn expiry_warning<F>(self, threshold: Duration, callback: F) -> TlsConnector
+    where
+        F: Fn(&expiry::CertExpiry) + Send + Sync + 'static,
+    {
+        TlsConnector(self.0.expiry_warning(threshold, callback))
+    }
+
+    /// When the client certificate expires, if it was given with
+    /// `certificate`.
+    pub fn cert_expiry(&self) -> Option<expiry::CertExpiry> {
+        self.0.cert_expiry()
+    }
+
+    /// Verifies server certificates against a set of public key pins, in
+    /// addition to or instead of the usual validation depending on the
+    /// set's `pin::PinMode`. Chains no pin matches fail with
+    /// `ErrorKind::PinMismatch`.
+    pub fn pins(self, pins: pin::PinSet) -> TlsConnector {
+        TlsConnector(self.0.pins(pins))
+    }
+
+    /// Trusts each server's certificate on first use and records its public
+    /// key in `hosts`, instead of validating it against the root store.
+    /// Later connections to a host presenting a different key fail with
+    /// `ErrorKind::KnownHostChanged`.
+    pub fn known_hosts(self, hosts: Arc<tofu::KnownHosts>) -> TlsConnector {
+        TlsConnector(self.0.known_hosts(hosts))
+    }
+
+    /// Passes each server certificate chain, along with the result of the
+    /// default verification, to `callback`, which can keep that result,
+    /// accept the chain anyway or reject it with a reason. Rejected chains
+    /// fail with `ErrorKind::CertificateRejected`.
+    ///
+    /// The default verification includes `pins` and `known_hosts` if they
+    /// were set up before this.
+    pub fn verify_callback<F>(self, callback: F) -> TlsConnector
+    where
+        F: Fn(&CertificateVerification) -> Verdict + Send + Sync + 'static,
+    {
+        TlsConnector(
+            self.0
+                .verify_callback(move |verification| verify_with(&callback, verification)),
+        )
+    }
+
     /// Initiates a TLS handshake.
     ///
     /// The provided domain will be used for both SNI and certificate hostname
@@ -160,6 +459,9 @@ impl TlsConnector {
     ///
     /// The domain is ignored if both SNI and hostname verification are
     /// disabled.
+    ///
+    /// A configured `handshake_timeout` is checked whenever the handshake
+    /// would block.
     pub fn connect<S>(
         &self,
         domain: &str,
@@ -168,23 +470,127 @@ impl TlsConnector {
     where
         S: io::Read + io::Write,
     {
-        let s = self.0.connect(domain, stream)?;
-        Ok(TlsStream(s))
+        let deadline = self.0.get_handshake_timeout().map(|t| Instant::now() + t);
+        handshake_step(self.0.connect(domain, stream), deadline)
+    }
+
+    /// Initiates a TLS handshake over a blocking `TcpStream`, giving up once
+    /// the configured handshake timeout elapses. The socket's read and write
+    /// timeouts bound each step of the handshake by the time left.
+    ///
+    /// Without a configured timeout this behaves like `connect`.
+    pub fn connect_timeout(
+        &self,
+        domain: &str,
+        stream: TcpStream,
+    ) -> result::Result<TlsStream<TcpStream>, HandshakeError<TcpStream>> {
+        handshake_timeout(stream, self.0.get_handshake_timeout(), |stream| {
+            self.connect(domain, stream)
+        })
     }
 }
 
 pub struct TlsAcceptor(imp::TlsAcceptor);
 
 impl TlsAcceptor {
+    /// Creates an acceptor for `identity`.
+    ///
+    /// Fails with `ErrorKind::CertificateExpired` if the identity's
+    /// certificate has already expired; see `new_allow_expired`.
     pub fn new(identity: imp::Identity) -> Result<TlsAcceptor> {
-        Ok(TlsAcceptor(imp::TlsAcceptorBuilder::new(identity).unwrap()))
+        let acceptor = TlsAcceptor::new_allow_expired(identity)?;
+        acceptor.0.check_expiry()?;
+        Ok(acceptor)
+    }
+
+    /// Creates an acceptor for `identity` even if its certificate has
+    /// already expired.
+    ///
+    /// The certificate is the one the identity serves to a client sending no
+    /// server name, found with a handshake in memory, which also checks that
+    /// the identity holds its key.
+    pub fn new_allow_expired(identity: imp::Identity) -> Result<TlsAcceptor> {
+        Ok(TlsAcceptor(
+            imp::TlsAcceptorBuilder::new(identity)?.certificate_from_handshake()?,
+        ))
+    }
+
+    /// Limits how long the handshake may take; see
+    /// `TlsConnector::handshake_timeout` for how it is enforced.
+    pub fn handshake_timeout(self, timeout: Duration) -> TlsAcceptor {
+        TlsAcceptor(self.0.handshake_timeout(timeout))
+    }
+
+    /// Limits how much unsent data the stream buffers.
+    ///
+    /// Once the limit is reached, writes fail with `WouldBlock` until the
+    /// socket accepts more data, instead of queueing more in memory.
+    pub fn buffer_limit(self, limit: usize) -> TlsAcceptor {
+        TlsAcceptor(self.0.buffer_limit(limit))
+    }
+
+    /// Reports the lifecycle of each stream to `observer`.
+    pub fn observer(self, observer: Arc<dyn observer::Observer>) -> TlsAcceptor {
+        TlsAcceptor(self.0.observer(observer))
+    }
+
+    /// Calls `callback` once the server certificate is within `threshold` of
+    /// expiring. This is checked now and on every `accept`.
+    pub fn expiry_warning<F>(self, threshold: Duration, callback: F) -> TlsAcceptor
+    where
+        F: Fn(&expiry::CertExpiry) + Send + Sync + 'static,
+    {
+        TlsAcceptor(self.0.expiry_warning(threshold, callback))
     }
 
+    /// When the server certificate expires.
+    pub fn cert_expiry(&self) -> Option<expiry::CertExpiry> {
+        self.0.cert_expiry()
+    }
+
+    /// Passes each client certificate chain, along with the result of the
+    /// default verification, to `callback`; see
+    /// `TlsConnector::verify_callback`.
+    ///
+    /// Client certificates are only requested if the identity's
+    /// configuration asks for them.
+    pub fn verify_callback<F>(self, callback: F) -> TlsAcceptor
+    where
+        F: Fn(&CertificateVerification) -> Verdict + Send + Sync + 'static,
+    {
+        TlsAcceptor(
+            self.0
+                .verify_callback(move |verification| verify_with(&callback, verification)),
+        )
+    }
+
+    /// Starts accepting a TLS handshake.
+    ///
+    /// A configured `handshake_timeout` is checked whenever
+    /// `MidHandshakeTlsStream::handshake` would block.
     pub fn accept<S>(&self, stream: S) -> MidHandshakeTlsStream<S>
     where
         S: AsyncRead + AsyncWrite,
     {
-        MidHandshakeTlsStream(self.0.accept(stream))
+        MidHandshakeTlsStream {
+            inner: self.0.accept(stream),
+            deadline: self.0.get_handshake_timeout().map(|t| Instant::now() + t),
+        }
+    }
+
+    /// Accepts a TLS handshake over a blocking `TcpStream`, giving up once
+    /// the configured handshake timeout elapses; see `connect_timeout`.
+    pub fn accept_timeout(
+        &self,
+        stream: TcpStream,
+    ) -> result::Result<TlsStream<TcpStream>, HandshakeError<TcpStream>> {
+        handshake_timeout(stream, self.0.get_handshake_timeout(), |stream| {
+            MidHandshakeTlsStream {
+                inner: self.0.accept(stream),
+                deadline: self.0.get_handshake_timeout().map(|t| Instant::now() + t),
+            }
+            .handshake()
+        })
     }
 }
 
@@ -0,0 +1,33 @@
+//! async-std support.
+//!
+//! async-std's sockets implement the futures 0.3 IO traits, so the streams
+//! here are the `futures03` ones, driven by the shared `common::Stream` I/O
+//! loop.
+pub use super::futures03::{Accept, Connect, TlsAcceptor, TlsConnector};
+
+use futures_io::{AsyncRead, AsyncWrite};
+use rustls::{ClientConfig, ServerConfig};
+use std::sync::Arc;
+use webpki::DNSNameRef;
+
+/// Starts a client handshake over `stream`, resolving to a `TlsStream` once
+/// it completes.
+///
+/// This is a shorthand for `TlsConnector::from(config).connect(domain, stream)`.
+pub fn connect<IO>(config: Arc<ClientConfig>, domain: DNSNameRef, stream: IO) -> Connect<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    TlsConnector::from(config).connect(domain, stream)
+}
+
+/// Starts a server handshake over `stream`, resolving to a `TlsStream` once
+/// it completes.
+///
+/// This is a shorthand for `TlsAcceptor::from(config).accept(stream)`.
+pub fn accept<IO>(config: Arc<ServerConfig>, stream: IO) -> Accept<IO>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    TlsAcceptor::from(config).accept(stream)
+}
@@ -0,0 +1,393 @@
+//! Certificate verification callbacks.
+//!
+//! A callback sees every certificate chain the peer presents along with the
+//! result of the configuration's own verifier, and decides whether to keep
+//! that result, accept the chain anyway or reject it. It is layered on top
+//! of the existing verifier, so it also sees the outcome of `pins` or
+//! `known_hosts` if they were set up first.
+use super::verify::{self, VerifyError};
+use rustls::{
+    Certificate, ClientCertVerified, ClientCertVerifier, ClientConfig, DistinguishedNames,
+    RootCertStore, ServerCertVerified, ServerCertVerifier, ServerConfig, TLSError,
+};
+use std::sync::Arc;
+use webpki::DNSNameRef;
+
+/// What a verification callback decides about a chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Go with the result of the configuration's verifier.
+    Default,
+    /// Accept the chain, even if the configuration's verifier rejected it.
+    Accept,
+    /// Reject the chain for the given reason, even if the configuration's
+    /// verifier accepted it. The stream fails with `VerifyError::Rejected`.
+    Reject(String),
+}
+
+/// A chain presented by the peer.
+#[derive(Debug)]
+pub struct Verification<'a> {
+    /// The presented certificates, leaf first.
+    pub chain: &'a [Certificate],
+    /// The name the client connects to, or `None` when verifying a client
+    /// certificate.
+    pub server_name: Option<&'a str>,
+    /// The stapled OCSP response, empty if there is none.
+    pub ocsp_response: &'a [u8],
+    /// What the configuration's verifier made of the chain.
+    pub default_result: &'a Result<(), TLSError>,
+}
+
+pub type Callback = dyn Fn(&Verification) -> Verdict + Send + Sync;
+
+fn decide(callback: &Callback, verification: &Verification) -> Result<(), TLSError> {
+    match callback(verification) {
+        Verdict::Default => verification.default_result.clone(),
+        Verdict::Accept => {
+            // The default result may have recorded a failure; it no longer
+            // describes the session.
+            verify::clear();
+            if verification.default_result.is_err() {
+                tls_event!(
+                    warn,
+                    error = ?verification.default_result,
+                    "TLS certificate accepted by the verification callback"
+                );
+            }
+            Ok(())
+        }
+        Verdict::Reject(reason) => Err(verify::fail(VerifyError::Rejected(reason))),
+    }
+}
+
+struct ServerCallback {
+    /// The configuration whose verifier produces the default result.
+    original: Arc<ClientConfig>,
+    callback: Arc<Callback>,
+}
+
+impl ServerCertVerifier for ServerCallback {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented: &[Certificate],
+        dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        verify::clear();
+        let default_result = self
+            .original
+            .get_verifier()
+            .verify_server_cert(roots, presented, dns_name, ocsp_response)
+            .map(|_| ());
+        let verification = Verification {
+            chain: presented,
+            server_name: Some(dns_name.into()),
+            ocsp_response,
+            default_result: &default_result,
+        };
+        decide(&*self.callback, &verification)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+struct ClientCallback {
+    /// The configuration whose verifier produces the default result and
+    /// decides whether to ask for client certificates at all.
+    original: Arc<ServerConfig>,
+    callback: Arc<Callback>,
+}
+
+impl ClientCertVerifier for ClientCallback {
+    fn offer_client_auth(&self) -> bool {
+        self.original.get_verifier().offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.original.get_verifier().client_auth_mandatory()
+    }
+
+    fn client_auth_root_subjects(&self) -> DistinguishedNames {
+        self.original.get_verifier().client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented: &[Certificate],
+    ) -> Result<ClientCertVerified, TLSError> {
+        verify::clear();
+        let default_result = self
+            .original
+            .get_verifier()
+            .verify_client_cert(presented)
+            .map(|_| ());
+        let verification = Verification {
+            chain: presented,
+            server_name: None,
+            ocsp_response: &[],
+            default_result: &default_result,
+        };
+        decide(&*self.callback, &verification)?;
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+/// A copy of `config` whose server certificate verification goes through
+/// `callback`.
+pub(crate) fn client_config(
+    config: &Arc<ClientConfig>,
+    callback: Arc<Callback>,
+) -> Arc<ClientConfig> {
+    let mut copy = (**config).clone();
+    copy.dangerous()
+        .set_certificate_verifier(Arc::new(ServerCallback {
+            original: config.clone(),
+            callback,
+        }));
+    Arc::new(copy)
+}
+
+/// A copy of `config` whose client certificate verification goes through
+/// `callback`.
+///
+/// rustls only sets the client certificate verifier in `ServerConfig::new`
+/// and gives no way to replace it, so this makes a new configuration and
+/// copies the other fields over. `test_server_config_fields` fails when the
+/// rustls in use has fields this does not know about.
+pub(crate) fn server_config(
+    config: &Arc<ServerConfig>,
+    callback: Arc<Callback>,
+) -> Arc<ServerConfig> {
+    let mut copy = ServerConfig::new(Arc::new(ClientCallback {
+        original: config.clone(),
+        callback,
+    }));
+    copy.ciphersuites = config.ciphersuites.clone();
+    copy.ignore_client_order = config.ignore_client_order;
+    copy.mtu = config.mtu;
+    copy.session_storage = config.session_storage.clone();
+    copy.ticketer = config.ticketer.clone();
+    copy.cert_resolver = config.cert_resolver.clone();
+    copy.alpn_protocols = config.alpn_protocols.clone();
+    copy.versions = config.versions.clone();
+    copy.key_log = config.key_log.clone();
+    #[cfg(feature = "quic")]
+    {
+        copy.max_early_data_size = config.max_early_data_size;
+    }
+    Arc::new(copy)
+}
+
+#[cfg(test)]
+mod test_callback {
+    use super::*;
+    use rustls::internal::pemfile::certs;
+    use rustls::{AllowAnyAuthenticatedClient, KeyLogFile, NoClientAuth, ProtocolVersion};
+    use std::io::{BufReader, Cursor};
+    use std::sync::Mutex;
+
+    const CHAIN: &[u8] = include_bytes!("../../tests/end.chain");
+    const CA: &[u8] = include_bytes!("../../tests/ca.cert");
+
+    fn chain() -> Vec<Certificate> {
+        certs(&mut BufReader::new(Cursor::new(CHAIN))).unwrap()
+    }
+
+    fn roots() -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add_pem_file(&mut BufReader::new(Cursor::new(CA)))
+            .unwrap();
+        roots
+    }
+
+    fn verify_server(config: &Arc<ClientConfig>, name: &str) -> Result<(), TLSError> {
+        let name = DNSNameRef::try_from_ascii_str(name).unwrap();
+        config
+            .get_verifier()
+            .verify_server_cert(&config.root_store, &chain(), name, b"ocsp")
+            .map(|_| ())
+    }
+
+    #[test]
+    fn test_server_verdicts() {
+        let mut config = ClientConfig::new();
+        config.root_store = roots();
+        let config = Arc::new(config);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        let config = client_config(
+            &config,
+            Arc::new(move |verification: &Verification| {
+                assert_eq!(verification.chain.len(), 2);
+                assert_eq!(verification.ocsp_response, b"ocsp");
+                let name = verification.server_name.unwrap();
+                log.lock()
+                    .unwrap()
+                    .push((name.to_owned(), verification.default_result.clone()));
+                match name {
+                    "localhost" => Verdict::Default,
+                    "example.com" => Verdict::Accept,
+                    _ => Verdict::Reject("not on the list".to_owned()),
+                }
+            }),
+        );
+
+        assert_eq!(verify_server(&config, "localhost"), Ok(()));
+        assert_eq!(verify_server(&config, "example.com"), Ok(()));
+        let err = verify_server(&config, "testserver.com").unwrap_err();
+        assert_eq!(
+            verify::failure(&err),
+            Some(VerifyError::Rejected("not on the list".to_owned()))
+        );
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen[0], ("localhost".to_owned(), Ok(())));
+        assert_eq!(
+            seen[1],
+            (
+                "example.com".to_owned(),
+                Err(TLSError::WebPKIError(webpki::Error::CertNotValidForName))
+            )
+        );
+        assert_eq!(seen[2], ("testserver.com".to_owned(), Ok(())));
+    }
+
+    #[test]
+    fn test_client_verdicts() {
+        let config = Arc::new(ServerConfig::new(AllowAnyAuthenticatedClient::new(roots())));
+        let reject = server_config(
+            &config,
+            Arc::new(|verification: &Verification| {
+                assert_eq!(verification.server_name, None);
+                assert_eq!(*verification.default_result, Ok(()));
+                Verdict::Reject("revoked".to_owned())
+            }),
+        );
+        let verifier = reject.get_verifier();
+        assert!(verifier.offer_client_auth());
+        assert!(verifier.client_auth_mandatory());
+        assert_eq!(verifier.client_auth_root_subjects().len(), 1);
+        let err = verifier.verify_client_cert(&chain()).err().unwrap();
+        assert_eq!(
+            verify::failure(&err),
+            Some(VerifyError::Rejected("revoked".to_owned()))
+        );
+
+        let config = Arc::new(ServerConfig::new(AllowAnyAuthenticatedClient::new(
+            RootCertStore::empty(),
+        )));
+        let accept = server_config(&config, Arc::new(|_: &Verification| Verdict::Accept));
+        assert!(accept.get_verifier().verify_client_cert(&chain()).is_ok());
+        let default = server_config(&config, Arc::new(|_: &Verification| Verdict::Default));
+        assert_eq!(
+            default
+                .get_verifier()
+                .verify_client_cert(&chain())
+                .map(|_| ()),
+            Err(TLSError::WebPKIError(webpki::Error::UnknownIssuer))
+        );
+
+        let none = server_config(
+            &Arc::new(ServerConfig::new(NoClientAuth::new())),
+            Arc::new(|_: &Verification| Verdict::Accept),
+        );
+        assert!(!none.get_verifier().offer_client_auth());
+    }
+
+    #[test]
+    fn test_server_config_copies_fields() {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.ciphersuites.truncate(1);
+        config.ignore_client_order = true;
+        config.mtu = Some(1200);
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        config.versions = vec![ProtocolVersion::TLSv1_2];
+        config.key_log = Arc::new(KeyLogFile::new());
+        #[cfg(feature = "quic")]
+        {
+            config.max_early_data_size = 1024;
+        }
+        let config = Arc::new(config);
+
+        let copy = server_config(&config, Arc::new(|_: &Verification| Verdict::Default));
+        assert_eq!(copy.ciphersuites, config.ciphersuites);
+        assert_eq!(copy.ignore_client_order, config.ignore_client_order);
+        assert_eq!(copy.mtu, config.mtu);
+        assert!(Arc::ptr_eq(&copy.session_storage, &config.session_storage));
+        assert!(Arc::ptr_eq(&copy.ticketer, &config.ticketer));
+        assert!(Arc::ptr_eq(&copy.cert_resolver, &config.cert_resolver));
+        assert_eq!(copy.alpn_protocols, config.alpn_protocols);
+        assert_eq!(copy.versions, config.versions);
+        assert!(Arc::ptr_eq(&copy.key_log, &config.key_log));
+        #[cfg(feature = "quic")]
+        assert_eq!(copy.max_early_data_size, config.max_early_data_size);
+    }
+
+    #[test]
+    fn test_server_config_fields() {
+        use rustls::{
+            KeyLog, ProducesTickets, ResolvesServerCert, StoresServerSessions, SupportedCipherSuite,
+        };
+        use std::mem;
+
+        // The fields `server_config` knows about. The client certificate
+        // verifier is private, so `ServerConfig` can not be destructured
+        // without `..`; a field rustls adds shows up as a size mismatch.
+        #[allow(dead_code)]
+        struct Known {
+            ciphersuites: Vec<&'static SupportedCipherSuite>,
+            ignore_client_order: bool,
+            mtu: Option<usize>,
+            session_storage: Arc<dyn StoresServerSessions + Send + Sync>,
+            ticketer: Arc<dyn ProducesTickets>,
+            cert_resolver: Arc<dyn ResolvesServerCert>,
+            alpn_protocols: Vec<Vec<u8>>,
+            versions: Vec<ProtocolVersion>,
+            verifier: Arc<dyn ClientCertVerifier>,
+            key_log: Arc<dyn KeyLog>,
+            #[cfg(feature = "quic")]
+            max_early_data_size: u32,
+        }
+
+        let config = ServerConfig::new(NoClientAuth::new());
+        let ServerConfig {
+            ciphersuites: _,
+            ignore_client_order: _,
+            mtu: _,
+            session_storage: _,
+            ticketer: _,
+            cert_resolver: _,
+            alpn_protocols: _,
+            versions: _,
+            key_log: _,
+            ..
+        } = config;
+        assert_eq!(mem::size_of::<ServerConfig>(), mem::size_of::<Known>());
+    }
+
+    #[test]
+    fn test_accept_clears_failure() {
+        let mut config = ClientConfig::new();
+        config.root_store = roots();
+        let config = client_config(
+            &Arc::new(config),
+            Arc::new(
+                |verification: &Verification| match verification.server_name {
+                    Some("testserver.com") => Verdict::Reject("not on the list".to_owned()),
+                    _ => Verdict::Accept,
+                },
+            ),
+        );
+
+        let err = verify_server(&config, "testserver.com").unwrap_err();
+        assert!(verify::failure(&err).is_some());
+        assert_eq!(verify_server(&config, "example.com"), Ok(()));
+        assert_eq!(
+            verify::failure(&TLSError::General("unrelated".to_owned())),
+            None
+        );
+    }
+}
@@ -0,0 +1,79 @@
+//! Half-close and graceful close_notify exchange.
+use super::deadline::Deadline;
+use super::entry::TlsState;
+use futures::{try_ready, Async, Future, Poll};
+use std::io;
+use std::time::Duration;
+use tokio_io::AsyncRead;
+
+/// Half-close operations of the client and server TLS streams.
+pub trait HalfClose {
+    /// The current shutdown state of the stream.
+    fn shutdown_state(&self) -> TlsState;
+
+    /// Sends close_notify and flushes it, leaving the read side open.
+    fn shutdown_write(&mut self) -> Poll<(), io::Error>;
+}
+
+/// Future returned from `TlsStream::close`, which resolves to the stream once
+/// the peer's close_notify has been received.
+///
+/// Under a tokio 0.1 timer, the task is woken once the timeout elapses.
+/// Without one, as under `Future::wait`, the deadline is only checked when
+/// the future is polled again, which takes the IO waking the task. A peer
+/// that goes silent then keeps the future waiting past the timeout, for as
+/// long as the IO stays quiet. The same goes for blocking IO, where a read
+/// only returns once the peer sends something, or the IO's own read timeout,
+/// such as `TcpStream::set_read_timeout`, runs out.
+pub struct Close<S> {
+    stream: Option<S>,
+    deadline: Deadline,
+}
+
+impl<S> Close<S> {
+    pub(crate) fn new(stream: S, timeout: Duration) -> Close<S> {
+        Close {
+            stream: Some(stream),
+            deadline: Deadline::after(timeout),
+        }
+    }
+}
+
+impl<S: HalfClose + AsyncRead> Future for Close<S> {
+    type Item = S;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        {
+            let stream = self.stream.as_mut().expect("Close polled after completion");
+            try_ready!(stream.shutdown_write());
+
+            // Application data still in flight from the peer is discarded;
+            // we only wait for its close_notify.
+            let mut buf = [0; 1024];
+            while stream.shutdown_state().readable() {
+                match stream.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(_) => (),
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        if self.deadline.poll_expired() {
+                            return Err(close_timed_out());
+                        }
+                        return Ok(Async::NotReady);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        Ok(Async::Ready(self.stream.take().unwrap()))
+    }
+}
+
+/// The error `Close` fails with once its timeout has elapsed.
+pub(crate) fn close_timed_out() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "timed out waiting for the peer's close_notify",
+    )
+}
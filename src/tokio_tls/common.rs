@@ -1,12 +1,32 @@
+use super::cork::Cork;
+use super::entry::CloseStatus;
+use super::observer::{self, HandshakeInfo, Observer, SharedObserver, CLOSE_NOTIFY};
+use super::stats::Stats;
+use super::verify;
+use bytes::{Buf, BufMut};
+use futures::{Async, Poll};
+use iovec::IoVec;
 use rustls::Session;
-use rustls::WriteV;
-use std::io::{self, Read, Write};
-use tokio_io::{AsyncRead, AsyncWrite};
+use rustls::{ProtocolVersion, TLSError, WriteV};
+use std::cmp;
+use std::io::{self, IoSlice, Read, Write};
+use std::ptr;
+use tokio_io::{try_nb, AsyncRead, AsyncWrite};
+
+/// The largest plaintext fragment a single TLS record can carry.
+pub const MAX_FRAGMENT_LEN: usize = 16 * 1024;
+
+/// How many buffers `write_buf` gathers into one vectored write.
+const MAX_WRITE_BUFS: usize = 64;
 
 pub struct Stream<'a, IO: 'a, S: 'a> {
     pub io: &'a mut IO,
     pub session: &'a mut S,
     pub eof: bool,
+    pub cork: Option<&'a mut Cork>,
+    pub stats: Option<&'a mut Stats>,
+    pub observer: Option<&'a dyn Observer>,
+    pub shared_observer: Option<&'a SharedObserver>,
 }
 
 pub trait WriteTls<'a, IO: AsyncRead + AsyncWrite, S: Session>: Read + Write {
@@ -28,6 +48,10 @@ impl<'a, IO: io::Read + io::Write, S: Session> Stream<'a, IO, S> {
             // The state so far is only used to detect EOF, so either Stream
             // or EarlyData state should both be all right.
             eof: false,
+            cork: None,
+            stats: None,
+            observer: None,
+            shared_observer: None,
         }
     }
 
@@ -36,25 +60,135 @@ impl<'a, IO: io::Read + io::Write, S: Session> Stream<'a, IO, S> {
         self
     }
 
+    pub fn set_cork(mut self, cork: &'a mut Cork) -> Self {
+        self.cork = Some(cork);
+        self
+    }
+
+    pub fn set_stats(mut self, stats: &'a mut Stats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    pub fn set_observer(mut self, observer: &'a SharedObserver) -> Self {
+        self.observer = observer.get();
+        self.shared_observer = Some(observer);
+        self
+    }
+
     pub fn complete_io(&mut self) -> io::Result<(usize, usize)> {
-        self.complete_inner_io(Focus::Empty)
+        let result = self.complete_inner_io(Focus::Empty);
+
+        if let Err(ref err) = result {
+            if err.kind() != io::ErrorKind::WouldBlock && self.session.is_handshaking() {
+                tls_event!(debug, error = %err, "handshake failed");
+                if let Some(observer) = self.observer {
+                    observer.handshake_failed(err);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Queues close_notify for the peer.
+    pub fn send_close_notify(&mut self) {
+        self.session.send_close_notify();
+        if let Some(observer) = self.observer {
+            observer.alert_sent(CLOSE_NOTIFY);
+        }
     }
 
     fn complete_read_io(&mut self) -> io::Result<usize> {
-        let n = self.session.read_tls(self.io)?;
+        let n = match self.stats {
+            Some(ref mut stats) => self.session.read_tls(&mut CountRead {
+                io: &mut *self.io,
+                stats: &mut **stats,
+            })?,
+            None => self.session.read_tls(self.io)?,
+        };
+
+        let handshaking = self.session.is_handshaking();
+        let pending = self.session.wants_write();
+        let session = &mut *self.session;
+        let processed = match self.shared_observer {
+            Some(shared) => verify::process(|| shared.process(|| session.process_new_packets())),
+            None => verify::process(|| session.process_new_packets()),
+        };
+        processed.map_err(|err| {
+            // Whether rustls answered the error with an alert; a failure it
+            // does not answer leaves nothing new to write.
+            let alerted = pending || self.session.wants_write();
 
-        self.session.process_new_packets().map_err(|err| {
             // In case we have an alert to send describing this error,
             // try a last-gasp write -- but don't predate the primary
-            // error.
-            let _ = self.write_tls();
+            // error. A write can take less than what is queued, and in
+            // TLS 1.3 the alert can queue behind a ChangeCipherSpec.
+            while self.session.wants_write() {
+                match self.write_tls() {
+                    Ok(n) if n > 0 => (),
+                    _ => break,
+                }
+            }
+
+            tls_event!(debug, error = %err, "TLS error");
+            if let Some(observer) = self.observer {
+                match err {
+                    TLSError::AlertReceived(alert) => observer.alert_received(alert.get_u8()),
+                    ref err if alerted => {
+                        let server = self
+                            .shared_observer
+                            .map_or(false, SharedObserver::is_server);
+                        let tls13 =
+                            self.session.get_protocol_version() == Some(ProtocolVersion::TLSv1_3);
+                        if let Some(code) = observer::sent_alert(err, server, tls13) {
+                            observer.alert_sent(code);
+                        }
+                    }
+                    _ => (),
+                }
+            }
 
-            io::Error::new(io::ErrorKind::InvalidData, err)
+            verify::io_error(err)
         })?;
 
+        if handshaking && !self.session.is_handshaking() {
+            self.handshake_complete();
+        }
+
         Ok(n)
     }
 
+    /// Records the end of the handshake, once processing records has taken
+    /// the session out of handshaking, which happens only once.
+    fn handshake_complete(&mut self) {
+        if let Some(ref mut stats) = self.stats {
+            stats.handshake_complete();
+        }
+
+        let duration = self.shared_observer.map(SharedObserver::elapsed);
+        tls_event!(
+            debug,
+            ?duration,
+            protocol_version = ?self.session.get_protocol_version(),
+            "handshake complete"
+        );
+
+        if let (Some(observer), Some(shared), Some(duration)) =
+            (self.observer, self.shared_observer, duration)
+        {
+            match self.session.get_peer_certificates() {
+                Some(ref chain) if !chain.is_empty() => observer.certificate_verified(chain),
+                _ => (),
+            }
+            if shared.resumed() {
+                observer.session_resumed();
+            }
+            let info = HandshakeInfo::new(&*self.session, duration, shared.is_server());
+            observer.handshake_complete(&info);
+        }
+    }
+
     fn complete_write_io(&mut self) -> io::Result<usize> {
         self.write_tls()
     }
@@ -71,6 +205,7 @@ impl<'a, IO: io::Read + io::Write, S: Session> Stream<'a, IO, S> {
                 match self.complete_write_io() {
                     Ok(n) => wrlen += n,
                     Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        tls_event!(trace, direction = "write", "would block");
                         write_would_block = true;
                         break;
                     }
@@ -78,11 +213,19 @@ impl<'a, IO: io::Read + io::Write, S: Session> Stream<'a, IO, S> {
                 }
             }
 
-            if !self.eof && self.session.wants_read() {
+            // Once the handshake is done, writing never needs anything from
+            // the peer, and blocking IO would wait for it to send something.
+            let read = match focus {
+                Focus::Writable => self.session.is_handshaking(),
+                Focus::Empty | Focus::Readable => true,
+            };
+
+            if read && !self.eof && self.session.wants_read() {
                 match self.complete_read_io() {
                     Ok(0) => self.eof = true,
                     Ok(n) => rdlen += n,
                     Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        tls_event!(trace, direction = "read", "would block");
                         read_would_block = true
                     }
                     Err(err) => return Err(err),
@@ -123,38 +266,93 @@ impl<'a, IO: AsyncRead + AsyncWrite, S: Session> WriteTls<'a, IO, S> for Stream<
         use super::vecbuf::VecBuf;
         use futures::Async;
 
-        struct V<'a, IO: 'a>(&'a mut IO);
+        struct V<'a, IO: 'a>(&'a mut IO, Option<&'a mut Stats>);
 
         impl<'a, IO: AsyncWrite> WriteV for V<'a, IO> {
             fn writev(&mut self, vbytes: &[&[u8]]) -> io::Result<usize> {
-                let mut vbytes = VecBuf::new(vbytes);
-                match self.0.write_buf(&mut vbytes) {
-                    Ok(Async::Ready(n)) => Ok(n),
-                    Ok(Async::NotReady) => Err(io::ErrorKind::WouldBlock.into()),
-                    Err(err) => Err(err),
+                let n = match self.0.write_buf(&mut VecBuf::new(vbytes)) {
+                    Ok(Async::Ready(n)) => n,
+                    Ok(Async::NotReady) => return Err(io::ErrorKind::WouldBlock.into()),
+                    Err(err) => return Err(err),
+                };
+
+                if let Some(ref mut stats) = self.1 {
+                    let mut left = n;
+                    for bytes in vbytes {
+                        let len = cmp::min(bytes.len(), left);
+                        stats.ciphertext_written(&bytes[..len]);
+                        left -= len;
+                    }
                 }
+                Ok(n)
             }
         }
 
-        let mut vecio = V(self.io);
+        let mut vecio = V(self.io, self.stats.as_mut().map(|stats| &mut **stats));
         self.session.writev_tls(&mut vecio)
     }
 }
 
-impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Read for Stream<'a, IO, S> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        while self.session.wants_read() {
-            if let (0, _) = self.complete_inner_io(Focus::Readable)? {
-                break;
+impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Stream<'a, IO, S> {
+    fn is_corked(&self) -> bool {
+        self.cork.as_ref().map_or(false, |cork| cork.is_enabled())
+    }
+
+    /// Writes out plaintext held back by the cork.
+    pub fn uncork(&mut self) -> io::Result<()> {
+        let mut pending = match self.cork {
+            Some(ref mut cork) if cork.buffered() > 0 => cork.take(),
+            _ => return Ok(()),
+        };
+
+        let mut pos = 0;
+        let mut result = Ok(());
+        while pos < pending.len() {
+            match self.write_plaintext(&pending[pos..]) {
+                Ok(n) => pos += n,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
             }
         }
-        self.session.read(buf)
+
+        pending.drain(..pos);
+        if let Some(ref mut cork) = self.cork {
+            cork.restore(pending);
+        }
+        result
     }
-}
 
-impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Write for Stream<'a, IO, S> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    /// Like `uncork`, but only once the cork is full or has expired.
+    fn uncork_if_due(&mut self) -> io::Result<()> {
+        let due = match self.cork {
+            Some(ref mut cork) => cork.is_full() || cork.expired(),
+            None => false,
+        };
+
+        match if due { self.uncork() } else { Ok(()) } {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Whether the peer's close_notify has been processed and everything
+    /// sent before it read. rustls keeps asking for more data after that,
+    /// and reading it would wait on a peer that may be waiting for our own
+    /// close_notify.
+    fn peer_closed(&mut self) -> bool {
+        match self.session.read(&mut []) {
+            Err(ref err) => err.kind() == io::ErrorKind::ConnectionAborted,
+            Ok(_) => false,
+        }
+    }
+
+    fn write_plaintext(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.session.write(buf)?;
+        if let Some(ref mut stats) = self.stats {
+            stats.plaintext_written(len);
+        }
         while self.session.wants_write() {
             match self.complete_inner_io(Focus::Writable) {
                 Ok(_) => (),
@@ -167,17 +365,96 @@ impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Write for Stream<'a, IO, S> {
             Ok(len)
         } else {
             // not write zero
-            self.session.write(buf).and_then(|len| {
-                if len != 0 {
-                    Ok(len)
-                } else {
-                    Err(io::ErrorKind::WouldBlock.into())
+            let len = self.session.write(buf)?;
+            if let Some(ref mut stats) = self.stats {
+                stats.plaintext_written(len);
+            }
+
+            if len != 0 {
+                Ok(len)
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+    }
+}
+
+impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Read for Stream<'a, IO, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.uncork_if_due()?;
+
+        while self.session.wants_read() && !self.peer_closed() {
+            if let (0, _) = self.complete_inner_io(Focus::Readable)? {
+                break;
+            }
+        }
+        let n = match self.session.read(buf) {
+            Ok(n) => n,
+            Err(err) => {
+                if let (io::ErrorKind::ConnectionAborted, Some(observer)) =
+                    (err.kind(), self.observer)
+                {
+                    observer.alert_received(CLOSE_NOTIFY);
+                    observer.close(CloseStatus::Authenticated);
+                }
+                return Err(err);
+            }
+        };
+
+        if let Some(ref mut stats) = self.stats {
+            stats.plaintext_read(n);
+        }
+        if let (0, false, true, Some(observer)) = (n, buf.is_empty(), self.eof, self.observer) {
+            observer.close(CloseStatus::Truncated);
+        }
+        Ok(n)
+    }
+}
+
+impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Write for Stream<'a, IO, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.is_corked() {
+            // Anything corked before the cork was disabled goes first.
+            self.uncork()?;
+            return self.write_plaintext(buf);
+        }
+
+        self.uncork_if_due()?;
+        let len = match self.cork {
+            Some(ref mut cork) => cork.push(buf),
+            None => 0,
+        };
+        self.uncork_if_due()?;
+
+        if len != 0 || buf.is_empty() {
+            Ok(len)
+        } else {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    /// Coalesces small buffers into a single record instead of letting each
+    /// of them become a record of its own.
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let total = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        let limit = cmp::min(total, MAX_FRAGMENT_LEN);
+
+        match bufs.iter().find(|buf| !buf.is_empty()) {
+            None => self.write(&[]),
+            Some(buf) if buf.len() >= limit => self.write(buf),
+            Some(_) => {
+                let mut record = Vec::with_capacity(limit);
+                for buf in bufs {
+                    let len = cmp::min(buf.len(), limit - record.len());
+                    record.extend_from_slice(&buf[..len]);
                 }
-            })
+                self.write(&record)
+            }
         }
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        self.uncork()?;
         self.session.flush()?;
         while self.session.wants_write() {
             self.complete_inner_io(Focus::Writable)?;
@@ -185,3 +462,162 @@ impl<'a, IO: AsyncRead + AsyncWrite, S: Session> Write for Stream<'a, IO, S> {
         Ok(())
     }
 }
+
+/// Counts the ciphertext read from the IO.
+struct CountRead<'a, IO: 'a> {
+    io: &'a mut IO,
+    stats: &'a mut Stats,
+}
+
+impl<'a, IO: Read> Read for CountRead<'a, IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.io.read(buf)?;
+        self.stats.ciphertext_read(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// `AsyncWrite::write_buf` for the TLS streams, handing all of `buf`'s chunks
+/// to a single vectored write.
+pub fn write_buf<W: Write, B: Buf>(writer: &mut W, buf: &mut B) -> Poll<usize, io::Error> {
+    if !buf.has_remaining() {
+        return Ok(Async::Ready(0));
+    }
+
+    let n = {
+        let mut iovecs: [&IoVec; MAX_WRITE_BUFS] = [Default::default(); MAX_WRITE_BUFS];
+        let mut slices = [IoSlice::new(&[]); MAX_WRITE_BUFS];
+        let cnt = buf.bytes_vec(&mut iovecs);
+        for (slice, iovec) in slices.iter_mut().zip(&iovecs[..cnt]) {
+            *slice = IoSlice::new(iovec);
+        }
+        try_nb!(writer.write_vectored(&slices[..cnt]))
+    };
+    buf.advance(n);
+    Ok(Async::Ready(n))
+}
+
+/// The most plaintext `read_buf` asks for at once: one TLS record's worth.
+/// The spare capacity is zeroed before each read, so this keeps the zeroing
+/// in proportion to what is read.
+const READ_CHUNK: usize = 16 * 1024;
+
+/// `AsyncRead::read_buf` for the TLS streams.
+///
+/// Plaintext is copied from the session into `buf`'s spare capacity, and as
+/// long as the session has more buffered, without returning in between; a
+/// read that comes up short of the chunk it was given means the session has
+/// run out, and reading on would go to the IO. This saves the copy through
+/// an intermediate buffer, but not the zeroing: rustls only reads into
+/// initialized memory, so each chunk of the capacity is zeroed before `read`
+/// sees it. If `read` fails after some plaintext was read, those bytes are
+/// returned and the error is left in `read_error` for the next read.
+pub fn read_buf<R: Read, B: BufMut>(
+    reader: &mut R,
+    read_error: &mut Option<io::Error>,
+    buf: &mut B,
+) -> Poll<usize, io::Error> {
+    let mut total = 0;
+
+    while buf.has_remaining_mut() {
+        let (n, len) = {
+            let chunk = unsafe { buf.bytes_mut() };
+            let len = cmp::min(chunk.len(), READ_CHUNK);
+            let chunk = &mut chunk[..len];
+            unsafe {
+                ptr::write_bytes(chunk.as_mut_ptr(), 0, len);
+            }
+            match reader.read(chunk) {
+                Ok(n) => (n, len),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock && total > 0 => break,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(err) if total > 0 => {
+                    *read_error = Some(err);
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        unsafe {
+            buf.advance_mut(n);
+        }
+        total += n;
+
+        if n < len {
+            break;
+        }
+    }
+
+    Ok(Async::Ready(total))
+}
+
+#[cfg(test)]
+mod test_common {
+    use super::*;
+    use bytes::BytesMut;
+    use std::collections::VecDeque;
+
+    /// Hands out the queued results one `read` at a time, checking that it
+    /// is only given zeroed buffers.
+    struct Reads(VecDeque<io::Result<Vec<u8>>>);
+
+    impl Read for Reads {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            assert!(buf.iter().all(|&byte| byte == 0));
+            let data = self.0.pop_front().unwrap()?;
+            buf[..data.len()].copy_from_slice(&data);
+            Ok(data.len())
+        }
+    }
+
+    #[test]
+    fn test_read_buf_keeps_error_after_data() {
+        let mut reader = Reads(
+            vec![
+                Ok(vec![1; READ_CHUNK]),
+                Err(io::Error::new(io::ErrorKind::InvalidData, "bad record")),
+            ]
+            .into(),
+        );
+        let mut read_error = None;
+        let mut buf = BytesMut::with_capacity(2 * READ_CHUNK);
+
+        let n = read_buf(&mut reader, &mut read_error, &mut buf).unwrap();
+        assert_eq!(n, Async::Ready(READ_CHUNK));
+        assert_eq!(buf.len(), READ_CHUNK);
+        assert_eq!(read_error.unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_read_buf_stops_after_short_read() {
+        // A second read would find the queue empty and panic.
+        let mut reader = Reads(vec![Ok(b"hello".to_vec())].into());
+        let mut read_error = None;
+        let mut buf = BytesMut::with_capacity(64);
+
+        let n = read_buf(&mut reader, &mut read_error, &mut buf).unwrap();
+        assert_eq!(n, Async::Ready(5));
+        assert_eq!(&buf[..], b"hello");
+    }
+
+    #[test]
+    fn test_read_buf_would_block() {
+        let would_block = || Err(io::Error::from(io::ErrorKind::WouldBlock));
+        let mut reader = Reads(vec![would_block(), Ok(b"hi".to_vec())].into());
+        let mut read_error = None;
+        let mut buf = BytesMut::with_capacity(64);
+
+        let n = read_buf(&mut reader, &mut read_error, &mut buf).unwrap();
+        assert_eq!(n, Async::NotReady);
+        let n = read_buf(&mut reader, &mut read_error, &mut buf).unwrap();
+        assert_eq!(n, Async::Ready(2));
+        assert!(read_error.is_none());
+
+        let mut reader = Reads(vec![Err(io::Error::from(io::ErrorKind::Other))].into());
+        let err = read_buf(&mut reader, &mut read_error, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+}
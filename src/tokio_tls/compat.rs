@@ -0,0 +1,225 @@
+//! Glue shared by the `std::future` flavours of the TLS streams and the mio
+//! stream.
+//!
+//! Each flavour wraps its IO, together with the task context of the current
+//! poll, in an adapter implementing the `tokio_io` traits `common::Stream` is
+//! written against, turning `Poll::Pending` into `WouldBlock`. The functions
+//! below then drive the session and `TlsState` the same way the futures 0.1
+//! client and server streams do.
+use super::common::Stream;
+use super::cork::Cork;
+use super::entry::{PeerClose, TlsState};
+use super::observer::SharedObserver;
+use super::stats::Stats;
+use futures::Async;
+use rustls::Session;
+use std::io::{self, Read, Write};
+#[cfg(any(feature = "futures03", feature = "tokio1"))]
+use std::task::Poll;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// Like `tokio_io::try_nb!`, but returns `Poll::Pending` on `WouldBlock`.
+#[cfg(any(feature = "futures03", feature = "tokio1"))]
+macro_rules! try_pending {
+    ($e:expr) => {
+        match $e {
+            Ok(t) => t,
+            Err(ref err) if err.kind() == ::std::io::ErrorKind::WouldBlock => {
+                return ::std::task::Poll::Pending;
+            }
+            Err(err) => return ::std::task::Poll::Ready(Err(err)),
+        }
+    };
+}
+
+#[cfg(any(feature = "futures03", feature = "tokio1"))]
+pub(crate) fn would_block<T>(poll: Poll<io::Result<T>>) -> io::Result<T> {
+    match poll {
+        Poll::Ready(result) => result,
+        Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+    }
+}
+
+pub(crate) fn handshake<IO, S>(
+    io: &mut IO,
+    session: &mut S,
+    state: TlsState,
+    stats: &mut Stats,
+    observer: &SharedObserver,
+) -> io::Result<()>
+where
+    IO: AsyncRead + AsyncWrite,
+    S: Session,
+{
+    let mut stream = Stream::new(io, session)
+        .set_eof(!state.readable())
+        .set_stats(stats)
+        .set_observer(observer);
+
+    if stream.session.is_handshaking() {
+        stream.complete_io()?;
+    }
+
+    if stream.session.wants_write() {
+        stream.flush()?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn read<IO, S>(
+    io: &mut IO,
+    session: &mut S,
+    state: &mut TlsState,
+    close: &mut PeerClose,
+    cork: &mut Cork,
+    stats: &mut Stats,
+    observer: &SharedObserver,
+    buf: &mut [u8],
+) -> io::Result<usize>
+where
+    IO: AsyncRead + AsyncWrite,
+    S: Session,
+{
+    let mut stream = Stream::new(io, session)
+        .set_eof(!state.readable())
+        .set_cork(cork)
+        .set_stats(stats)
+        .set_observer(observer);
+
+    match *state {
+        TlsState::Stream | TlsState::WriteShutdown => match stream.read(buf) {
+            Ok(0) if buf.is_empty() => Ok(0),
+            Ok(0) => {
+                state.shutdown_read();
+                close.eof()?;
+                Ok(0)
+            }
+            Ok(n) => Ok(n),
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => {
+                state.shutdown_read();
+                close.close_notify();
+                // The write side stays open until it is shut down, so
+                // that a half-closed peer can still be answered.
+                Ok(0)
+            }
+            Err(e) => Err(e),
+        },
+        TlsState::ReadShutdown | TlsState::FullyShutdown => Ok(0),
+        #[cfg(feature = "early-data")]
+        s => unreachable!("std::future TLS streams can not hit this state: {:?}", s),
+    }
+}
+
+pub(crate) fn write<IO, S>(
+    io: &mut IO,
+    session: &mut S,
+    state: TlsState,
+    cork: &mut Cork,
+    stats: &mut Stats,
+    observer: &SharedObserver,
+    buf: &[u8],
+) -> io::Result<usize>
+where
+    IO: AsyncRead + AsyncWrite,
+    S: Session,
+{
+    Stream::new(io, session)
+        .set_eof(!state.readable())
+        .set_cork(cork)
+        .set_stats(stats)
+        .set_observer(observer)
+        .write(buf)
+}
+
+pub(crate) fn write_vectored<IO, S>(
+    io: &mut IO,
+    session: &mut S,
+    state: TlsState,
+    cork: &mut Cork,
+    stats: &mut Stats,
+    observer: &SharedObserver,
+    bufs: &[io::IoSlice],
+) -> io::Result<usize>
+where
+    IO: AsyncRead + AsyncWrite,
+    S: Session,
+{
+    Stream::new(io, session)
+        .set_eof(!state.readable())
+        .set_cork(cork)
+        .set_stats(stats)
+        .set_observer(observer)
+        .write_vectored(bufs)
+}
+
+pub(crate) fn flush<IO, S>(
+    io: &mut IO,
+    session: &mut S,
+    state: TlsState,
+    cork: &mut Cork,
+    stats: &mut Stats,
+    observer: &SharedObserver,
+) -> io::Result<()>
+where
+    IO: AsyncRead + AsyncWrite,
+    S: Session,
+{
+    Stream::new(&mut *io, session)
+        .set_eof(!state.readable())
+        .set_cork(cork)
+        .set_stats(stats)
+        .set_observer(observer)
+        .flush()?;
+    io.flush()
+}
+
+/// Sends close_notify and flushes it to the IO, leaving the read side open.
+pub(crate) fn shutdown_write<IO, S>(
+    io: &mut IO,
+    session: &mut S,
+    state: &mut TlsState,
+    cork: &mut Cork,
+    stats: &mut Stats,
+    observer: &SharedObserver,
+) -> io::Result<()>
+where
+    IO: AsyncRead + AsyncWrite,
+    S: Session,
+{
+    let mut stream = Stream::new(&mut *io, session)
+        .set_eof(!state.readable())
+        .set_cork(cork)
+        .set_stats(stats)
+        .set_observer(observer);
+
+    if state.writeable() {
+        // Corked plaintext has to go out before close_notify.
+        stream.flush()?;
+        stream.send_close_notify();
+        state.shutdown_write();
+    }
+
+    stream.flush()?;
+    io.flush()
+}
+
+/// Sends close_notify, flushes it to the IO and then shuts the IO down.
+pub(crate) fn close<IO, S>(
+    io: &mut IO,
+    session: &mut S,
+    state: &mut TlsState,
+    cork: &mut Cork,
+    stats: &mut Stats,
+    observer: &SharedObserver,
+) -> io::Result<()>
+where
+    IO: AsyncRead + AsyncWrite,
+    S: Session,
+{
+    shutdown_write(io, session, state, cork, stats, observer)?;
+    match io.shutdown()? {
+        Async::Ready(()) => Ok(()),
+        Async::NotReady => Err(io::ErrorKind::WouldBlock.into()),
+    }
+}
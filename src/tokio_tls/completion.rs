@@ -0,0 +1,272 @@
+//! Completion-based TLS streams, for io_uring-style runtimes.
+//!
+//! Readiness-based IO is retried with a borrowed buffer after `WouldBlock`.
+//! Completion-based IO instead takes ownership of a buffer when an operation
+//! is submitted and hands it back once the operation completes.
+//! `CompletionStream` drives an `Engine` over such IO, keeping at most one
+//! read and one write in flight.
+use super::engine::Engine;
+use bytes::BytesMut;
+use futures::{try_ready, Async, Future, Poll};
+use rustls::Session;
+use std::cmp;
+use std::io;
+
+/// Large enough to hold a full TLS record.
+const READ_BUF_SIZE: usize = 18 * 1024;
+
+/// IO whose operations own their buffer until they complete.
+pub trait CompletionIo {
+    /// Completes with the buffer, its length extended by the number of bytes
+    /// read. Reading zero bytes means EOF.
+    type Read: Future<Item = (BytesMut, usize), Error = io::Error>;
+
+    /// Completes with the buffer and the number of bytes written from its
+    /// start.
+    type Write: Future<Item = (Vec<u8>, usize), Error = io::Error>;
+
+    /// Submits a read into the spare capacity of `buf`.
+    fn read(&mut self, buf: BytesMut) -> Self::Read;
+
+    /// Submits a write of `buf`.
+    fn write(&mut self, buf: Vec<u8>) -> Self::Write;
+}
+
+/// A TLS stream over completion-based IO.
+pub struct CompletionStream<IO: CompletionIo, S> {
+    io: IO,
+    engine: Engine<S>,
+    // Each buffer is either idle here or owned by its in-flight operation.
+    rbuf: Option<BytesMut>,
+    wbuf: Option<Vec<u8>>,
+    reading: Option<IO::Read>,
+    writing: Option<IO::Write>,
+}
+
+impl<IO: CompletionIo, S: Session> CompletionStream<IO, S> {
+    pub fn new(io: IO, engine: Engine<S>) -> CompletionStream<IO, S> {
+        CompletionStream {
+            io,
+            engine,
+            rbuf: Some(BytesMut::with_capacity(READ_BUF_SIZE)),
+            wbuf: Some(Vec::new()),
+            reading: None,
+            writing: None,
+        }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> (&IO, &Engine<S>) {
+        (&self.io, &self.engine)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> (&mut IO, &mut Engine<S>) {
+        (&mut self.io, &mut self.engine)
+    }
+
+    /// Drives the handshake to completion.
+    pub fn handshake(self) -> Handshake<IO, S> {
+        Handshake(Some(self))
+    }
+
+    /// Reads plaintext, appending it to `buf`.
+    pub fn read(self, buf: BytesMut) -> Read<IO, S> {
+        Read(Some((self, buf)))
+    }
+
+    /// Writes all of `buf` and waits until the resulting records have been
+    /// written to the IO.
+    pub fn write(self, buf: Vec<u8>) -> Write<IO, S> {
+        Write(Some((self, buf, 0)))
+    }
+
+    pub fn poll_handshake(&mut self) -> Poll<(), io::Error> {
+        loop {
+            try_ready!(self.poll_flush());
+
+            if !self.engine.is_handshaking() {
+                return Ok(Async::Ready(()));
+            }
+
+            if try_ready!(self.poll_fill()) == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    /// Reads plaintext into the spare capacity of `buf`, growing it if it
+    /// has none. Resolves to 0 at the end of the stream.
+    ///
+    /// At most a record's worth of the spare capacity is used, so that only
+    /// that much has to be zeroed on each call.
+    pub fn poll_read(&mut self, buf: &mut BytesMut) -> Poll<usize, io::Error> {
+        let len = buf.len();
+        if buf.capacity() == len {
+            buf.reserve(READ_BUF_SIZE);
+        }
+        let end = cmp::min(buf.capacity(), len + READ_BUF_SIZE);
+        buf.resize(end, 0);
+
+        let result = self.poll_read_into(&mut buf[len..]);
+        let n = match result {
+            Ok(Async::Ready(n)) => n,
+            _ => 0,
+        };
+        buf.truncate(len + n);
+        result
+    }
+
+    fn poll_read_into(&mut self, buf: &mut [u8]) -> Poll<usize, io::Error> {
+        loop {
+            match self.engine.read_plaintext(buf) {
+                Ok(n) => return Ok(Async::Ready(n)),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                Err(err) => return Err(err),
+            }
+
+            // Processing records may have queued a response, e.g. to a key
+            // update.
+            try_ready!(self.poll_flush());
+
+            if try_ready!(self.poll_fill()) == 0 {
+                self.engine.transport_eof()?;
+                return Ok(Async::Ready(0));
+            }
+        }
+    }
+
+    /// Encrypts plaintext and submits the resulting records. Only one write
+    /// is kept in flight, so this waits for the previous one first.
+    ///
+    /// Once the session's buffer limit is reached, this waits for the
+    /// handshake to finish, which sends the plaintext buffered so far. After
+    /// the handshake, a write that finds no room even with everything ahead
+    /// of it written fails with `WriteZero`.
+    pub fn poll_write(&mut self, buf: &[u8]) -> Poll<usize, io::Error> {
+        loop {
+            try_ready!(self.poll_flush());
+            let n = self.engine.write_plaintext(buf)?;
+            if n > 0 || buf.is_empty() {
+                self.poll_flush()?;
+                return Ok(Async::Ready(n));
+            }
+
+            if !self.engine.is_handshaking() {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            try_ready!(self.poll_handshake());
+        }
+    }
+
+    /// Waits until all queued records have been written to the IO.
+    pub fn poll_flush(&mut self) -> Poll<(), io::Error> {
+        loop {
+            if let Some(writing) = self.writing.as_mut() {
+                let (mut buf, n) = try_ready!(writing.poll());
+                self.writing = None;
+
+                if n == 0 && !buf.is_empty() {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+
+                buf.drain(..n);
+                if !buf.is_empty() {
+                    self.writing = Some(self.io.write(buf));
+                    continue;
+                }
+                self.wbuf = Some(buf);
+            }
+
+            let mut buf = self.wbuf.take().expect("write buffer is idle");
+            if self.engine.take_ciphertext_into(&mut buf) == 0 {
+                self.wbuf = Some(buf);
+                return Ok(Async::Ready(()));
+            }
+            self.writing = Some(self.io.write(buf));
+        }
+    }
+
+    /// Sends close_notify and waits until it has been written to the IO.
+    pub fn poll_close(&mut self) -> Poll<(), io::Error> {
+        self.engine.close();
+        self.poll_flush()
+    }
+
+    /// Reads ciphertext from the IO and feeds all of it to the engine.
+    /// Resolves to 0 at EOF.
+    fn poll_fill(&mut self) -> Poll<usize, io::Error> {
+        if self.reading.is_none() {
+            let mut buf = self.rbuf.take().expect("read buffer is idle");
+            buf.clear();
+            buf.reserve(READ_BUF_SIZE);
+            self.reading = Some(self.io.read(buf));
+        }
+
+        let (buf, n) = try_ready!(self.reading.as_mut().unwrap().poll());
+        self.reading = None;
+
+        let fed = self.engine.feed_ciphertext(&buf);
+        self.rbuf = Some(buf);
+        fed?;
+
+        Ok(Async::Ready(n))
+    }
+}
+
+/// Future returned from `CompletionStream::handshake`, which resolves to the
+/// stream once the handshake has finished.
+pub struct Handshake<IO: CompletionIo, S>(Option<CompletionStream<IO, S>>);
+
+impl<IO: CompletionIo, S: Session> Future for Handshake<IO, S> {
+    type Item = CompletionStream<IO, S>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        try_ready!(self
+            .0
+            .as_mut()
+            .expect("Handshake polled after completion")
+            .poll_handshake());
+        Ok(Async::Ready(self.0.take().unwrap()))
+    }
+}
+
+/// Future returned from `CompletionStream::read`, which resolves to the
+/// stream, the buffer and the number of bytes read into it.
+pub struct Read<IO: CompletionIo, S>(Option<(CompletionStream<IO, S>, BytesMut)>);
+
+impl<IO: CompletionIo, S: Session> Future for Read<IO, S> {
+    type Item = (CompletionStream<IO, S>, BytesMut, usize);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let n = {
+            let (stream, buf) = self.0.as_mut().expect("Read polled after completion");
+            try_ready!(stream.poll_read(buf))
+        };
+        let (stream, buf) = self.0.take().unwrap();
+        Ok(Async::Ready((stream, buf, n)))
+    }
+}
+
+/// Future returned from `CompletionStream::write`, which resolves to the
+/// stream and the buffer once all of it has been written.
+pub struct Write<IO: CompletionIo, S>(Option<(CompletionStream<IO, S>, Vec<u8>, usize)>);
+
+impl<IO: CompletionIo, S: Session> Future for Write<IO, S> {
+    type Item = (CompletionStream<IO, S>, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        {
+            let (stream, buf, pos) = self.0.as_mut().expect("Write polled after completion");
+            while *pos < buf.len() {
+                *pos += try_ready!(stream.poll_write(&buf[*pos..]));
+            }
+            try_ready!(stream.poll_flush());
+        }
+        let (stream, buf, _) = self.0.take().unwrap();
+        Ok(Async::Ready((stream, buf)))
+    }
+}
@@ -0,0 +1,161 @@
+//! Corking: holding back small writes so that they share TLS records.
+//!
+//! By default every write on a TLS stream is encrypted and sent right away,
+//! so a protocol doing many tiny writes pays for a record header and MAC on
+//! each of them. A corked stream instead collects plaintext until `limit`
+//! bytes are buffered, the stream is flushed, or, if set, `max_delay` has
+//! passed since the first byte was buffered.
+//!
+//! Corking applies to every stream a corked connector or acceptor creates,
+//! including the futures 0.3 and tokio 1.x ones, whose `max_delay` runs on
+//! the timer of their runtime.
+use super::deadline::Deadline;
+#[cfg(any(feature = "futures03", feature = "tokio1"))]
+use super::std_future::Timer;
+use std::cmp;
+use std::mem;
+#[cfg(any(feature = "futures03", feature = "tokio1"))]
+use std::task::Context;
+use std::time::Duration;
+
+/// Plaintext held back by a corked stream.
+#[derive(Debug)]
+pub struct Cork {
+    limit: usize,
+    max_delay: Option<Duration>,
+    buf: Vec<u8>,
+    deadline: Option<Deadline>,
+}
+
+impl Cork {
+    /// Buffers up to `limit` bytes of plaintext. A `limit` of 0 disables
+    /// corking.
+    ///
+    /// The plaintext held back for `max_delay` is only sent when the stream
+    /// is next read from, written to or flushed. The task that last did so is
+    /// woken once `max_delay` has passed, so a task waiting on the stream
+    /// sends it then: by the tokio 0.1 timer on the futures 0.1 streams, and
+    /// by the timer of their runtime on the futures 0.3 and tokio 1.x ones.
+    /// Without a tokio 0.1 timer, as under `Future::wait`, nothing wakes the
+    /// task; flush the stream when there is nothing more to write rather
+    /// than relying on `max_delay`.
+    pub fn new(limit: usize, max_delay: Option<Duration>) -> Cork {
+        Cork {
+            limit,
+            max_delay,
+            buf: Vec::new(),
+            deadline: None,
+        }
+    }
+
+    pub fn disabled() -> Cork {
+        Cork::new(0, None)
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.limit > 0
+    }
+
+    /// How much plaintext is currently held back.
+    #[inline]
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Changes the limits, keeping whatever is already buffered.
+    pub(crate) fn set(&mut self, limit: usize, max_delay: Option<Duration>) {
+        self.limit = limit;
+        self.max_delay = max_delay;
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.buf.len() >= self.limit
+    }
+
+    /// Buffers as much of `data` as fits, returning how much that was.
+    pub(crate) fn push(&mut self, data: &[u8]) -> usize {
+        if self.buf.is_empty() {
+            self.deadline = self.max_delay.map(Deadline::after);
+        }
+
+        let len = cmp::min(data.len(), self.limit.saturating_sub(self.buf.len()));
+        self.buf.extend_from_slice(&data[..len]);
+        len
+    }
+
+    /// Whether the buffered plaintext has been held back for too long. If
+    /// not, the current task is woken once it has, if a timer is available.
+    pub(crate) fn expired(&mut self) -> bool {
+        self.deadline
+            .as_mut()
+            .map_or(false, |deadline| deadline.poll_expired())
+    }
+
+    /// Like `expired`, for the `std::future` flavour `F`, whose runtime's
+    /// timer wakes the task of `cx` once the buffered plaintext has been held
+    /// back for too long.
+    #[cfg(any(feature = "futures03", feature = "tokio1"))]
+    pub(crate) fn poll_timer<F: Timer>(&mut self, cx: &mut Context) {
+        if self.buf.is_empty() {
+            return;
+        }
+        if let Some(ref mut deadline) = self.deadline {
+            deadline.poll_expired_in::<F>(cx);
+        }
+    }
+
+    /// Takes the buffered plaintext to write it out. Its deadline stays, for
+    /// whatever is put back with `restore`.
+    pub(crate) fn take(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.buf, Vec::new())
+    }
+
+    /// Puts back what `take` returned and could not be written yet, which
+    /// is still due when the original plaintext was.
+    pub(crate) fn restore(&mut self, buf: Vec<u8>) {
+        debug_assert!(self.buf.is_empty());
+        if buf.is_empty() {
+            self.deadline = None;
+        }
+        self.buf = buf;
+    }
+}
+
+impl Default for Cork {
+    fn default() -> Cork {
+        Cork::disabled()
+    }
+}
+
+#[cfg(test)]
+mod test_cork {
+    use super::*;
+    use futures::{future, Future};
+    use std::thread;
+
+    #[test]
+    fn test_restore_keeps_deadline() {
+        future::lazy(|| {
+            let mut cork = Cork::new(16, Some(Duration::from_millis(50)));
+            assert_eq!(cork.push(b"corked"), 6);
+            thread::sleep(Duration::from_millis(30));
+
+            // Part of it was written; the rest is due when all of it was.
+            let buf = cork.take();
+            cork.restore(buf[2..].to_vec());
+            assert_eq!(cork.buffered(), 4);
+            assert!(!cork.expired());
+            thread::sleep(Duration::from_millis(30));
+            assert!(cork.expired());
+
+            // Once everything is written, there is nothing left to be due.
+            let _ = cork.take();
+            cork.restore(Vec::new());
+            assert!(!cork.expired());
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+//! Deadlines for futures that give up after a while.
+//!
+//! A deadline registers a wakeup with the tokio 0.1 timer when there is one.
+//! Without a timer, as under `Future::wait` or another executor, the timer
+//! fails when polled; the deadline then only compares against the clock, so
+//! it is noticed the next time the future is polled for some other reason,
+//! such as its IO becoming ready.
+//!
+//! The `std::future` flavours use `poll_expired_in` instead, which sleeps on
+//! the timer of their own runtime; see `std_future::Timer`.
+#[cfg(any(feature = "futures03", feature = "tokio1"))]
+use super::std_future::{Sleep, Timer};
+use futures::{Async, Future};
+use std::fmt;
+#[cfg(any(feature = "futures03", feature = "tokio1"))]
+use std::task::Context;
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+
+pub(crate) struct Deadline {
+    at: Instant,
+    delay: Option<Delay>,
+    #[cfg(any(feature = "futures03", feature = "tokio1"))]
+    sleep: Option<Sleep>,
+}
+
+impl fmt::Debug for Deadline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Deadline")
+            .field("at", &self.at)
+            .field("delay", &self.delay)
+            .finish()
+    }
+}
+
+impl Deadline {
+    /// A deadline `timeout` from now.
+    pub(crate) fn after(timeout: Duration) -> Deadline {
+        let at = Instant::now() + timeout;
+        Deadline {
+            at,
+            delay: Some(Delay::new(at)),
+            #[cfg(any(feature = "futures03", feature = "tokio1"))]
+            sleep: None,
+        }
+    }
+
+    /// Whether the deadline has passed. If it has not, the current task is
+    /// woken once it does, if a timer is available.
+    pub(crate) fn poll_expired(&mut self) -> bool {
+        let timer_failed = match self.delay {
+            Some(ref mut delay) => match delay.poll() {
+                Ok(Async::Ready(())) => return true,
+                Ok(Async::NotReady) => false,
+                Err(_) => true,
+            },
+            None => false,
+        };
+        if timer_failed {
+            self.delay = None;
+        }
+        Instant::now() >= self.at
+    }
+
+    /// Like `poll_expired`, for the `std::future` flavour `F`: if the
+    /// deadline has not passed, the task of `cx` is woken once it does by
+    /// the timer of `F`'s runtime.
+    #[cfg(any(feature = "futures03", feature = "tokio1"))]
+    pub(crate) fn poll_expired_in<F: Timer>(&mut self, cx: &mut Context) -> bool {
+        if Instant::now() >= self.at {
+            return true;
+        }
+        let at = self.at;
+        let sleep = self.sleep.get_or_insert_with(|| F::sleep_until(at));
+        sleep.as_mut().poll(cx).is_ready()
+    }
+}
+
+#[cfg(test)]
+mod test_deadline {
+    use super::*;
+    use futures::future;
+    use std::thread;
+
+    #[test]
+    fn test_without_timer() {
+        future::lazy(|| {
+            let mut passed = Deadline::after(Duration::from_millis(0));
+            assert!(passed.poll_expired());
+
+            let mut pending = Deadline::after(Duration::from_millis(50));
+            assert!(!pending.poll_expired());
+            thread::sleep(Duration::from_millis(60));
+            assert!(pending.poll_expired());
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+//! A sans-IO TLS engine for custom event loops.
+//!
+//! `Engine` never touches a socket: ciphertext received from the network is
+//! handed to it with `feed_ciphertext`, and ciphertext to send is collected
+//! with `take_ciphertext`. Plaintext goes in and out through
+//! `write_plaintext` and `read_plaintext`. Anything noteworthy that happens
+//! along the way is reported through `poll_event`.
+use super::entry::{CloseStatus, PeerClose, TlsState};
+use super::verify;
+use bytes::Bytes;
+use rustls::{Session, TLSError};
+use std::collections::VecDeque;
+use std::io;
+
+/// Something that happened while processing ciphertext.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The handshake completed; application data can now flow both ways.
+    HandshakeComplete,
+    /// The peer sent close_notify. No more plaintext will arrive.
+    PeerClosed,
+    /// The peer sent a fatal alert. The parameter is the alert code.
+    AlertReceived(u8),
+}
+
+/// A TLS session driven entirely by buffers.
+#[derive(Debug)]
+pub struct Engine<S> {
+    session: S,
+    state: TlsState,
+    close: PeerClose,
+    handshake_complete: bool,
+    events: VecDeque<Event>,
+}
+
+impl<S: Session> Engine<S> {
+    pub fn new(session: S) -> Engine<S> {
+        Engine::with_close(session, false)
+    }
+
+    pub(crate) fn with_close(session: S, strict_close: bool) -> Engine<S> {
+        Engine {
+            session,
+            state: TlsState::Stream,
+            close: PeerClose::new(strict_close),
+            handshake_complete: false,
+            events: VecDeque::new(),
+        }
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &S {
+        &self.session
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.session
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.session
+    }
+
+    /// Whether the handshake is still in progress.
+    #[inline]
+    pub fn is_handshaking(&self) -> bool {
+        self.session.is_handshaking()
+    }
+
+    /// Whether there is ciphertext waiting to be collected with
+    /// `take_ciphertext`.
+    #[inline]
+    pub fn wants_write(&self) -> bool {
+        self.session.wants_write()
+    }
+
+    /// The current shutdown state of the engine.
+    #[inline]
+    pub fn shutdown_state(&self) -> TlsState {
+        self.state
+    }
+
+    /// Processes ciphertext received from the peer, returning how many bytes
+    /// were consumed.
+    ///
+    /// All of `data` is consumed unless this fails: the session takes records
+    /// one buffer at a time and keeps the plaintext they decrypt to until it
+    /// is read, without a limit. On error, the session may have queued an
+    /// alert for the peer, which `take_ciphertext` returns.
+    pub fn feed_ciphertext(&mut self, mut data: &[u8]) -> io::Result<usize> {
+        let len = data.len();
+
+        while !data.is_empty() {
+            if self.session.read_tls(&mut data)? == 0 {
+                break;
+            }
+
+            let session = &mut self.session;
+            if let Err(err) = verify::process(|| session.process_new_packets()) {
+                if let TLSError::AlertReceived(alert) = err {
+                    self.events.push_back(Event::AlertReceived(alert.get_u8()));
+                }
+                return Err(verify::io_error(err));
+            }
+        }
+
+        if !self.handshake_complete && !self.session.is_handshaking() {
+            self.handshake_complete = true;
+            self.events.push_back(Event::HandshakeComplete);
+        }
+        self.check_closed();
+
+        Ok(len - data.len())
+    }
+
+    /// Takes all ciphertext the session wants to send to the peer.
+    pub fn take_ciphertext(&mut self) -> Bytes {
+        let mut buf = Vec::new();
+        self.take_ciphertext_into(&mut buf);
+        Bytes::from(buf)
+    }
+
+    /// Like `take_ciphertext`, but appends to a buffer owned by the caller,
+    /// returning how many bytes were appended.
+    pub fn take_ciphertext_into(&mut self, buf: &mut Vec<u8>) -> usize {
+        let len = buf.len();
+        while self.session.wants_write() {
+            // Writing into a `Vec` can not fail.
+            self.session.write_tls(buf).unwrap();
+        }
+        buf.len() - len
+    }
+
+    /// Reads decrypted application data.
+    ///
+    /// Returns `Ok(0)` once the peer has closed the session, and
+    /// `WouldBlock` when more ciphertext needs to be fed first.
+    pub fn read_plaintext(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.state.readable() {
+            return Ok(0);
+        }
+
+        match self.session.read(buf) {
+            Ok(0) if !buf.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+            Ok(n) => Ok(n),
+            Err(ref err) if err.kind() == io::ErrorKind::ConnectionAborted => {
+                self.peer_closed();
+                Ok(0)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Encrypts application data, to be collected with `take_ciphertext`.
+    ///
+    /// Data written before the handshake completes is buffered and sent once
+    /// it does.
+    pub fn write_plaintext(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.state.writeable() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.session.write(buf)
+    }
+
+    /// Queues close_notify for the peer; the read side stays open.
+    pub fn close(&mut self) {
+        if self.state.writeable() {
+            self.session.send_close_notify();
+            self.state.shutdown_write();
+        }
+    }
+
+    /// Returns the next event, if any.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// How the peer ended its side of the session.
+    #[inline]
+    pub fn close_status(&self) -> CloseStatus {
+        self.close.status
+    }
+
+    /// Records that the transport reached EOF. Unless the peer sent
+    /// close_notify first, the session is marked as truncated, which is an
+    /// `UnexpectedEof` error in strict close mode.
+    pub fn transport_eof(&mut self) -> io::Result<()> {
+        self.state.shutdown_read();
+        match self.close.status {
+            CloseStatus::Open => self.close.eof(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reports `PeerClosed` as soon as close_notify has arrived and all
+    /// plaintext before it has been read.
+    fn check_closed(&mut self) {
+        if self.close.status != CloseStatus::Open {
+            return;
+        }
+
+        if let Err(ref err) = self.session.read(&mut []) {
+            if err.kind() == io::ErrorKind::ConnectionAborted {
+                self.peer_closed();
+            }
+        }
+    }
+
+    fn peer_closed(&mut self) {
+        if self.close.status == CloseStatus::Open {
+            self.close.close_notify();
+            self.state.shutdown_read();
+            self.events.push_back(Event::PeerClosed);
+        }
+    }
+}
@@ -1,47 +1,74 @@
 //! Asynchronous TLS/SSL streams for Tokio using [Rustls](https://github.com/ctz/rustls).
+use super::callback::{self, Verdict, Verification};
 use super::client;
 use super::common::Stream;
+use super::cork::Cork;
+use super::deadline::Deadline;
+use super::engine::Engine;
+use super::error::handshake_timed_out;
+use super::expiry::{self, CertExpiry, ExpiryWarning};
+use super::observer::{self, Observer, SharedObserver};
+use super::pin::{self, PinSet};
 use super::server;
+use super::stats::Stats;
+use super::tofu::{self, KnownHosts};
+use super::trace::ConnSpan;
 use futures::{Async, Future, Poll};
-use rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession};
+use rustls::{Certificate, ClientConfig, ClientSession, ServerConfig, ServerSession, Session};
+use std::error;
+use std::fmt;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{io, mem};
 use tokio_io::{try_nb, AsyncRead, AsyncWrite};
 use webpki::DNSNameRef;
 
-#[derive(Debug, Copy, Clone)]
+/// The shutdown state of a TLS stream.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum TlsState {
+    /// The handshake is still sending 0-RTT data.
     #[cfg(feature = "early-data")]
     EarlyData,
+    /// Both directions are open.
     Stream,
+    /// The peer closed its side; writing is still possible.
     ReadShutdown,
+    /// We sent close_notify; reading is still possible.
     WriteShutdown,
+    /// Both directions are closed.
     FullyShutdown,
 }
 
 impl TlsState {
+    pub(crate) fn set(&mut self, to: TlsState) {
+        tls_event!(debug, from = ?*self, to = ?to, "TLS state transition");
+        *self = to;
+    }
+
     pub(crate) fn shutdown_read(&mut self) {
         match *self {
-            TlsState::WriteShutdown | TlsState::FullyShutdown => *self = TlsState::FullyShutdown,
-            _ => *self = TlsState::ReadShutdown,
+            TlsState::WriteShutdown | TlsState::FullyShutdown => self.set(TlsState::FullyShutdown),
+            _ => self.set(TlsState::ReadShutdown),
         }
     }
 
     pub(crate) fn shutdown_write(&mut self) {
         match *self {
-            TlsState::ReadShutdown | TlsState::FullyShutdown => *self = TlsState::FullyShutdown,
-            _ => *self = TlsState::WriteShutdown,
+            TlsState::ReadShutdown | TlsState::FullyShutdown => self.set(TlsState::FullyShutdown),
+            _ => self.set(TlsState::WriteShutdown),
         }
     }
 
-    pub(crate) fn writeable(&self) -> bool {
+    /// Whether data may still be written to the stream.
+    pub fn writeable(&self) -> bool {
         match *self {
             TlsState::WriteShutdown | TlsState::FullyShutdown => false,
             _ => true,
         }
     }
 
-    pub(crate) fn readable(self) -> bool {
+    /// Whether data may still be read from the stream.
+    pub fn readable(self) -> bool {
         match self {
             TlsState::ReadShutdown | TlsState::FullyShutdown => false,
             _ => true,
@@ -49,10 +76,65 @@ impl TlsState {
     }
 }
 
+/// How the peer ended its side of a TLS stream.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CloseStatus {
+    /// The peer has not closed its side yet.
+    Open,
+    /// The peer sent close_notify, so all of its data was received.
+    Authenticated,
+    /// The underlying IO reached EOF without a close_notify; the data may
+    /// have been truncated.
+    Truncated,
+}
+
+/// Tracks whether the peer ended the stream with close_notify.
+#[derive(Debug, Copy, Clone)]
+pub struct PeerClose {
+    pub strict: bool,
+    pub status: CloseStatus,
+}
+
+impl PeerClose {
+    pub(crate) fn new(strict: bool) -> PeerClose {
+        PeerClose {
+            strict,
+            status: CloseStatus::Open,
+        }
+    }
+
+    pub(crate) fn close_notify(&mut self) {
+        self.status = CloseStatus::Authenticated;
+    }
+
+    /// Records an EOF without close_notify, which is an error in strict mode.
+    pub(crate) fn eof(&mut self) -> io::Result<()> {
+        self.status = CloseStatus::Truncated;
+        if self.strict {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "peer closed connection without sending TLS close_notify",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
 /// A wrapper around a `rustls::ClientConfig`, providing an async `connect` method.
 #[derive(Clone)]
 pub struct TlsConnector {
     pub inner: Arc<ClientConfig>,
+    pub handshake_timeout: Option<Duration>,
+    pub strict_close: bool,
+    pub cork_limit: usize,
+    pub cork_delay: Option<Duration>,
+    pub buffer_limit: Option<usize>,
+    pub observer: SharedObserver,
+    /// When the client certificate expires, if it was given with
+    /// `certificate`.
+    pub cert_expiry: Option<CertExpiry>,
+    pub expiry_warning: Option<Arc<ExpiryWarning>>,
     #[cfg(feature = "early-data")]
     early_data: bool,
 }
@@ -61,12 +143,30 @@ pub struct TlsConnector {
 #[derive(Clone)]
 pub struct TlsAcceptor {
     pub inner: Arc<ServerConfig>,
+    pub handshake_timeout: Option<Duration>,
+    pub strict_close: bool,
+    pub cork_limit: usize,
+    pub cork_delay: Option<Duration>,
+    pub buffer_limit: Option<usize>,
+    pub observer: SharedObserver,
+    /// When the server certificate expires, if it was given with
+    /// `certificate`.
+    pub cert_expiry: Option<CertExpiry>,
+    pub expiry_warning: Option<Arc<ExpiryWarning>>,
 }
 
 impl From<Arc<ClientConfig>> for TlsConnector {
     fn from(inner: Arc<ClientConfig>) -> TlsConnector {
         TlsConnector {
             inner,
+            handshake_timeout: None,
+            strict_close: false,
+            cork_limit: 0,
+            cork_delay: None,
+            buffer_limit: None,
+            observer: SharedObserver::default(),
+            cert_expiry: None,
+            expiry_warning: None,
             #[cfg(feature = "early-data")]
             early_data: false,
         }
@@ -75,8 +175,27 @@ impl From<Arc<ClientConfig>> for TlsConnector {
 
 impl From<Arc<ServerConfig>> for TlsAcceptor {
     fn from(inner: Arc<ServerConfig>) -> TlsAcceptor {
-        TlsAcceptor { inner }
+        TlsAcceptor {
+            inner,
+            handshake_timeout: None,
+            strict_close: false,
+            cork_limit: 0,
+            cork_delay: None,
+            buffer_limit: None,
+            observer: SharedObserver::default(),
+            cert_expiry: None,
+            expiry_warning: None,
+        }
+    }
+}
+
+pub(crate) fn poll_deadline(deadline: &mut Option<Deadline>) -> io::Result<()> {
+    if let Some(deadline) = deadline {
+        if deadline.poll_expired() {
+            return Err(handshake_timed_out());
+        }
     }
+    Ok(())
 }
 
 impl TlsConnector {
@@ -90,6 +209,182 @@ impl TlsConnector {
         self
     }
 
+    /// Limit how long the handshake may take.
+    ///
+    /// Once the timeout elapses, `Connect` fails with a `TimedOut` error and
+    /// drops the underlying IO. Within a tokio runtime the task is woken when
+    /// that happens; without one, as under `Future::wait`, the timeout is
+    /// only noticed when the handshake is polled because of its IO.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> TlsConnector {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Treat an EOF without close_notify as an `UnexpectedEof` error instead
+    /// of a clean end of stream.
+    ///
+    /// Without this, a truncated stream is only visible through
+    /// `TlsStream::close_status`.
+    ///
+    /// This applies to every stream built from this connector: the futures
+    /// 0.1, mio and `std::future` streams as well as the sans-IO engine.
+    pub fn strict_close(mut self, flag: bool) -> TlsConnector {
+        self.strict_close = flag;
+        self
+    }
+
+    /// Cork the streams: hold back up to `limit` bytes of plaintext so that
+    /// small writes share TLS records, sending it once the limit is reached,
+    /// on `flush`, or once `max_delay` has passed.
+    ///
+    /// See `cork::Cork` for the details.
+    pub fn cork(mut self, limit: usize, max_delay: Option<Duration>) -> TlsConnector {
+        self.cork_limit = limit;
+        self.cork_delay = max_delay;
+        self
+    }
+
+    /// Limit how much unsent data each stream buffers, counting plaintext
+    /// written before the handshake completes and TLS records not yet
+    /// written to the IO.
+    ///
+    /// Once the limit is reached, writes fail with `WouldBlock` (`NotReady`
+    /// for `poll_write`) until the IO accepts more data, instead of queueing
+    /// more in memory. Without a limit, a peer that stops reading lets the
+    /// buffers grow without bound.
+    pub fn buffer_limit(mut self, limit: usize) -> TlsConnector {
+        self.buffer_limit = Some(limit);
+        self
+    }
+
+    /// Report the lifecycle of each stream to `observer`; see
+    /// `observer::Observer`.
+    pub fn observer(mut self, observer: Arc<dyn Observer>) -> TlsConnector {
+        self.observer = SharedObserver::new(observer);
+        self
+    }
+
+    /// Read the expiry of `cert`, the client certificate the configuration
+    /// authenticates with, failing with `InvalidData` if it can not be
+    /// parsed.
+    ///
+    /// The configuration's certificate resolver is not asked for it: that is
+    /// code of the application's, meant to run during handshakes.
+    pub fn certificate(mut self, cert: &Certificate) -> io::Result<TlsConnector> {
+        self.cert_expiry = Some(CertExpiry::from_certificate(cert)?);
+        Ok(self)
+    }
+
+    /// Call `callback` once the client certificate is within `threshold` of
+    /// expiring, and emit a `tracing` warning with the `tracing` feature.
+    ///
+    /// This is checked now and whenever a stream is created, and fires only
+    /// once.
+    ///
+    /// # Panics
+    ///
+    /// If the certificate was not given with `certificate` first.
+    pub fn expiry_warning<F>(mut self, threshold: Duration, callback: F) -> TlsConnector
+    where
+        F: Fn(&CertExpiry) + Send + Sync + 'static,
+    {
+        let expiry = self
+            .cert_expiry
+            .expect("`expiry_warning` called before `certificate`");
+        self.expiry_warning = Some(ExpiryWarning::new(expiry, threshold, callback));
+        self
+    }
+
+    /// Verify server certificates against `pins`; see `pin::PinSet`.
+    ///
+    /// This replaces the configuration's certificate verifier, including one
+    /// set with `ClientConfig::dangerous`, with the default verification
+    /// plus the pin check.
+    pub fn pins(mut self, pins: PinSet) -> TlsConnector {
+        self.inner = pin::pinned_config(&self.inner, pins);
+        self
+    }
+
+    /// Trust each server's certificate on first use and record it in
+    /// `hosts`, failing later connections if it changes; see
+    /// `tofu::KnownHosts`.
+    ///
+    /// Like `pins`, this replaces the configuration's certificate verifier.
+    pub fn known_hosts(mut self, hosts: Arc<KnownHosts>) -> TlsConnector {
+        self.inner = tofu::tofu_config(&self.inner, hosts);
+        self
+    }
+
+    /// Pass each server certificate chain, and what the configuration's
+    /// verifier made of it, to `callback`, which has the final say; see
+    /// `callback::Verdict`.
+    ///
+    /// The callback wraps the verifier as it is at this point, including one
+    /// set up by `pins` or `known_hosts`.
+    pub fn verify_callback<F>(mut self, callback: F) -> TlsConnector
+    where
+        F: Fn(&Verification) -> Verdict + Send + Sync + 'static,
+    {
+        self.inner = callback::client_config(&self.inner, Arc::new(callback));
+        self
+    }
+
+    /// Apply the buffer limit, if any, to a new session.
+    pub(crate) fn limit_buffers<S: Session>(&self, session: &mut S) {
+        if let Some(limit) = self.buffer_limit {
+            session.set_buffer_limit(limit);
+        }
+    }
+
+    /// Fire the expiry warning if it is due.
+    pub(crate) fn warn_expiry(&self) {
+        if let Some(ref warning) = self.expiry_warning {
+            warning.check();
+        }
+    }
+
+    /// Start a client session for `domain` with this connector's settings.
+    pub(crate) fn session(&self, domain: DNSNameRef) -> ClientSession {
+        let mut session = ClientSession::new(&self.inner, domain);
+        self.limit_buffers(&mut session);
+        self.warn_expiry();
+        session
+    }
+
+    /// Wrap `session` and `io` in a client stream with this connector's
+    /// settings, starting out in `state`.
+    pub(crate) fn stream<IO>(
+        &self,
+        domain: DNSNameRef,
+        session: ClientSession,
+        io: IO,
+        state: TlsState,
+    ) -> client::TlsStream<IO> {
+        client::TlsStream {
+            session,
+            io,
+            state,
+            close: PeerClose::new(self.strict_close),
+            cork: Cork::new(self.cork_limit, self.cork_delay),
+            stats: Stats::new(),
+            observer: self.observer.start_stream(),
+            span: ConnSpan::client(domain),
+            read_error: None,
+            #[cfg(feature = "early-data")]
+            early_data: (0, Vec::new()),
+        }
+    }
+
+    /// The deadline of a handshake starting now.
+    pub(crate) fn handshake_deadline(&self) -> Option<Deadline> {
+        self.handshake_timeout.map(Deadline::after)
+    }
+
+    /// Create a sans-IO client engine using this connector's configuration.
+    pub fn engine(&self, domain: DNSNameRef) -> Engine<ClientSession> {
+        Engine::with_close(self.session(domain), self.strict_close)
+    }
+
     pub fn connect<IO>(&self, domain: DNSNameRef, stream: IO) -> Connect<IO>
     where
         IO: AsyncRead + AsyncWrite,
@@ -103,40 +398,217 @@ impl TlsConnector {
         IO: AsyncRead + AsyncWrite,
         F: FnOnce(&mut ClientSession),
     {
-        let mut session = ClientSession::new(&self.inner, domain);
+        let mut session = self.session(domain);
         f(&mut session);
 
         #[cfg(not(feature = "early-data"))]
-        {
-            Connect(client::MidHandshake::Handshaking(client::TlsStream {
-                session,
-                io: stream,
-                state: TlsState::Stream,
-            }))
-        }
+        let handshake = client::MidHandshake::Handshaking(self.stream(
+            domain,
+            session,
+            stream,
+            TlsState::Stream,
+        ));
 
         #[cfg(feature = "early-data")]
-        {
-            Connect(if self.early_data {
-                client::MidHandshake::EarlyData(client::TlsStream {
-                    session,
-                    io: stream,
-                    state: TlsState::EarlyData,
-                    early_data: (0, Vec::new()),
-                })
-            } else {
-                client::MidHandshake::Handshaking(client::TlsStream {
-                    session,
-                    io: stream,
-                    state: TlsState::Stream,
-                    early_data: (0, Vec::new()),
-                })
-            })
-        }
+        let handshake = if self.early_data {
+            client::MidHandshake::EarlyData(self.stream(
+                domain,
+                session,
+                stream,
+                TlsState::EarlyData,
+            ))
+        } else {
+            client::MidHandshake::Handshaking(self.stream(
+                domain,
+                session,
+                stream,
+                TlsState::Stream,
+            ))
+        };
+
+        Connect(handshake, self.handshake_deadline())
     }
 }
 
 impl TlsAcceptor {
+    /// Limit how long the handshake may take.
+    ///
+    /// Once the timeout elapses, `Accept` fails with a `TimedOut` error and
+    /// drops the underlying IO. Within a tokio runtime the task is woken when
+    /// that happens; without one, as under `Future::wait`, the timeout is
+    /// only noticed when the handshake is polled because of its IO.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> TlsAcceptor {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Treat an EOF without close_notify as an `UnexpectedEof` error instead
+    /// of a clean end of stream.
+    ///
+    /// Without this, a truncated stream is only visible through
+    /// `TlsStream::close_status`. Like `TlsConnector::strict_close`, this
+    /// applies to every flavour of stream built from this acceptor.
+    pub fn strict_close(mut self, flag: bool) -> TlsAcceptor {
+        self.strict_close = flag;
+        self
+    }
+
+    /// Cork the streams: hold back up to `limit` bytes of plaintext so that
+    /// small writes share TLS records, sending it once the limit is reached,
+    /// on `flush`, or once `max_delay` has passed.
+    ///
+    /// See `cork::Cork` for the details.
+    pub fn cork(mut self, limit: usize, max_delay: Option<Duration>) -> TlsAcceptor {
+        self.cork_limit = limit;
+        self.cork_delay = max_delay;
+        self
+    }
+
+    /// Limit how much unsent data each stream buffers, counting plaintext
+    /// written before the handshake completes and TLS records not yet
+    /// written to the IO.
+    ///
+    /// Once the limit is reached, writes fail with `WouldBlock` (`NotReady`
+    /// for `poll_write`) until the IO accepts more data, instead of queueing
+    /// more in memory. Without a limit, a peer that stops reading lets the
+    /// buffers grow without bound.
+    pub fn buffer_limit(mut self, limit: usize) -> TlsAcceptor {
+        self.buffer_limit = Some(limit);
+        self
+    }
+
+    /// Report the lifecycle of each stream to `observer`; see
+    /// `observer::Observer`.
+    ///
+    /// ClientHellos and resumed sessions are recognised from the certificate
+    /// resolver, so the first call wraps the `cert_resolver` of the
+    /// configuration as it is at this point. Later calls only replace the
+    /// observer.
+    pub fn observer(mut self, observer: Arc<dyn Observer>) -> TlsAcceptor {
+        if self.observer.get().is_none() {
+            self.inner = observer::observe_config(&self.inner);
+        }
+        self.observer = SharedObserver::new_server(observer);
+        self
+    }
+
+    /// Pass each client certificate chain, and what the configuration's
+    /// client certificate verifier made of it, to `callback`, which has the
+    /// final say; see `callback::Verdict`.
+    ///
+    /// Whether client certificates are requested at all is still up to the
+    /// configuration's verifier, so this does nothing with `NoClientAuth`.
+    pub fn verify_callback<F>(mut self, callback: F) -> TlsAcceptor
+    where
+        F: Fn(&Verification) -> Verdict + Send + Sync + 'static,
+    {
+        self.inner = callback::server_config(&self.inner, Arc::new(callback));
+        self
+    }
+
+    /// Read the expiry of `cert`, the server certificate the configuration
+    /// serves, failing with `InvalidData` if it can not be parsed.
+    ///
+    /// The configuration's certificate resolver is not asked for it: that is
+    /// code of the application's, meant to run during handshakes, and may
+    /// pick a different certificate per server name.
+    pub fn certificate(mut self, cert: &Certificate) -> io::Result<TlsAcceptor> {
+        self.cert_expiry = Some(CertExpiry::from_certificate(cert)?);
+        Ok(self)
+    }
+
+    /// Like `certificate`, for the certificate the configuration serves to
+    /// clients that send no server name, found with a handshake in memory.
+    ///
+    /// That handshake runs the certificate resolver, and checks that the
+    /// configuration holds the key of the certificate it serves. It fails
+    /// with `InvalidData` if the configuration serves nothing without a
+    /// server name.
+    pub fn certificate_from_handshake(self) -> io::Result<TlsAcceptor> {
+        let cert = expiry::served_certificate(&self.inner)?;
+        self.certificate(&cert)
+    }
+
+    /// Call `callback` once the server certificate is within `threshold` of
+    /// expiring, and emit a `tracing` warning with the `tracing` feature.
+    ///
+    /// This is checked now and whenever a stream is created, and fires only
+    /// once.
+    ///
+    /// # Panics
+    ///
+    /// If the certificate was not given with `certificate` or
+    /// `certificate_from_handshake` first.
+    pub fn expiry_warning<F>(mut self, threshold: Duration, callback: F) -> TlsAcceptor
+    where
+        F: Fn(&CertExpiry) + Send + Sync + 'static,
+    {
+        let expiry = self
+            .cert_expiry
+            .expect("`expiry_warning` called before `certificate`");
+        self.expiry_warning = Some(ExpiryWarning::new(expiry, threshold, callback));
+        self
+    }
+
+    /// Fails if the server certificate has already expired, so that a server
+    /// can refuse to start with it. The error is `InvalidData`, wrapping the
+    /// `CertExpired` error a client would reject the certificate with. Passes
+    /// if the certificate was not given with `certificate`.
+    pub fn check_expiry(&self) -> io::Result<()> {
+        match self.cert_expiry {
+            Some(ref expiry) => expiry.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Apply the buffer limit, if any, to a new session.
+    pub(crate) fn limit_buffers<S: Session>(&self, session: &mut S) {
+        if let Some(limit) = self.buffer_limit {
+            session.set_buffer_limit(limit);
+        }
+    }
+
+    /// Fire the expiry warning if it is due.
+    pub(crate) fn warn_expiry(&self) {
+        if let Some(ref warning) = self.expiry_warning {
+            warning.check();
+        }
+    }
+
+    /// Start a server session with this acceptor's settings.
+    pub(crate) fn session(&self) -> ServerSession {
+        let mut session = ServerSession::new(&self.inner);
+        self.limit_buffers(&mut session);
+        self.warn_expiry();
+        session
+    }
+
+    /// Wrap `session` and `io` in a server stream with this acceptor's
+    /// settings.
+    pub(crate) fn stream<IO>(&self, session: ServerSession, io: IO) -> server::TlsStream<IO> {
+        server::TlsStream {
+            session,
+            io,
+            state: TlsState::Stream,
+            close: PeerClose::new(self.strict_close),
+            cork: Cork::new(self.cork_limit, self.cork_delay),
+            stats: Stats::new(),
+            observer: self.observer.start_stream(),
+            span: ConnSpan::server(),
+            read_error: None,
+        }
+    }
+
+    /// The deadline of a handshake starting now.
+    pub(crate) fn handshake_deadline(&self) -> Option<Deadline> {
+        self.handshake_timeout.map(Deadline::after)
+    }
+
+    /// Create a sans-IO server engine using this acceptor's configuration.
+    pub fn engine(&self) -> Engine<ServerSession> {
+        Engine::with_close(self.session(), self.strict_close)
+    }
+
     pub fn accept<IO>(&self, stream: IO) -> Accept<IO>
     where
         IO: AsyncRead + AsyncWrite,
@@ -150,41 +622,99 @@ impl TlsAcceptor {
         IO: AsyncRead + AsyncWrite,
         F: FnOnce(&mut ServerSession),
     {
-        let mut session = ServerSession::new(&self.inner);
+        let mut session = self.session();
         f(&mut session);
 
-        Accept(server::MidHandshake::Handshaking(server::TlsStream {
-            session,
-            io: stream,
-            state: TlsState::Stream,
-        }))
+        Accept(
+            server::MidHandshake::Handshaking(self.stream(session, stream)),
+            self.handshake_deadline(),
+        )
     }
 }
 
 /// Future returned from `ClientConfigExt::connect_async` which will resolve
 /// once the connection handshake has finished.
-pub struct Connect<IO>(client::MidHandshake<IO>);
+pub struct Connect<IO>(client::MidHandshake<IO>, Option<Deadline>);
 
 /// Future returned from `ServerConfigExt::accept_async` which will resolve
 /// once the accept handshake has finished.
-pub struct Accept<IO>(server::MidHandshake<IO>);
+pub struct Accept<IO>(server::MidHandshake<IO>, Option<Deadline>);
 
 impl<IO> Connect<IO> {
     pub fn take_inner(&mut self) -> Option<IO> {
+        self.take_parts().map(|(io, _)| io)
+    }
+
+    /// Convert this future into one that hands back the IO and session when
+    /// the handshake fails.
+    pub fn into_failable(self) -> FailableConnect<IO> {
+        FailableConnect(self)
+    }
+
+    fn take_parts(&mut self) -> Option<(IO, ClientSession)> {
         match mem::replace(&mut self.0, client::MidHandshake::End) {
-            client::MidHandshake::Handshaking(client::TlsStream { io, .. }) => Some(io),
-            _ => None,
+            client::MidHandshake::Handshaking(stream) => Some(stream.into_inner()),
+            #[cfg(feature = "early-data")]
+            client::MidHandshake::EarlyData(stream) => Some(stream.into_inner()),
+            client::MidHandshake::End => None,
+        }
+    }
+
+    fn poll_handshake(&mut self) -> Poll<client::TlsStream<IO>, io::Error>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        if let Async::Ready(stream) = self.0.poll()? {
+            return Ok(Async::Ready(stream));
         }
+
+        poll_deadline(&mut self.1).map_err(|err| {
+            match self.0 {
+                client::MidHandshake::Handshaking(ref stream) => stream.handshake_failed(&err),
+                #[cfg(feature = "early-data")]
+                client::MidHandshake::EarlyData(ref stream) => stream.handshake_failed(&err),
+                client::MidHandshake::End => (),
+            }
+            err
+        })?;
+        Ok(Async::NotReady)
     }
 }
 
 impl<IO> Accept<IO> {
     pub fn take_inner(&mut self) -> Option<IO> {
+        self.take_parts().map(|(io, _)| io)
+    }
+
+    /// Convert this future into one that hands back the IO and session when
+    /// the handshake fails.
+    pub fn into_failable(self) -> FailableAccept<IO> {
+        FailableAccept(self)
+    }
+
+    fn take_parts(&mut self) -> Option<(IO, ServerSession)> {
         match mem::replace(&mut self.0, server::MidHandshake::End) {
-            server::MidHandshake::Handshaking(server::TlsStream { io, .. }) => Some(io),
-            _ => None,
+            server::MidHandshake::Handshaking(stream) => Some(stream.into_inner()),
+            server::MidHandshake::End => None,
         }
     }
+
+    fn poll_handshake(&mut self) -> Poll<server::TlsStream<IO>, io::Error>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        if let Async::Ready(stream) = self.0.poll()? {
+            return Ok(Async::Ready(stream));
+        }
+
+        poll_deadline(&mut self.1).map_err(|err| {
+            if let server::MidHandshake::Handshaking(ref stream) = self.0 {
+                stream.handshake_failed(&err);
+            }
+            err
+        })?;
+        Ok(Async::NotReady)
+    }
 }
 
 impl<IO: AsyncRead + AsyncWrite> Future for Connect<IO> {
@@ -192,7 +722,10 @@ impl<IO: AsyncRead + AsyncWrite> Future for Connect<IO> {
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.0.poll()
+        self.poll_handshake().map_err(|err| {
+            self.take_inner();
+            err
+        })
     }
 }
 
@@ -201,6 +734,90 @@ impl<IO: AsyncRead + AsyncWrite> Future for Accept<IO> {
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        self.0.poll()
+        self.poll_handshake().map_err(|err| {
+            self.take_inner();
+            err
+        })
+    }
+}
+
+/// Like `Connect`, but fails with a `HandshakeFailure` carrying the IO and
+/// session instead of dropping them.
+pub struct FailableConnect<IO>(Connect<IO>);
+
+/// Like `Accept`, but fails with a `HandshakeFailure` carrying the IO and
+/// session instead of dropping them.
+pub struct FailableAccept<IO>(Accept<IO>);
+
+/// A failed handshake, along with the IO and the session it failed on.
+///
+/// This allows a caller to keep using the connection after the TLS handshake
+/// failed, e.g. to send a plaintext error or to log the peer address.
+pub struct HandshakeFailure<IO, S> {
+    pub error: io::Error,
+    pub io: IO,
+    pub session: S,
+}
+
+impl<IO, S> HandshakeFailure<IO, S> {
+    #[inline]
+    pub fn into_inner(self) -> (io::Error, IO, S) {
+        (self.error, self.io, self.session)
+    }
+}
+
+impl<IO, S> fmt::Debug for HandshakeFailure<IO, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HandshakeFailure")
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl<IO, S> fmt::Display for HandshakeFailure<IO, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl<IO, S> error::Error for HandshakeFailure<IO, S> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl<IO, S> From<HandshakeFailure<IO, S>> for io::Error {
+    fn from(failure: HandshakeFailure<IO, S>) -> io::Error {
+        failure.error
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite> Future for FailableConnect<IO> {
+    type Item = client::TlsStream<IO>;
+    type Error = HandshakeFailure<IO, ClientSession>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = &mut self.0;
+        inner
+            .poll_handshake()
+            .map_err(|error| match inner.take_parts() {
+                Some((io, session)) => HandshakeFailure { error, io, session },
+                None => unreachable!("handshake failed after completion"),
+            })
+    }
+}
+
+impl<IO: AsyncRead + AsyncWrite> Future for FailableAccept<IO> {
+    type Item = server::TlsStream<IO>;
+    type Error = HandshakeFailure<IO, ServerSession>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = &mut self.0;
+        inner
+            .poll_handshake()
+            .map_err(|error| match inner.take_parts() {
+                Some((io, session)) => HandshakeFailure { error, io, session },
+                None => unreachable!("handshake failed after completion"),
+            })
     }
 }
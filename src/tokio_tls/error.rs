@@ -0,0 +1,191 @@
+//! Classification of the errors the TLS streams fail with.
+//!
+//! Errors reach callers as `io::Error`s; `kind` looks at what they wrap to
+//! tell the categories apart. The crate's `Error::kind` and the labels of
+//! `metrics::Metrics` are both built on it.
+use super::verify::VerifyError;
+use rustls::TLSError;
+use std::error;
+use std::fmt;
+use std::io;
+
+/// The category of an `Error`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The peer's certificate has expired.
+    CertificateExpired,
+
+    /// The peer's certificate was not issued by a trusted authority.
+    UnknownIssuer,
+
+    /// The peer's certificate is not valid for the requested hostname.
+    HostnameMismatch,
+
+    /// The peer's certificate chain is invalid for another reason.
+    InvalidCertificate,
+
+    /// None of the peer's certificates matches a pinned public key.
+    PinMismatch,
+
+    /// The peer's public key differs from the one recorded on first use.
+    KnownHostChanged,
+
+    /// A verification callback rejected the peer's certificate.
+    CertificateRejected,
+
+    /// The peer sent a fatal alert. The parameter is the alert code.
+    AlertReceived(u8),
+
+    /// The peer does not support a protocol version or feature we require.
+    PeerIncompatible,
+
+    /// The peer deviated from the TLS protocol.
+    Protocol,
+
+    /// The underlying stream reached EOF before the handshake completed.
+    UnexpectedEof,
+
+    /// The handshake did not complete before the configured deadline.
+    HandshakeTimeout,
+
+    /// An I/O error on the underlying stream, including a read or write
+    /// that timed out.
+    Io,
+}
+
+/// The error wrapped in the `TimedOut` error a handshake fails with once its
+/// deadline has passed, telling it apart from IO that timed out.
+#[derive(Debug)]
+pub struct HandshakeTimedOut;
+
+impl fmt::Display for HandshakeTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TLS handshake timed out")
+    }
+}
+
+impl error::Error for HandshakeTimedOut {}
+
+/// The error a handshake fails with once its deadline has passed.
+pub(crate) fn handshake_timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, HandshakeTimedOut)
+}
+
+/// The category of an error returned from a TLS stream.
+pub fn kind(err: &io::Error) -> ErrorKind {
+    if err
+        .get_ref()
+        .map_or(false, |err| err.is::<HandshakeTimedOut>())
+    {
+        return ErrorKind::HandshakeTimeout;
+    }
+
+    let verify_error = err
+        .get_ref()
+        .and_then(|err| err.downcast_ref::<VerifyError>());
+    if let Some(err) = verify_error {
+        return match *err {
+            VerifyError::PinMismatch => ErrorKind::PinMismatch,
+            VerifyError::Rejected(_) => ErrorKind::CertificateRejected,
+            VerifyError::KnownHostChanged { .. } => ErrorKind::KnownHostChanged,
+        };
+    }
+
+    let tls_error = err.get_ref().and_then(|err| err.downcast_ref::<TLSError>());
+    if let Some(err) = tls_error {
+        return match *err {
+            TLSError::WebPKIError(webpki::Error::CertExpired) => ErrorKind::CertificateExpired,
+            TLSError::WebPKIError(webpki::Error::UnknownIssuer) => ErrorKind::UnknownIssuer,
+            TLSError::WebPKIError(webpki::Error::CertNotValidForName) => {
+                ErrorKind::HostnameMismatch
+            }
+            TLSError::WebPKIError(_) | TLSError::NoCertificatesPresented => {
+                ErrorKind::InvalidCertificate
+            }
+            TLSError::AlertReceived(alert) => ErrorKind::AlertReceived(alert.get_u8()),
+            TLSError::PeerIncompatibleError(_) => ErrorKind::PeerIncompatible,
+            _ => ErrorKind::Protocol,
+        };
+    }
+
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => ErrorKind::UnexpectedEof,
+        _ => ErrorKind::Io,
+    }
+}
+
+#[cfg(test)]
+mod test_error {
+    use super::*;
+    use crate::tokio_tls::pin::Pin;
+    use rustls::internal::msgs::enums::AlertDescription;
+
+    fn wrap<E>(err: E) -> io::Error
+    where
+        E: Into<Box<dyn error::Error + Send + Sync>>,
+    {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+
+    #[test]
+    fn test_kind() {
+        let changed = VerifyError::KnownHostChanged {
+            host: "localhost".into(),
+            known: Pin::sha256([1; 32]),
+            presented: Pin::sha256([2; 32]),
+        };
+        let cases = vec![
+            (
+                wrap(TLSError::WebPKIError(webpki::Error::CertExpired)),
+                ErrorKind::CertificateExpired,
+            ),
+            (
+                wrap(TLSError::WebPKIError(webpki::Error::UnknownIssuer)),
+                ErrorKind::UnknownIssuer,
+            ),
+            (
+                wrap(TLSError::WebPKIError(webpki::Error::CertNotValidForName)),
+                ErrorKind::HostnameMismatch,
+            ),
+            (
+                wrap(TLSError::WebPKIError(webpki::Error::BadDER)),
+                ErrorKind::InvalidCertificate,
+            ),
+            (
+                wrap(TLSError::NoCertificatesPresented),
+                ErrorKind::InvalidCertificate,
+            ),
+            (wrap(VerifyError::PinMismatch), ErrorKind::PinMismatch),
+            (wrap(changed), ErrorKind::KnownHostChanged),
+            (
+                wrap(VerifyError::Rejected("revoked".into())),
+                ErrorKind::CertificateRejected,
+            ),
+            (
+                wrap(TLSError::AlertReceived(AlertDescription::HandshakeFailure)),
+                ErrorKind::AlertReceived(40),
+            ),
+            (
+                wrap(TLSError::PeerIncompatibleError("no TLS 1.3".into())),
+                ErrorKind::PeerIncompatible,
+            ),
+            (wrap(TLSError::CorruptMessage), ErrorKind::Protocol),
+            (
+                io::ErrorKind::UnexpectedEof.into(),
+                ErrorKind::UnexpectedEof,
+            ),
+            (handshake_timed_out(), ErrorKind::HandshakeTimeout),
+            // A read timing out is not the handshake deadline passing.
+            (
+                io::Error::new(io::ErrorKind::TimedOut, "read timed out"),
+                ErrorKind::Io,
+            ),
+            (io::ErrorKind::ConnectionReset.into(), ErrorKind::Io),
+            (wrap("not a TLS error"), ErrorKind::Io),
+        ];
+
+        for (err, expected) in cases {
+            assert_eq!(kind(&err), expected, "{:?}", err);
+        }
+    }
+}
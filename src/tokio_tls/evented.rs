@@ -0,0 +1,244 @@
+//! Nonblocking TLS streams for event loops running directly on mio.
+//!
+//! The stream registers the underlying socket with a `mio::Poll` and reports
+//! which readiness the session is waiting for through `interest`. On each
+//! readiness event, call `handshake` until it stops returning `WouldBlock`,
+//! then read and write as usual; afterwards, `reregister` with the updated
+//! interest.
+use super::compat;
+use super::cork::Cork;
+use super::entry::{CloseStatus, PeerClose, TlsAcceptor, TlsConnector, TlsState};
+use super::observer::SharedObserver;
+use super::stats::Stats;
+use super::trace::ConnSpan;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+use rustls::{ClientSession, ServerSession, Session};
+use std::io::{self, Read, Write};
+use std::time::Duration;
+use tokio_io::io::AllowStdIo;
+use webpki::DNSNameRef;
+
+/// A TLS stream over a nonblocking, mio-registered IO.
+#[derive(Debug)]
+pub struct TlsStream<IO, S> {
+    io: IO,
+    session: S,
+    state: TlsState,
+    close: PeerClose,
+    cork: Cork,
+    stats: Stats,
+    observer: SharedObserver,
+    span: ConnSpan,
+}
+
+impl<IO> TlsStream<IO, ClientSession> {
+    /// Starts a client session over `io` using the connector's configuration.
+    pub fn connect(connector: &TlsConnector, domain: DNSNameRef, io: IO) -> Self {
+        let mut session = ClientSession::new(&connector.inner, domain);
+        connector.limit_buffers(&mut session);
+        connector.warn_expiry();
+
+        TlsStream {
+            io,
+            session,
+            state: TlsState::Stream,
+            close: PeerClose::new(connector.strict_close),
+            cork: Cork::new(connector.cork_limit, connector.cork_delay),
+            stats: Stats::new(),
+            observer: connector.observer.start_stream(),
+            span: ConnSpan::client(domain),
+        }
+    }
+}
+
+impl<IO> TlsStream<IO, ServerSession> {
+    /// Starts a server session over `io` using the acceptor's configuration.
+    pub fn accept(acceptor: &TlsAcceptor, io: IO) -> Self {
+        let mut session = ServerSession::new(&acceptor.inner);
+        acceptor.limit_buffers(&mut session);
+        acceptor.warn_expiry();
+
+        TlsStream {
+            io,
+            session,
+            state: TlsState::Stream,
+            close: PeerClose::new(acceptor.strict_close),
+            cork: Cork::new(acceptor.cork_limit, acceptor.cork_delay),
+            stats: Stats::new(),
+            observer: acceptor.observer.start_stream(),
+            span: ConnSpan::server(),
+        }
+    }
+}
+
+impl<IO, S: Session> TlsStream<IO, S> {
+    #[inline]
+    pub fn get_ref(&self) -> (&IO, &S) {
+        (&self.io, &self.session)
+    }
+
+    #[inline]
+    pub fn get_mut(&mut self) -> (&mut IO, &mut S) {
+        (&mut self.io, &mut self.session)
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> (IO, S) {
+        (self.io, self.session)
+    }
+
+    #[inline]
+    pub fn is_handshaking(&self) -> bool {
+        self.session.is_handshaking()
+    }
+
+    /// The current shutdown state of the stream.
+    #[inline]
+    pub fn shutdown_state(&self) -> TlsState {
+        self.state
+    }
+
+    /// How the peer ended its side of the stream.
+    #[inline]
+    pub fn close_status(&self) -> CloseStatus {
+        self.close.status
+    }
+
+    /// Traffic statistics for this stream.
+    #[inline]
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Hold back up to `limit` bytes of plaintext so that small writes share
+    /// TLS records; see `TlsConnector::cork`.
+    #[inline]
+    pub fn set_cork(&mut self, limit: usize, max_delay: Option<Duration>) {
+        self.cork.set(limit, max_delay);
+    }
+
+    /// The readiness the session is waiting for: readable while it wants to
+    /// read records, writable while it has records to send.
+    pub fn interest(&self) -> Ready {
+        let mut interest = Ready::empty();
+        if self.state.readable() && self.session.wants_read() {
+            interest |= Ready::readable();
+        }
+        if self.session.wants_write() {
+            interest |= Ready::writable();
+        }
+        interest
+    }
+}
+
+impl<IO: Read + Write, S: Session> TlsStream<IO, S> {
+    /// Advances the handshake as far as the IO allows, returning
+    /// `WouldBlock` until it has completed and been flushed.
+    pub fn handshake(&mut self) -> io::Result<()> {
+        let _span = self.span.enter();
+        compat::handshake(
+            &mut AllowStdIo::new(&mut self.io),
+            &mut self.session,
+            self.state,
+            &mut self.stats,
+            &self.observer,
+        )
+    }
+
+    /// Sends close_notify, leaving the read side open. Returns `WouldBlock`
+    /// until it has been flushed.
+    pub fn shutdown_write(&mut self) -> io::Result<()> {
+        let _span = self.span.enter();
+        // Shutting down `AllowStdIo` is a no-op, so the socket stays open.
+        compat::close(
+            &mut AllowStdIo::new(&mut self.io),
+            &mut self.session,
+            &mut self.state,
+            &mut self.cork,
+            &mut self.stats,
+            &self.observer,
+        )
+    }
+}
+
+impl<IO: Evented, S: Session> TlsStream<IO, S> {
+    /// Registers the IO with the interest the session currently needs.
+    pub fn register(&self, poll: &Poll, token: Token) -> io::Result<()> {
+        poll.register(&self.io, token, self.interest(), PollOpt::level())
+    }
+
+    /// Updates the registration to the interest the session currently needs.
+    /// Call this after handling each readiness event.
+    pub fn reregister(&self, poll: &Poll, token: Token) -> io::Result<()> {
+        poll.reregister(&self.io, token, self.interest(), PollOpt::level())
+    }
+}
+
+impl<IO: Evented, S> Evented for TlsStream<IO, S> {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.io.register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        self.io.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.io.deregister(poll)
+    }
+}
+
+impl<IO: Read + Write, S: Session> Read for TlsStream<IO, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let _span = self.span.enter();
+        compat::read(
+            &mut AllowStdIo::new(&mut self.io),
+            &mut self.session,
+            &mut self.state,
+            &mut self.close,
+            &mut self.cork,
+            &mut self.stats,
+            &self.observer,
+            buf,
+        )
+    }
+}
+
+impl<IO: Read + Write, S: Session> Write for TlsStream<IO, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _span = self.span.enter();
+        compat::write(
+            &mut AllowStdIo::new(&mut self.io),
+            &mut self.session,
+            self.state,
+            &mut self.cork,
+            &mut self.stats,
+            &self.observer,
+            buf,
+        )
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let _span = self.span.enter();
+        compat::flush(
+            &mut AllowStdIo::new(&mut self.io),
+            &mut self.session,
+            self.state,
+            &mut self.cork,
+            &mut self.stats,
+            &self.observer,
+        )
+    }
+}
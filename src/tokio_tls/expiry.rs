@@ -0,0 +1,336 @@
+//! Expiry of the certificate a connector or acceptor authenticates with.
+//!
+//! The leaf certificate is given to the connector or acceptor with
+//! `certificate`, alongside the configuration that was set up with it. It is
+//! not looked up through the configuration's certificate resolver, which may
+//! be the application's own and pick a certificate per server name, unless
+//! an acceptor asks for it with `certificate_from_handshake`.
+use super::x509;
+use rustls::{
+    Certificate, ClientConfig, ClientSession, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, ServerConfig, ServerSession, Session, TLSError,
+};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use webpki::DNSNameRef;
+
+/// When a certificate expires.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CertExpiry {
+    pub not_after: SystemTime,
+}
+
+impl CertExpiry {
+    /// Reads the expiry of a DER-encoded certificate, failing with
+    /// `InvalidData` if it can not be parsed.
+    pub fn from_certificate(cert: &Certificate) -> io::Result<CertExpiry> {
+        match x509::not_after(&cert.0) {
+            Some(not_after) => Ok(CertExpiry { not_after }),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "failed to read the certificate's expiry",
+            )),
+        }
+    }
+
+    /// How long until the certificate expires, or `None` if it already has.
+    pub fn time_left(&self) -> Option<Duration> {
+        self.not_after.duration_since(SystemTime::now()).ok()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.time_left().is_none()
+    }
+
+    /// Fails with the same error as a peer rejecting the certificate if it
+    /// has expired.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        if self.is_expired() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                TLSError::WebPKIError(webpki::Error::CertExpired),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The leaf certificate `config` serves, found by handshaking with it in
+/// memory as a client that sends no server name.
+///
+/// The client checks the server's signature with the certificate before it
+/// accepts it, so this also shows that the configuration holds the
+/// certificate's key. Fails with `InvalidData` if the handshake does.
+pub(crate) fn served_certificate(config: &Arc<ServerConfig>) -> io::Result<Certificate> {
+    let capture = Arc::new(Capture(Mutex::new(None)));
+    let mut client_config = ClientConfig::new();
+    client_config.enable_sni = false;
+    client_config
+        .dangerous()
+        .set_certificate_verifier(capture.clone());
+
+    // Without SNI, the name is only seen by the verifier, which ignores it.
+    let name = DNSNameRef::try_from_ascii_str("invalid").unwrap();
+    let mut client = ClientSession::new(&Arc::new(client_config), name);
+    let mut server = ServerSession::new(config);
+
+    // The client verifies the certificate and the signature made with its
+    // key as soon as it has the server's first flight.
+    transfer(&mut client, &mut server)?;
+    transfer(&mut server, &mut client)?;
+
+    let leaf = capture.0.lock().unwrap().take();
+    leaf.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the handshake did not get to the server certificate",
+        )
+    })
+}
+
+/// Accepts any certificate chain, keeping its leaf; the handshake still
+/// checks the server's signature with it.
+struct Capture(Mutex<Option<Certificate>>);
+
+impl ServerCertVerifier for Capture {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let leaf = presented.first().ok_or(TLSError::NoCertificatesPresented)?;
+        *self.0.lock().unwrap() = Some(leaf.clone());
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Moves everything `from` has to send into `to` and processes it.
+fn transfer<A: Session, B: Session>(from: &mut A, to: &mut B) -> io::Result<()> {
+    let mut buf = Vec::new();
+    while from.wants_write() {
+        from.write_tls(&mut buf)?;
+    }
+
+    let mut rd = &buf[..];
+    while !rd.is_empty() {
+        to.read_tls(&mut rd)?;
+        to.process_new_packets()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    }
+    Ok(())
+}
+
+/// Calls back once when a certificate gets within a threshold of expiring.
+pub struct ExpiryWarning {
+    expiry: CertExpiry,
+    warn_at: SystemTime,
+    fired: AtomicBool,
+    callback: Box<dyn Fn(&CertExpiry) + Send + Sync>,
+}
+
+impl ExpiryWarning {
+    pub(crate) fn new<F>(expiry: CertExpiry, threshold: Duration, callback: F) -> Arc<ExpiryWarning>
+    where
+        F: Fn(&CertExpiry) + Send + Sync + 'static,
+    {
+        let warning = ExpiryWarning {
+            expiry,
+            warn_at: expiry
+                .not_after
+                .checked_sub(threshold)
+                .unwrap_or(SystemTime::UNIX_EPOCH),
+            fired: AtomicBool::new(false),
+            callback: Box::new(callback),
+        };
+        warning.check();
+        Arc::new(warning)
+    }
+
+    /// Fires the warning if the threshold has been crossed and it has not
+    /// fired yet.
+    pub(crate) fn check(&self) {
+        if SystemTime::now() < self.warn_at || self.fired.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        tls_event!(
+            warn,
+            not_after = ?self.expiry.not_after,
+            "TLS certificate is about to expire"
+        );
+        (self.callback)(&self.expiry);
+    }
+}
+
+impl fmt::Debug for ExpiryWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ExpiryWarning")
+            .field("expiry", &self.expiry)
+            .field("warn_at", &self.warn_at)
+            .field("fired", &self.fired)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test_expiry {
+    use super::*;
+    use rustls::internal::pemfile::{certs, rsa_private_keys};
+    use rustls::sign::CertifiedKey;
+    use rustls::{NoClientAuth, ResolvesServerCert, ResolvesServerCertUsingSNI, SignatureScheme};
+    use std::io::{BufReader, Cursor};
+    use std::sync::atomic::AtomicUsize;
+    use webpki::DNSNameRef;
+
+    const CHAIN: &[u8] = include_bytes!("../../tests/end.chain");
+    const RSA: &[u8] = include_bytes!("../../tests/end.rsa");
+    const EXPIRED: &[u8] = include_bytes!("../../tests/expired.cert");
+    const EXPIRED_RSA: &[u8] = include_bytes!("../../tests/expired.rsa");
+
+    fn leaf(chain: &[u8]) -> Certificate {
+        certs(&mut BufReader::new(Cursor::new(chain)))
+            .unwrap()
+            .remove(0)
+    }
+
+    fn server_config(chain: &[u8], key: &[u8]) -> ServerConfig {
+        let chain = certs(&mut BufReader::new(Cursor::new(chain))).unwrap();
+        let mut keys = rsa_private_keys(&mut BufReader::new(Cursor::new(key))).unwrap();
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.set_single_cert(chain, keys.remove(0)).unwrap();
+        config
+    }
+
+    /// A resolver that must only be called during handshakes.
+    struct HandshakeOnly;
+
+    impl ResolvesServerCert for HandshakeOnly {
+        fn resolve(&self, _: Option<DNSNameRef>, _: &[SignatureScheme]) -> Option<CertifiedKey> {
+            panic!("resolver called outside a handshake");
+        }
+    }
+
+    #[test]
+    fn test_from_certificate() {
+        let expiry = CertExpiry::from_certificate(&leaf(CHAIN)).unwrap();
+        assert!(!expiry.is_expired());
+        assert!(expiry.check().is_ok());
+
+        let expiry = CertExpiry::from_certificate(&leaf(EXPIRED)).unwrap();
+        assert!(expiry.is_expired());
+        assert_eq!(
+            expiry.check().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let err = CertExpiry::from_certificate(&Certificate(vec![0x30, 0x00])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_warning_fires_once() {
+        let expiry = CertExpiry::from_certificate(&leaf(CHAIN)).unwrap();
+        let left = expiry.time_left().unwrap();
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let counter = fired.clone();
+        let warning = ExpiryWarning::new(expiry, left + Duration::from_secs(3600), move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        warning.check();
+        warning.check();
+        assert_eq!(fired.load(Ordering::Relaxed), 1);
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let counter = fired.clone();
+        let warning = ExpiryWarning::new(expiry, Duration::from_secs(86400), move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        warning.check();
+        assert_eq!(fired.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_acceptor_check_expiry() {
+        use super::super::entry::TlsAcceptor;
+
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(CHAIN, RSA)))
+            .certificate(&leaf(CHAIN))
+            .unwrap();
+        assert!(acceptor.check_expiry().is_ok());
+
+        let fired = Arc::new(AtomicUsize::new(0));
+        let counter = fired.clone();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(EXPIRED, EXPIRED_RSA)))
+            .certificate(&leaf(EXPIRED))
+            .unwrap()
+            .expiry_warning(Duration::from_secs(86400), move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+            });
+        assert_eq!(fired.load(Ordering::Relaxed), 1);
+        let err = acceptor.check_expiry().unwrap_err();
+        let err = err.get_ref().and_then(|err| err.downcast_ref::<TLSError>());
+        assert_eq!(
+            err,
+            Some(&TLSError::WebPKIError(webpki::Error::CertExpired))
+        );
+    }
+
+    #[test]
+    fn test_served_certificate() {
+        let config = Arc::new(server_config(CHAIN, RSA));
+        assert_eq!(served_certificate(&config).unwrap(), leaf(CHAIN));
+
+        // A key that does not belong to the certificate fails the client's
+        // check of the server's signature.
+        let config = Arc::new(server_config(CHAIN, EXPIRED_RSA));
+        let err = served_certificate(&config).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // A resolver that picks certificates by server name has none to
+        // serve without one.
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = Arc::new(ResolvesServerCertUsingSNI::new());
+        let err = served_certificate(&Arc::new(config)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_acceptor_certificate_from_handshake() {
+        use super::super::entry::TlsAcceptor;
+
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(EXPIRED, EXPIRED_RSA)))
+            .certificate_from_handshake()
+            .unwrap();
+        assert!(acceptor.cert_expiry.unwrap().is_expired());
+        assert!(acceptor.check_expiry().is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "`expiry_warning` called before `certificate`")]
+    fn test_expiry_warning_without_certificate() {
+        use super::super::entry::TlsAcceptor;
+
+        TlsAcceptor::from(Arc::new(server_config(CHAIN, RSA)))
+            .expiry_warning(Duration::from_secs(86400), |_| ());
+    }
+
+    #[test]
+    fn test_resolver_not_called() {
+        use super::super::entry::TlsAcceptor;
+
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = Arc::new(HandshakeOnly);
+
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        assert_eq!(acceptor.cert_expiry, None);
+        assert!(acceptor.check_expiry().is_ok());
+
+        let acceptor = acceptor.certificate(&leaf(EXPIRED)).unwrap();
+        assert!(acceptor.cert_expiry.unwrap().is_expired());
+    }
+}
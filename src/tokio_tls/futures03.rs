@@ -0,0 +1,181 @@
+//! `std::future` flavour of the TLS streams, for IO implementing the futures
+//! 0.3 `AsyncRead`/`AsyncWrite` traits.
+//!
+//! The handshake and the client/server streams are driven by the same
+//! `common::Stream` I/O loop as the futures 0.1 flavour; see `std_future`
+//! for how the IO is adapted to it.
+use super::client;
+use super::server;
+use super::std_future::{self, PollIo, PollStream, Sleep, Timer};
+use futures_io::{AsyncRead, AsyncWrite};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Marks the futures 0.3 flavour of the `std_future` types.
+pub enum Futures03 {}
+
+/// Deadlines sleep on the async-io timer, which runs on a thread of its own
+/// and so works under any executor.
+impl Timer for Futures03 {
+    fn sleep_until(at: Instant) -> Sleep {
+        Box::pin(async move {
+            async_io::Timer::at(at).await;
+        })
+    }
+}
+
+/// A wrapper around an `entry::TlsConnector`, providing a `connect` method
+/// returning a `std::future::Future`.
+pub type TlsConnector = std_future::TlsConnector<Futures03>;
+
+/// A wrapper around an `entry::TlsAcceptor`, providing an `accept` method
+/// returning a `std::future::Future`.
+pub type TlsAcceptor = std_future::TlsAcceptor<Futures03>;
+
+/// Future returned from `TlsConnector::connect` which will resolve once the
+/// connection handshake has finished.
+pub type Connect<IO> = std_future::Connect<IO, Futures03>;
+
+/// Future returned from `TlsAcceptor::accept` which will resolve once the
+/// accept handshake has finished.
+pub type Accept<IO> = std_future::Accept<IO, Futures03>;
+
+/// Like `Connect`, but fails with an `entry::HandshakeFailure` carrying the
+/// IO and session; see `Connect::into_failable`.
+pub type FailableConnect<IO> = std_future::FailableConnect<IO, Futures03>;
+
+/// Like `Accept`, but fails with an `entry::HandshakeFailure` carrying the
+/// IO and session; see `Accept::into_failable`.
+pub type FailableAccept<IO> = std_future::FailableAccept<IO, Futures03>;
+
+/// Sends close_notify and flushes it, but keeps the read side open so that
+/// the peer can finish sending.
+///
+/// Unlike closing the stream, this does not shut down the underlying IO.
+pub fn shutdown_write<S>(stream: &mut S) -> std_future::ShutdownWrite<S, Futures03>
+where
+    S: PollStream<Futures03>,
+{
+    std_future::ShutdownWrite::new(stream)
+}
+
+/// Sends close_notify and waits for the peer's close_notify, resolving to
+/// the stream, or failing with a `TimedOut` error once `timeout` elapses.
+///
+/// Application data the peer sends in the meantime is discarded.
+pub fn close<S>(stream: S, timeout: Duration) -> std_future::Close<S, Futures03>
+where
+    S: PollStream<Futures03> + Unpin,
+{
+    std_future::Close::new(stream, timeout)
+}
+
+/// The readable half of a stream, from `split`.
+pub type ReadHalf<S> = std_future::ReadHalf<S, Futures03>;
+
+/// The writable half of a stream, from `split`.
+pub type WriteHalf<S> = std_future::WriteHalf<S, Futures03>;
+
+/// Splits a stream into halves that can be used from different tasks.
+/// `ReadHalf::unsplit` puts them back together.
+pub fn split<S>(stream: S) -> (ReadHalf<S>, WriteHalf<S>)
+where
+    S: PollStream<Futures03>,
+{
+    std_future::split(stream)
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> PollIo<Futures03> for IO {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(self).poll_read(cx, buf)
+    }
+
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(self).poll_write(cx, buf)
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(self).poll_flush(cx)
+    }
+
+    fn poll_shutdown(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(self).poll_close(cx)
+    }
+}
+
+macro_rules! futures03_stream {
+    ($stream:ident) => {
+        impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncRead for $stream::TlsStream<IO> {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                PollStream::<Futures03>::poll_tls_read(self.get_mut(), cx, buf)
+            }
+        }
+
+        impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncWrite for $stream::TlsStream<IO> {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                PollStream::<Futures03>::poll_tls_write(self.get_mut(), cx, buf)
+            }
+
+            fn poll_write_vectored(
+                self: Pin<&mut Self>,
+                cx: &mut Context,
+                bufs: &[io::IoSlice],
+            ) -> Poll<io::Result<usize>> {
+                PollStream::<Futures03>::poll_tls_write_vectored(self.get_mut(), cx, bufs)
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                PollStream::<Futures03>::poll_tls_flush(self.get_mut(), cx)
+            }
+
+            fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                PollStream::<Futures03>::poll_tls_close(self.get_mut(), cx)
+            }
+        }
+    };
+}
+
+futures03_stream!(client);
+futures03_stream!(server);
+
+impl<S: PollStream<Futures03>> AsyncRead for ReadHalf<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_read(cx, buf)
+    }
+}
+
+impl<S: PollStream<Futures03>> AsyncWrite for WriteHalf<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        bufs: &[io::IoSlice],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write_vectored(cx, bufs)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_close(cx)
+    }
+}
@@ -0,0 +1,373 @@
+//! Handshake and connection metrics in the Prometheus text format.
+//!
+//! `Metrics` is an `Observer`: register it on connectors and acceptors with
+//! their `observer` builders, and serve `render()` from whatever HTTP
+//! endpoint the application already has.
+use super::error::{self, ErrorKind};
+use super::expiry::CertExpiry;
+use super::observer::{HandshakeInfo, Observer};
+use rustls::Certificate;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Upper bounds of the handshake latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters for the streams of any number of connectors and acceptors.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    started: AtomicU64,
+    completed: AtomicU64,
+    /// Completed handshakes of acceptor streams, the only ones that report
+    /// resumed sessions.
+    accepted: AtomicU64,
+    dropped: AtomicU64,
+    resumed: AtomicU64,
+    /// Completed handshakes by latency bucket; the last one is `+Inf`.
+    latency: [AtomicU64; 12],
+    latency_micros: AtomicU64,
+    labelled: Mutex<Labelled>,
+    not_after: Mutex<Option<SystemTime>>,
+}
+
+#[derive(Debug, Default)]
+struct Labelled {
+    failed: BTreeMap<&'static str, u64>,
+    versions: BTreeMap<String, u64>,
+    cipher_suites: BTreeMap<String, u64>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Export the seconds until `cert` expires. Pass the leaf certificate
+    /// the acceptor serves, or the client certificate of a connector.
+    pub fn set_certificate(&self, cert: &Certificate) -> io::Result<()> {
+        let expiry = CertExpiry::from_certificate(cert)?;
+        *self.not_after.lock().unwrap() = Some(expiry.not_after);
+        Ok(())
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let started = self.started.load(Ordering::Relaxed);
+        let completed = self.completed.load(Ordering::Relaxed);
+        let accepted = self.accepted.load(Ordering::Relaxed);
+        let resumed = self.resumed.load(Ordering::Relaxed);
+        let dropped = self.dropped.load(Ordering::Relaxed);
+
+        header(
+            &mut out,
+            "tls_handshakes_total",
+            "counter",
+            "TLS handshakes started.",
+        );
+        sample(&mut out, "tls_handshakes_total", "", started);
+
+        header(
+            &mut out,
+            "tls_handshakes_completed_total",
+            "counter",
+            "TLS handshakes that completed.",
+        );
+        sample(&mut out, "tls_handshakes_completed_total", "", completed);
+
+        let labelled = self.labelled.lock().unwrap();
+        header(
+            &mut out,
+            "tls_handshakes_failed_total",
+            "counter",
+            "TLS handshakes that failed, by kind of error.",
+        );
+        for (kind, count) in &labelled.failed {
+            let labels = format!("{{kind=\"{}\"}}", kind);
+            sample(&mut out, "tls_handshakes_failed_total", &labels, *count);
+        }
+
+        header(
+            &mut out,
+            "tls_protocol_version_total",
+            "counter",
+            "Completed TLS handshakes by negotiated protocol version.",
+        );
+        for (version, count) in &labelled.versions {
+            let labels = format!("{{version=\"{}\"}}", version);
+            sample(&mut out, "tls_protocol_version_total", &labels, *count);
+        }
+
+        header(
+            &mut out,
+            "tls_cipher_suite_total",
+            "counter",
+            "Completed TLS handshakes by negotiated cipher suite.",
+        );
+        for (suite, count) in &labelled.cipher_suites {
+            let labels = format!("{{suite=\"{}\"}}", suite);
+            sample(&mut out, "tls_cipher_suite_total", &labels, *count);
+        }
+        drop(labelled);
+
+        header(
+            &mut out,
+            "tls_sessions_resumed_total",
+            "counter",
+            "TLS handshakes that acceptors completed by resuming a session.",
+        );
+        sample(&mut out, "tls_sessions_resumed_total", "", resumed);
+
+        header(
+            &mut out,
+            "tls_resumption_ratio",
+            "gauge",
+            "Resumed sessions per TLS handshake that acceptors completed.",
+        );
+        let ratio = if accepted == 0 {
+            0.0
+        } else {
+            resumed as f64 / accepted as f64
+        };
+        let _ = writeln!(out, "tls_resumption_ratio {}", ratio);
+
+        header(
+            &mut out,
+            "tls_handshake_duration_seconds",
+            "histogram",
+            "How long completed TLS handshakes took.",
+        );
+        let mut cumulative = 0;
+        for (i, count) in self.latency.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let labels = match LATENCY_BUCKETS.get(i) {
+                Some(le) => format!("{{le=\"{}\"}}", le),
+                None => "{le=\"+Inf\"}".to_string(),
+            };
+            sample(
+                &mut out,
+                "tls_handshake_duration_seconds_bucket",
+                &labels,
+                cumulative,
+            );
+        }
+        let sum = self.latency_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "tls_handshake_duration_seconds_sum {}", sum);
+        sample(
+            &mut out,
+            "tls_handshake_duration_seconds_count",
+            "",
+            cumulative,
+        );
+
+        header(
+            &mut out,
+            "tls_active_streams",
+            "gauge",
+            "TLS streams that are handshaking or established.",
+        );
+        sample(
+            &mut out,
+            "tls_active_streams",
+            "",
+            started.saturating_sub(dropped),
+        );
+
+        if let Some(not_after) = *self.not_after.lock().unwrap() {
+            header(
+                &mut out,
+                "tls_certificate_expiry_seconds",
+                "gauge",
+                "Seconds until the configured certificate expires.",
+            );
+            let seconds = match not_after.duration_since(SystemTime::now()) {
+                Ok(left) => left.as_secs() as i64,
+                Err(err) => -(err.duration().as_secs() as i64),
+            };
+            let _ = writeln!(out, "tls_certificate_expiry_seconds {}", seconds);
+        }
+
+        out
+    }
+
+    fn observe_latency(&self, duration: Duration) {
+        let seconds = duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9;
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|&le| seconds <= le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+        let micros = duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros());
+        self.latency_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+}
+
+impl Observer for Metrics {
+    fn handshake_start(&self) {
+        self.started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stream_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn session_resumed(&self) {
+        self.resumed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn handshake_complete(&self, info: &HandshakeInfo) {
+        self.completed.fetch_add(1, Ordering::Relaxed);
+        if info.server {
+            self.accepted.fetch_add(1, Ordering::Relaxed);
+        }
+        self.observe_latency(info.duration);
+
+        let mut labelled = self.labelled.lock().unwrap();
+        if let Some(version) = info.protocol_version {
+            *labelled
+                .versions
+                .entry(format!("{:?}", version))
+                .or_insert(0) += 1;
+        }
+        if let Some(suite) = info.cipher_suite {
+            *labelled
+                .cipher_suites
+                .entry(format!("{:?}", suite.suite))
+                .or_insert(0) += 1;
+        }
+    }
+
+    fn handshake_failed(&self, err: &io::Error) {
+        let mut labelled = self.labelled.lock().unwrap();
+        *labelled.failed.entry(label(error::kind(err))).or_insert(0) += 1;
+    }
+}
+
+/// The label for a failed handshake.
+fn label(kind: ErrorKind) -> &'static str {
+    match kind {
+        ErrorKind::CertificateExpired => "certificate_expired",
+        ErrorKind::UnknownIssuer => "unknown_issuer",
+        ErrorKind::HostnameMismatch => "hostname_mismatch",
+        ErrorKind::InvalidCertificate => "invalid_certificate",
+        ErrorKind::PinMismatch => "pin_mismatch",
+        ErrorKind::KnownHostChanged => "known_host_changed",
+        ErrorKind::CertificateRejected => "certificate_rejected",
+        ErrorKind::AlertReceived(_) => "alert_received",
+        ErrorKind::PeerIncompatible => "peer_incompatible",
+        ErrorKind::Protocol => "protocol",
+        ErrorKind::UnexpectedEof => "unexpected_eof",
+        ErrorKind::HandshakeTimeout => "handshake_timeout",
+        ErrorKind::Io => "io",
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: u64) {
+    let _ = writeln!(out, "{}{} {}", name, labels, value);
+}
+
+#[cfg(test)]
+mod test_metrics {
+    use super::*;
+    use rustls::internal::pemfile::certs;
+    use rustls::{ProtocolVersion, TLSError, ALL_CIPHERSUITES};
+    use std::io::{BufReader, Cursor};
+
+    const CHAIN: &[u8] = include_bytes!("../../tests/end.chain");
+
+    fn lines(metrics: &Metrics) -> Vec<String> {
+        metrics
+            .render()
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        for _ in 0..3 {
+            metrics.handshake_start();
+        }
+        metrics.session_resumed();
+        metrics.handshake_complete(&HandshakeInfo {
+            duration: Duration::from_millis(30),
+            protocol_version: Some(ProtocolVersion::TLSv1_3),
+            cipher_suite: Some(ALL_CIPHERSUITES[0]),
+            alpn_protocol: None,
+            server: true,
+        });
+        metrics.handshake_complete(&HandshakeInfo {
+            duration: Duration::from_millis(3),
+            protocol_version: Some(ProtocolVersion::TLSv1_2),
+            cipher_suite: Some(ALL_CIPHERSUITES[0]),
+            alpn_protocol: None,
+            server: false,
+        });
+        metrics.handshake_failed(&error::handshake_timed_out());
+        metrics.handshake_failed(&io::Error::new(
+            io::ErrorKind::InvalidData,
+            TLSError::WebPKIError(webpki::Error::UnknownIssuer),
+        ));
+        metrics.stream_dropped();
+
+        let suite = format!("{:?}", ALL_CIPHERSUITES[0].suite);
+        let lines = lines(&metrics);
+        for expected in &[
+            "tls_handshakes_total 3".to_string(),
+            "tls_handshakes_completed_total 2".to_string(),
+            "tls_handshakes_failed_total{kind=\"handshake_timeout\"} 1".to_string(),
+            "tls_handshakes_failed_total{kind=\"unknown_issuer\"} 1".to_string(),
+            "tls_protocol_version_total{version=\"TLSv1_3\"} 1".to_string(),
+            "tls_protocol_version_total{version=\"TLSv1_2\"} 1".to_string(),
+            format!("tls_cipher_suite_total{{suite=\"{}\"}} 2", suite),
+            "tls_sessions_resumed_total 1".to_string(),
+            "tls_resumption_ratio 1".to_string(),
+            "tls_handshake_duration_seconds_bucket{le=\"0.025\"} 1".to_string(),
+            "tls_handshake_duration_seconds_bucket{le=\"0.05\"} 2".to_string(),
+            "tls_handshake_duration_seconds_bucket{le=\"+Inf\"} 2".to_string(),
+            "tls_handshake_duration_seconds_sum 0.033".to_string(),
+            "tls_handshake_duration_seconds_count 2".to_string(),
+            "tls_active_streams 2".to_string(),
+        ] {
+            assert!(
+                lines.contains(expected),
+                "missing {:?} in {:#?}",
+                expected,
+                lines
+            );
+        }
+        assert!(!lines.iter().any(|line| line.starts_with("tls_certificate")));
+    }
+
+    #[test]
+    fn test_certificate_expiry() {
+        let chain = certs(&mut BufReader::new(Cursor::new(CHAIN))).unwrap();
+        let metrics = Metrics::new();
+        metrics.set_certificate(&chain[0]).unwrap();
+
+        let line = lines(&metrics)
+            .into_iter()
+            .find(|line| line.starts_with("tls_certificate_expiry_seconds "))
+            .unwrap();
+        let seconds: i64 = line.split(' ').nth(1).unwrap().parse().unwrap();
+        assert!(seconds > 0);
+
+        let err = metrics
+            .set_certificate(&Certificate(vec![0x30, 0x00]))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
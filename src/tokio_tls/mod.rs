@@ -1,13 +1,60 @@
+// Declared first so that its macros are visible in all other modules.
+#[macro_use]
+pub mod trace;
+
+#[cfg(feature = "async-std")]
+pub mod async_std;
+pub mod callback;
 pub mod client;
+pub mod close;
 pub mod common;
+pub mod completion;
+#[cfg(any(feature = "futures03", feature = "tokio1", feature = "mio"))]
+#[macro_use]
+mod compat;
+pub mod cork;
+mod deadline;
+pub mod engine;
 pub mod entry;
+pub mod error;
+pub mod expiry;
+#[cfg(feature = "mio")]
+pub mod evented;
+#[cfg(feature = "futures03")]
+pub mod futures03;
+pub mod metrics;
+pub mod observer;
+pub mod pin;
 pub mod server;
+pub mod split;
+pub mod stats;
+#[cfg(any(feature = "futures03", feature = "tokio1"))]
+pub mod std_future;
+pub mod tofu;
+#[cfg(feature = "tokio1")]
+pub mod tokio1;
 pub mod vecbuf;
+pub mod verify;
+mod x509;
+
+#[cfg(test)]
+mod test_stream;
 
 pub extern crate rustls;
 pub extern crate webpki;
 
+extern crate base64;
 extern crate bytes;
 extern crate futures;
+#[cfg(feature = "futures03")]
+extern crate futures_io;
 extern crate iovec;
+extern crate ring;
+#[cfg(feature = "mio")]
+extern crate mio;
+#[cfg(feature = "tokio1")]
+extern crate tokio;
 extern crate tokio_io;
+extern crate tokio_timer;
+#[cfg(feature = "tracing")]
+extern crate tracing;
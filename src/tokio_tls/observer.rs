@@ -0,0 +1,509 @@
+//! Callbacks for the lifecycle of TLS streams.
+//!
+//! An `Observer` registered on a connector or acceptor is told about every
+//! stream it creates: the handshake starting, progressing and completing or
+//! failing, alerts going either way, and the peer closing its side. This is
+//! meant for metrics and audit logging; the callbacks can not change how the
+//! stream behaves.
+use super::entry::CloseStatus;
+use rustls::internal::msgs::enums::{ContentType, SignatureAlgorithm};
+use rustls::sign::{CertifiedKey, Signer, SigningKey};
+use rustls::{
+    Certificate, ProtocolVersion, ResolvesServerCert, ServerConfig, Session, SignatureScheme,
+    SupportedCipherSuite, TLSError,
+};
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use webpki::DNSNameRef;
+
+/// The alert code for close_notify.
+pub const CLOSE_NOTIFY: u8 = 0;
+
+/// Receives lifecycle events of TLS streams. All methods do nothing by
+/// default.
+///
+/// The callbacks run inline on the task driving the stream, so they should
+/// be quick.
+pub trait Observer: Send + Sync {
+    /// A stream was created and is about to start its handshake.
+    fn handshake_start(&self) {}
+
+    /// A stream that reported `handshake_start` was dropped, whether or not
+    /// its handshake completed.
+    fn stream_dropped(&self) {}
+
+    /// A server received a ClientHello asking for `server_name` through SNI
+    /// and offering `sigschemes`. Only called for acceptors.
+    ///
+    /// rustls picks the certificate before it looks for a session to resume,
+    /// so this is called for resumed handshakes too. It is called twice for
+    /// a handshake where the server asks the client to retry its ClientHello
+    /// with another key share.
+    fn client_hello(&self, _server_name: Option<DNSNameRef>, _sigschemes: &[SignatureScheme]) {}
+
+    /// The handshake resumed an earlier session instead of authenticating
+    /// the server with its certificate. Only called for acceptors, right
+    /// before `handshake_complete`.
+    fn session_resumed(&self) {}
+
+    /// The peer's certificate chain was verified. Called right before
+    /// `handshake_complete` if the peer presented certificates.
+    fn certificate_verified(&self, _chain: &[Certificate]) {}
+
+    /// The handshake completed.
+    fn handshake_complete(&self, _info: &HandshakeInfo) {}
+
+    /// The handshake failed; `err.kind()` tells timeouts, truncation and
+    /// protocol errors apart, and for the latter the inner error is the
+    /// `rustls::TLSError`.
+    fn handshake_failed(&self, _err: &io::Error) {}
+
+    /// An alert was sent to the peer: close_notify, or one of the fatal
+    /// alerts rustls is known to send when it rejects what the peer sent.
+    /// Other fatal alerts only show up as the error they accompany.
+    fn alert_sent(&self, _code: u8) {}
+
+    /// An alert was received from the peer, including close_notify.
+    fn alert_received(&self, _code: u8) {}
+
+    /// The peer ended its side of the stream, either with close_notify or by
+    /// closing the connection.
+    fn close(&self, _status: CloseStatus) {}
+}
+
+/// The parameters a handshake negotiated.
+#[derive(Debug)]
+pub struct HandshakeInfo<'a> {
+    /// How long the handshake took, measured from `handshake_start`.
+    pub duration: Duration,
+    pub protocol_version: Option<ProtocolVersion>,
+    pub cipher_suite: Option<&'static SupportedCipherSuite>,
+    pub alpn_protocol: Option<&'a [u8]>,
+    /// Whether this is the server side of the stream, created by an
+    /// acceptor.
+    pub server: bool,
+}
+
+impl<'a> HandshakeInfo<'a> {
+    pub(crate) fn new<S: Session>(
+        session: &'a S,
+        duration: Duration,
+        server: bool,
+    ) -> HandshakeInfo<'a> {
+        HandshakeInfo {
+            duration,
+            protocol_version: session.get_protocol_version(),
+            cipher_suite: session.get_negotiated_ciphersuite(),
+            alpn_protocol: session.get_alpn_protocol(),
+            server,
+        }
+    }
+}
+
+/// An optional observer, shared between a connector or acceptor and the
+/// streams it creates.
+pub struct SharedObserver {
+    observer: Option<Arc<dyn Observer>>,
+    /// Whether this belongs to an acceptor rather than a connector.
+    server: bool,
+    /// Whether this is the handle of a stream, which reports
+    /// `stream_dropped` when dropped.
+    stream: bool,
+    /// Whether the certificate resolver saw the stream's ClientHello.
+    resolved: AtomicBool,
+    /// Whether the stream's handshake signed anything with the key of the
+    /// server certificate, which only a full handshake does.
+    signed: AtomicBool,
+    /// When this was created; for a stream, when its handshake started.
+    started: Instant,
+}
+
+impl SharedObserver {
+    pub fn new(observer: Arc<dyn Observer>) -> SharedObserver {
+        SharedObserver {
+            observer: Some(observer),
+            server: false,
+            stream: false,
+            resolved: AtomicBool::new(false),
+            signed: AtomicBool::new(false),
+            started: Instant::now(),
+        }
+    }
+
+    /// Like `new`, for the observer of an acceptor.
+    pub fn new_server(observer: Arc<dyn Observer>) -> SharedObserver {
+        SharedObserver {
+            observer: Some(observer),
+            server: true,
+            stream: false,
+            resolved: AtomicBool::new(false),
+            signed: AtomicBool::new(false),
+            started: Instant::now(),
+        }
+    }
+
+    #[inline]
+    pub fn is_server(&self) -> bool {
+        self.server
+    }
+
+    #[inline]
+    pub fn get(&self) -> Option<&dyn Observer> {
+        self.observer.as_ref().map(|observer| &**observer)
+    }
+
+    /// Reports `handshake_start` and returns the handle for the new stream.
+    pub(crate) fn start_stream(&self) -> SharedObserver {
+        if let Some(observer) = self.get() {
+            observer.handshake_start();
+        }
+        SharedObserver {
+            observer: self.observer.clone(),
+            server: self.server,
+            stream: true,
+            resolved: AtomicBool::new(false),
+            signed: AtomicBool::new(false),
+            started: Instant::now(),
+        }
+    }
+
+    /// Runs `f`, which processes the stream's records, reporting ClientHellos
+    /// to this observer and noting whether the server certificate's key was
+    /// used; see `observe_config`.
+    pub(crate) fn process<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        let observer = match (self.server, &self.observer) {
+            (true, &Some(ref observer)) => observer.clone(),
+            _ => return f(),
+        };
+
+        let current = Current {
+            observer,
+            resolved: false,
+            signed: false,
+        };
+        let previous = CURRENT.with(|cell| cell.replace(Some(current)));
+        let result = f();
+        let current = CURRENT.with(|cell| cell.replace(previous));
+
+        if let Some(current) = current {
+            if current.resolved {
+                self.resolved.store(true, Ordering::Relaxed);
+            }
+            if current.signed {
+                self.signed.store(true, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+
+    /// How long since the stream's handshake started.
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Whether the completed handshake of an acceptor's stream resumed a
+    /// session: without a certificate to authenticate with, the server never
+    /// signs anything. Unknown, and so false, if the configuration's resolver
+    /// is not the one `observe_config` installed.
+    pub(crate) fn resumed(&self) -> bool {
+        self.server && self.resolved.load(Ordering::Relaxed) && !self.signed.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for SharedObserver {
+    fn default() -> SharedObserver {
+        SharedObserver {
+            observer: None,
+            server: false,
+            stream: false,
+            resolved: AtomicBool::new(false),
+            signed: AtomicBool::new(false),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Clone for SharedObserver {
+    fn clone(&self) -> SharedObserver {
+        SharedObserver {
+            observer: self.observer.clone(),
+            server: self.server,
+            stream: false,
+            resolved: AtomicBool::new(false),
+            signed: AtomicBool::new(false),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for SharedObserver {
+    fn drop(&mut self) {
+        if let (true, Some(observer)) = (self.stream, self.get()) {
+            observer.stream_dropped();
+        }
+    }
+}
+
+impl fmt::Debug for SharedObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.observer {
+            Some(_) => f.write_str("SharedObserver(Some(..))"),
+            None => f.write_str("SharedObserver(None)"),
+        }
+    }
+}
+
+thread_local! {
+    /// The observer of the acceptor's stream whose records are being
+    /// processed on this thread, for the certificate resolver to report to.
+    static CURRENT: RefCell<Option<Current>> = RefCell::new(None);
+}
+
+struct Current {
+    observer: Arc<dyn Observer>,
+    resolved: bool,
+    signed: bool,
+}
+
+/// Reports each ClientHello to the observer of the current stream before
+/// resolving the server certificate as usual, and has its key note when it
+/// is used to sign.
+struct ObserveClientHello {
+    inner: Arc<dyn ResolvesServerCert>,
+}
+
+struct ObserveSigning(Arc<Box<dyn SigningKey>>);
+
+/// A copy of `config` whose certificate resolver reports to the observer of
+/// the stream using it.
+///
+/// The observer is looked up for each stream rather than built into the
+/// configuration, so the copy only has to be made once, and clones of an
+/// acceptor can have observers of their own.
+pub(crate) fn observe_config(config: &ServerConfig) -> Arc<ServerConfig> {
+    let mut config = config.clone();
+    config.cert_resolver = Arc::new(ObserveClientHello {
+        inner: config.cert_resolver.clone(),
+    });
+    Arc::new(config)
+}
+
+impl ResolvesServerCert for ObserveClientHello {
+    fn resolve(
+        &self,
+        server_name: Option<DNSNameRef>,
+        sigschemes: &[SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        let observer = CURRENT.with(|cell| {
+            cell.borrow_mut().as_mut().map(|current| {
+                current.resolved = true;
+                current.observer.clone()
+            })
+        });
+        let observer = match observer {
+            Some(observer) => observer,
+            None => return self.inner.resolve(server_name, sigschemes),
+        };
+
+        observer.client_hello(server_name, sigschemes);
+        let mut certified = self.inner.resolve(server_name, sigschemes)?;
+        certified.key = Arc::new(Box::new(ObserveSigning(certified.key)));
+        Some(certified)
+    }
+}
+
+impl SigningKey for ObserveSigning {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        CURRENT.with(|cell| {
+            if let Some(ref mut current) = *cell.borrow_mut() {
+                current.signed = true;
+            }
+        });
+        self.0.choose_scheme(offered)
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        self.0.algorithm()
+    }
+}
+
+/// The fatal alert rustls 0.16 sends to the peer when processing its records
+/// fails with `err`, on the server side if `server` and with TLS 1.3 if
+/// `tls13`.
+///
+/// Only meaningful if processing queued something for the peer: some of these
+/// errors, like a record that fails to decrypt, are not answered at all. Where
+/// rustls answers the same error with different alerts depending on what
+/// failed, this is the one it sends for most causes, or `None` if there is
+/// none; `PeerMisbehavedError`, for one, can be any of half a dozen.
+pub(crate) fn sent_alert(err: &TLSError, server: bool, tls13: bool) -> Option<u8> {
+    match *err {
+        // unexpected_message
+        TLSError::InappropriateMessage { .. } | TLSError::InappropriateHandshakeMessage { .. } => {
+            Some(10)
+        }
+        // bad_record_mac is never sent: rustls drops the session without an
+        // alert when a record fails to decrypt. decrypt_error is for a
+        // Finished message that does not match the handshake.
+        TLSError::DecryptError => Some(51),
+        // record_overflow
+        TLSError::PeerSentOversizedRecord => Some(22),
+        // decode_error, but illegal_parameter for a malformed KeyUpdate.
+        TLSError::CorruptMessagePayload(ContentType::Handshake) => Some(50),
+        // The server rejects the client certificate chain with
+        // handshake_failure, whatever the verifier returned, and a bad
+        // signature on CertificateVerify with access_denied. A General error
+        // is also what it fails with when no certificate resolves for the
+        // ClientHello, which is answered with access_denied.
+        TLSError::WebPKIError(webpki::Error::InvalidSignatureForPublicKey)
+        | TLSError::WebPKIError(webpki::Error::UnsupportedSignatureAlgorithmForPublicKey)
+        | TLSError::WebPKIError(webpki::Error::UnsupportedSignatureAlgorithm)
+            if server =>
+        {
+            Some(49)
+        }
+        TLSError::WebPKIError(_) | TLSError::General(_) if server => Some(40),
+        // certificate_required in TLS 1.3; TLS 1.2 has the verifier reject the
+        // empty chain.
+        TLSError::NoCertificatesPresented if server && tls13 => Some(116),
+        TLSError::NoCertificatesPresented if server => Some(40),
+        // handshake_failure when nothing the client offered is acceptable,
+        // but protocol_version for unsupported versions and
+        // illegal_parameter for a few malformed ClientHellos.
+        TLSError::PeerIncompatibleError(_) if server => Some(40),
+        // The client rejects the server certificate with bad_certificate,
+        // whatever the verifier returned, or decode_error if it could not be
+        // parsed.
+        TLSError::WebPKIError(webpki::Error::BadDER) => Some(50),
+        TLSError::WebPKIError(_) | TLSError::General(_) => Some(42),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test_observer {
+    use super::*;
+    use rustls::internal::msgs::enums::AlertDescription;
+
+    #[test]
+    fn test_sent_alert() {
+        let cases = vec![
+            (
+                TLSError::InappropriateMessage {
+                    expect_types: vec![ContentType::Handshake],
+                    got_type: ContentType::ApplicationData,
+                },
+                false,
+                false,
+                Some(AlertDescription::UnexpectedMessage),
+            ),
+            (
+                TLSError::CorruptMessagePayload(ContentType::Handshake),
+                true,
+                true,
+                Some(AlertDescription::DecodeError),
+            ),
+            (
+                TLSError::CorruptMessagePayload(ContentType::Alert),
+                true,
+                true,
+                None,
+            ),
+            (TLSError::CorruptMessage, false, true, None),
+            (
+                TLSError::DecryptError,
+                false,
+                false,
+                Some(AlertDescription::DecryptError),
+            ),
+            (
+                TLSError::PeerSentOversizedRecord,
+                true,
+                false,
+                Some(AlertDescription::RecordOverflow),
+            ),
+            (
+                TLSError::WebPKIError(webpki::Error::UnknownIssuer),
+                false,
+                true,
+                Some(AlertDescription::BadCertificate),
+            ),
+            (
+                TLSError::WebPKIError(webpki::Error::BadDER),
+                false,
+                true,
+                Some(AlertDescription::DecodeError),
+            ),
+            (
+                TLSError::General("pin mismatch".into()),
+                false,
+                false,
+                Some(AlertDescription::BadCertificate),
+            ),
+            (
+                TLSError::WebPKIError(webpki::Error::CertExpired),
+                true,
+                true,
+                Some(AlertDescription::HandshakeFailure),
+            ),
+            (
+                TLSError::General("revoked".into()),
+                true,
+                false,
+                Some(AlertDescription::HandshakeFailure),
+            ),
+            (
+                TLSError::WebPKIError(webpki::Error::InvalidSignatureForPublicKey),
+                true,
+                true,
+                Some(AlertDescription::AccessDenied),
+            ),
+            (
+                TLSError::NoCertificatesPresented,
+                true,
+                true,
+                Some(AlertDescription::CertificateRequired),
+            ),
+            (
+                TLSError::NoCertificatesPresented,
+                true,
+                false,
+                Some(AlertDescription::HandshakeFailure),
+            ),
+            (TLSError::NoCertificatesPresented, false, true, None),
+            (
+                TLSError::PeerIncompatibleError("no ciphersuites in common".into()),
+                true,
+                true,
+                Some(AlertDescription::HandshakeFailure),
+            ),
+            (
+                TLSError::PeerMisbehavedError("server sent duplicate extensions".into()),
+                false,
+                true,
+                None,
+            ),
+            (
+                TLSError::AlertReceived(AlertDescription::HandshakeFailure),
+                false,
+                true,
+                None,
+            ),
+        ];
+
+        for (err, server, tls13, expected) in cases {
+            assert_eq!(
+                sent_alert(&err, server, tls13),
+                expected.map(|alert| alert.get_u8()),
+                "{:?}, server: {}, TLS 1.3: {}",
+                err,
+                server,
+                tls13
+            );
+        }
+    }
+}
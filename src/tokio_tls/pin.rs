@@ -0,0 +1,471 @@
+//! Public key pinning for connectors.
+//!
+//! A `Pin` is the SHA-256 digest of a certificate's DER-encoded
+//! SubjectPublicKeyInfo, the value HPKP calls `pin-sha256`. It can be
+//! computed with
+//!
+//! ```text
+//! openssl x509 -in cert.pem -pubkey -noout | openssl pkey -pubin -outform der \
+//!     | openssl dgst -sha256 -binary | base64
+//! ```
+//!
+//! A `PinSet` is installed on a connector with `TlsConnector::pins`, which
+//! replaces the configuration's certificate verifier. Chains no pin matches
+//! fail with `verify::VerifyError::PinMismatch`.
+use super::verify::{self, VerifyError};
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use webpki::{trust_anchor_util, DNSNameRef};
+
+/// The SHA-256 digest of a SubjectPublicKeyInfo.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Pin([u8; 32]);
+
+impl Pin {
+    pub fn sha256(digest: [u8; 32]) -> Pin {
+        Pin(digest)
+    }
+
+    /// Parses a base64-encoded digest, failing with `InvalidInput` if it is
+    /// not valid base64 or not 32 bytes long.
+    pub fn from_base64(pin: &str) -> io::Result<Pin> {
+        let bytes =
+            base64::decode(pin).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        if bytes.len() != 32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a SHA-256 pin must be 32 bytes long",
+            ));
+        }
+        let mut digest = [0; 32];
+        digest.copy_from_slice(&bytes);
+        Ok(Pin(digest))
+    }
+
+    /// The pin of a DER-encoded certificate's public key, failing with
+    /// `InvalidData` if the certificate can not be parsed.
+    pub fn of_certificate(cert: &Certificate) -> io::Result<Pin> {
+        let anchor = trust_anchor_util::cert_der_as_trust_anchor(&cert.0).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "failed to read the certificate's public key",
+            )
+        })?;
+        Ok(Pin::of_spki_contents(anchor.spki))
+    }
+
+    /// The pin of a public key given as the contents of a
+    /// SubjectPublicKeyInfo, the way webpki's `TrustAnchor::spki` holds it.
+    fn of_spki_contents(spki: &[u8]) -> Pin {
+        let mut der = vec![0x30];
+        if spki.len() < 0x80 {
+            der.push(spki.len() as u8);
+        } else {
+            let len = (spki.len() as u32).to_be_bytes();
+            let skip = len.iter().take_while(|&&byte| byte == 0).count();
+            der.push(0x80 | (len.len() - skip) as u8);
+            der.extend_from_slice(&len[skip..]);
+        }
+        der.extend_from_slice(spki);
+        Pin::digest(&der)
+    }
+
+    fn digest(spki: &[u8]) -> Pin {
+        let mut digest = [0; 32];
+        digest.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, spki).as_ref());
+        Pin(digest)
+    }
+}
+
+/// Formats the pin as base64, as `from_base64` parses it.
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&base64::encode(&self.0))
+    }
+}
+
+impl fmt::Debug for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Pin({})", self)
+    }
+}
+
+/// Which certificates of the chain a pin may match.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PinScope {
+    /// Only the server's own certificate.
+    Leaf,
+    /// The server's own certificate or any certificate on a path from it to
+    /// a trust anchor that validates, hostname included. That covers roots
+    /// in the root store the server does not send; certificates the server
+    /// sends that are not on such a path do not count.
+    AnyInChain,
+}
+
+/// What a `PinSet` does besides matching pins.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PinMode {
+    /// Verify the chain against the root store as usual, and additionally
+    /// require a pin to match.
+    Enforce,
+    /// Skip verification against the root store, for servers with
+    /// self-signed or privately issued certificates. A pin matching the
+    /// server's own certificate is accepted without further checks, hostname
+    /// included. An `AnyInChain` pin matching another certificate only counts
+    /// if the chain validates with that certificate as its only trust
+    /// anchor. Fail if no pin matches.
+    PinsOnly,
+    /// Verify the chain as usual, but only report pin mismatches instead of
+    /// failing.
+    ReportOnly,
+}
+
+/// The details of a chain no pin matched.
+#[derive(Debug, Clone)]
+pub struct PinMismatch {
+    pub server_name: String,
+    /// The pins of the presented certificates, leaf first. Certificates that
+    /// can not be parsed are left out.
+    pub presented: Vec<Pin>,
+}
+
+type MismatchCallback = dyn Fn(&PinMismatch) + Send + Sync;
+
+/// A set of pins and how to apply them.
+#[derive(Clone)]
+pub struct PinSet {
+    pins: Vec<(Pin, PinScope)>,
+    mode: PinMode,
+    on_mismatch: Option<Arc<MismatchCallback>>,
+}
+
+impl PinSet {
+    /// An empty set in `PinMode::Enforce`. Add pins with `pin`.
+    pub fn new() -> PinSet {
+        PinSet {
+            pins: Vec::new(),
+            mode: PinMode::Enforce,
+            on_mismatch: None,
+        }
+    }
+
+    pub fn pin(mut self, pin: Pin, scope: PinScope) -> PinSet {
+        self.pins.push((pin, scope));
+        self
+    }
+
+    pub fn mode(mut self, mode: PinMode) -> PinSet {
+        self.mode = mode;
+        self
+    }
+
+    /// Call `callback` whenever no pin matches, in any mode. Mismatches are
+    /// also logged as `tracing` warnings with the `tracing` feature.
+    pub fn on_mismatch<F>(mut self, callback: F) -> PinSet
+    where
+        F: Fn(&PinMismatch) + Send + Sync + 'static,
+    {
+        self.on_mismatch = Some(Arc::new(callback));
+        self
+    }
+
+    /// Whether a pin matches the server's own certificate, or an
+    /// `AnyInChain` pin matches a trust anchor the chain validates against.
+    ///
+    /// Pins are not matched against the other presented certificates
+    /// directly: a server can send any certificate along with its chain, so
+    /// only the ones on a path webpki validates count. Those are found by
+    /// validating the chain with the pinned certificates as the only trust
+    /// anchors.
+    fn matches(
+        &self,
+        roots: &RootCertStore,
+        presented: &[Certificate],
+        dns_name: DNSNameRef,
+    ) -> bool {
+        if let Ok(leaf) = Pin::of_certificate(&presented[0]) {
+            if self.pins.iter().any(|&(pin, _)| pin == leaf) {
+                return true;
+            }
+        }
+
+        let pinned = |candidate: Pin| {
+            self.pins
+                .iter()
+                .any(|&(pin, scope)| scope == PinScope::AnyInChain && pin == candidate)
+        };
+        let mut anchors = Vec::new();
+        for cert in &presented[1..] {
+            if let Ok(anchor) = trust_anchor_util::cert_der_as_trust_anchor(&cert.0) {
+                if pinned(Pin::of_spki_contents(anchor.spki)) {
+                    anchors.push(anchor);
+                }
+            }
+        }
+        for root in &roots.roots {
+            let anchor = root.to_trust_anchor();
+            if pinned(Pin::of_spki_contents(anchor.spki)) {
+                anchors.push(anchor);
+            }
+        }
+        !anchors.is_empty() && verify::verify_with_anchors(&anchors, presented, dns_name).is_ok()
+    }
+
+    fn report(&self, presented: &[Certificate], dns_name: DNSNameRef) {
+        let server_name: &str = dns_name.into();
+        tls_event!(
+            warn,
+            server_name = server_name,
+            mode = ?self.mode,
+            "TLS certificate pin mismatch"
+        );
+        if let Some(ref callback) = self.on_mismatch {
+            callback(&PinMismatch {
+                server_name: server_name.to_owned(),
+                presented: presented
+                    .iter()
+                    .filter_map(|cert| Pin::of_certificate(cert).ok())
+                    .collect(),
+            });
+        }
+    }
+}
+
+impl Default for PinSet {
+    fn default() -> PinSet {
+        PinSet::new()
+    }
+}
+
+impl fmt::Debug for PinSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PinSet")
+            .field("pins", &self.pins)
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+struct PinningVerifier {
+    pins: PinSet,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented: &[Certificate],
+        dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        verify::clear();
+        if presented.is_empty() {
+            return Err(TLSError::NoCertificatesPresented);
+        }
+        if self.pins.mode != PinMode::PinsOnly {
+            verify::verify_server_cert(roots, presented, dns_name)?;
+        }
+        if self.pins.matches(roots, presented, dns_name) {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        self.pins.report(presented, dns_name);
+        match self.pins.mode {
+            PinMode::ReportOnly => Ok(ServerCertVerified::assertion()),
+            _ => Err(verify::fail(VerifyError::PinMismatch)),
+        }
+    }
+}
+
+/// A copy of `config` that verifies server certificates against `pins`.
+pub(crate) fn pinned_config(config: &ClientConfig, pins: PinSet) -> Arc<ClientConfig> {
+    let mut config = config.clone();
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(PinningVerifier { pins }));
+    Arc::new(config)
+}
+
+#[cfg(test)]
+mod test_pin {
+    use super::*;
+    use rustls::internal::pemfile::certs;
+    use std::io::{BufReader, Cursor};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const CHAIN: &[u8] = include_bytes!("../../tests/end.chain");
+    const CA: &[u8] = include_bytes!("../../tests/ca.cert");
+    const SELF_SIGNED: &[u8] = include_bytes!("../../tests/expired.cert");
+    const END_PIN: &str = "Sec6OpkdaOfkjoMsVZMroPq9ZiT3yl/RVZNBOs7dE6E=";
+    const CA_PIN: &str = "RbTiq2XMkA7okbkqQ/cvzwGi0U+whyDqG5PI/imaAic=";
+    const OTHER_PIN: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+    fn chain() -> Vec<Certificate> {
+        certs(&mut BufReader::new(Cursor::new(CHAIN))).unwrap()
+    }
+
+    fn self_signed() -> Certificate {
+        certs(&mut BufReader::new(Cursor::new(SELF_SIGNED)))
+            .unwrap()
+            .remove(0)
+    }
+
+    fn roots() -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add_pem_file(&mut BufReader::new(Cursor::new(CA)))
+            .unwrap();
+        roots
+    }
+
+    fn verify(pins: PinSet, roots: &RootCertStore, name: &str) -> Result<(), TLSError> {
+        verify_chain(pins, roots, &chain(), name)
+    }
+
+    fn verify_chain(
+        pins: PinSet,
+        roots: &RootCertStore,
+        chain: &[Certificate],
+        name: &str,
+    ) -> Result<(), TLSError> {
+        let name = DNSNameRef::try_from_ascii_str(name).unwrap();
+        PinningVerifier { pins }
+            .verify_server_cert(roots, chain, name, &[])
+            .map(|_| ())
+    }
+
+    fn is_mismatch(err: &TLSError) -> bool {
+        verify::failure(err) == Some(VerifyError::PinMismatch)
+    }
+
+    fn pin(pin: &str) -> Pin {
+        Pin::from_base64(pin).unwrap()
+    }
+
+    #[test]
+    fn test_of_certificate() {
+        let chain = chain();
+        assert_eq!(Pin::of_certificate(&chain[0]).unwrap(), pin(END_PIN));
+        assert_eq!(Pin::of_certificate(&chain[1]).unwrap(), pin(CA_PIN));
+        assert_eq!(pin(END_PIN).to_string(), END_PIN);
+
+        assert!(Pin::from_base64("not base64!").is_err());
+        assert!(Pin::from_base64("AAAA").is_err());
+        assert!(Pin::of_certificate(&Certificate(vec![0x30, 0x00])).is_err());
+    }
+
+    #[test]
+    fn test_scope() {
+        let roots = roots();
+        let leaf = PinSet::new().pin(pin(END_PIN), PinScope::Leaf);
+        assert_eq!(verify(leaf, &roots, "localhost"), Ok(()));
+
+        let ca = PinSet::new().pin(pin(CA_PIN), PinScope::AnyInChain);
+        assert_eq!(verify(ca, &roots, "localhost"), Ok(()));
+
+        let ca_as_leaf = PinSet::new().pin(pin(CA_PIN), PinScope::Leaf);
+        let err = verify(ca_as_leaf, &roots, "localhost").unwrap_err();
+        assert!(is_mismatch(&err));
+
+        // A pinned root matches from the root store when the server does not
+        // send it.
+        let ca = PinSet::new().pin(pin(CA_PIN), PinScope::AnyInChain);
+        let leaf_only = &chain()[..1];
+        assert_eq!(verify_chain(ca, &roots, leaf_only, "localhost"), Ok(()));
+    }
+
+    #[test]
+    fn test_pins_only_ignores_unrelated_pinned_cert() {
+        // A self-signed leaf sent along with the public, pinned CA
+        // certificate must not pass as issued by that CA.
+        let attack = vec![self_signed(), chain().remove(1)];
+        let pins = PinSet::new()
+            .pin(pin(CA_PIN), PinScope::AnyInChain)
+            .mode(PinMode::PinsOnly);
+        let err = verify_chain(pins.clone(), &RootCertStore::empty(), &attack, "localhost");
+        assert!(is_mismatch(&err.unwrap_err()));
+
+        // The genuine chain still matches through the pinned CA, hostname
+        // included.
+        assert_eq!(
+            verify(pins.clone(), &RootCertStore::empty(), "localhost"),
+            Ok(())
+        );
+        let err = verify(pins, &RootCertStore::empty(), "example.com").unwrap_err();
+        assert!(is_mismatch(&err));
+    }
+
+    #[test]
+    fn test_enforce_ignores_unused_pinned_cert() {
+        // A chain valid through a trusted CA, with an unrelated pinned
+        // certificate appended that is not on its path.
+        let mut attack = chain();
+        attack.push(self_signed());
+        let other = Pin::of_certificate(&self_signed()).unwrap();
+        let pins = PinSet::new().pin(other, PinScope::AnyInChain);
+        let err = verify_chain(pins, &roots(), &attack, "localhost").unwrap_err();
+        assert!(is_mismatch(&err));
+    }
+
+    #[test]
+    fn test_enforce() {
+        let pins = PinSet::new().pin(pin(END_PIN), PinScope::Leaf);
+        // A matching pin does not make up for an untrusted chain or a wrong
+        // hostname.
+        assert_eq!(
+            verify(pins.clone(), &RootCertStore::empty(), "localhost"),
+            Err(TLSError::WebPKIError(webpki::Error::UnknownIssuer))
+        );
+        assert_eq!(
+            verify(pins, &roots(), "example.com"),
+            Err(TLSError::WebPKIError(webpki::Error::CertNotValidForName))
+        );
+
+        let err = verify(PinSet::new(), &roots(), "localhost").unwrap_err();
+        assert!(is_mismatch(&err));
+        // The mismatch is not reported for another session's error.
+        let other = verify::process::<(), _>(|| Err(TLSError::General("other".to_owned())));
+        assert!(!is_mismatch(&other.unwrap_err()));
+    }
+
+    #[test]
+    fn test_pins_only() {
+        let pins = PinSet::new()
+            .pin(pin(END_PIN), PinScope::Leaf)
+            .mode(PinMode::PinsOnly);
+        assert_eq!(verify(pins, &RootCertStore::empty(), "example.com"), Ok(()));
+
+        let pins = PinSet::new()
+            .pin(pin(OTHER_PIN), PinScope::AnyInChain)
+            .mode(PinMode::PinsOnly);
+        let err = verify(pins, &roots(), "localhost").unwrap_err();
+        assert!(is_mismatch(&err));
+    }
+
+    #[test]
+    fn test_report_only() {
+        let reports = Arc::new(AtomicUsize::new(0));
+        let counter = reports.clone();
+        let pins = PinSet::new()
+            .pin(pin(OTHER_PIN), PinScope::AnyInChain)
+            .mode(PinMode::ReportOnly)
+            .on_mismatch(move |mismatch| {
+                assert_eq!(mismatch.server_name, "localhost");
+                assert_eq!(mismatch.presented, vec![pin(END_PIN), pin(CA_PIN)]);
+                counter.fetch_add(1, Ordering::Relaxed);
+            });
+        assert_eq!(verify(pins.clone(), &roots(), "localhost"), Ok(()));
+        assert_eq!(reports.load(Ordering::Relaxed), 1);
+
+        // The chain is still verified.
+        assert_eq!(
+            verify(pins, &RootCertStore::empty(), "localhost"),
+            Err(TLSError::WebPKIError(webpki::Error::UnknownIssuer))
+        );
+        assert_eq!(reports.load(Ordering::Relaxed), 1);
+    }
+}
@@ -1,13 +1,22 @@
 use super::*;
+use bytes::{Buf, BufMut};
 use rustls::Session;
+use std::io::Write;
 
+use close::{Close, HalfClose};
 use common::Stream;
-use entry::TlsState;
+use cork::Cork;
+use entry::{CloseStatus, PeerClose, TlsState};
 use futures::{Async, Future, Poll};
+use observer::SharedObserver;
 use rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession};
+use split::{ReadHalf, WriteHalf};
+use stats::Stats;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{io, mem};
 use tokio_io::{try_nb, AsyncRead, AsyncWrite};
+use trace::ConnSpan;
 use webpki::DNSNameRef;
 
 /// A wrapper around an underlying raw stream which implements the TLS or SSL
@@ -17,6 +26,14 @@ pub struct TlsStream<IO> {
     pub io: IO,
     pub session: ServerSession,
     pub state: TlsState,
+    pub close: PeerClose,
+    pub cork: Cork,
+    pub stats: Stats,
+    pub observer: SharedObserver,
+    pub span: ConnSpan,
+    /// An error `read_buf` ran into after it had already read some
+    /// plaintext, returned by the next read.
+    pub read_error: Option<io::Error>,
 }
 
 pub enum MidHandshake<IO> {
@@ -39,6 +56,119 @@ impl<IO> TlsStream<IO> {
     pub fn into_inner(self) -> (IO, ServerSession) {
         (self.io, self.session)
     }
+
+    /// Splits this stream into a read half and a write half, which can be
+    /// used from different tasks. `ReadHalf::unsplit` puts them back
+    /// together.
+    pub fn split(self) -> (ReadHalf<TlsStream<IO>>, WriteHalf<TlsStream<IO>>)
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        split::split(self)
+    }
+
+    /// The current shutdown state of the stream.
+    #[inline]
+    pub fn shutdown_state(&self) -> TlsState {
+        self.state
+    }
+
+    /// How the peer ended its side of the stream.
+    #[inline]
+    pub fn close_status(&self) -> CloseStatus {
+        self.close.status
+    }
+
+    /// Whether the peer ended the stream with close_notify, i.e. everything
+    /// it sent is known to have arrived.
+    #[inline]
+    pub fn is_close_authenticated(&self) -> bool {
+        self.close.status == CloseStatus::Authenticated
+    }
+
+    /// Treat an EOF without close_notify as an `UnexpectedEof` error instead
+    /// of a clean end of stream.
+    #[inline]
+    pub fn set_strict_close(&mut self, strict: bool) {
+        self.close.strict = strict;
+    }
+
+    /// Traffic statistics for this stream.
+    #[inline]
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Reports a handshake failure that was detected outside the stream,
+    /// such as a timeout.
+    pub(crate) fn handshake_failed(&self, err: &io::Error) {
+        let _span = self.span.enter();
+        tls_event!(debug, error = %err, "handshake failed");
+        if let Some(observer) = self.observer.get() {
+            observer.handshake_failed(err);
+        }
+    }
+
+    /// Hold back up to `limit` bytes of plaintext so that small writes share
+    /// TLS records; see `TlsConnector::cork`. A `limit` of 0 turns corking
+    /// off, and whatever is still held back goes out with the next write or
+    /// flush.
+    #[inline]
+    pub fn set_cork(&mut self, limit: usize, max_delay: Option<Duration>) {
+        self.cork.set(limit, max_delay);
+    }
+
+    /// Sends close_notify and flushes it, but keeps the read side open so
+    /// that the peer can finish sending.
+    ///
+    /// Unlike `AsyncWrite::shutdown`, this does not shut down the underlying
+    /// IO.
+    pub fn shutdown_write(&mut self) -> Poll<(), io::Error>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        let _span = self.span.enter();
+        let mut stream = Stream::new(&mut self.io, &mut self.session)
+            .set_eof(!self.state.readable())
+            .set_cork(&mut self.cork)
+            .set_stats(&mut self.stats)
+            .set_observer(&self.observer);
+
+        if self.state.writeable() {
+            // Corked plaintext has to go out before close_notify.
+            try_nb!(stream.flush());
+            stream.send_close_notify();
+            self.state.shutdown_write();
+        }
+
+        try_nb!(stream.flush());
+        try_nb!(self.io.flush());
+        Ok(Async::Ready(()))
+    }
+
+    /// Sends close_notify and waits for the peer's close_notify, giving up
+    /// with a `TimedOut` error once `timeout` elapses.
+    ///
+    /// Application data the peer sends in the meantime is discarded.
+    pub fn close(self, timeout: Duration) -> Close<TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite,
+    {
+        Close::new(self, timeout)
+    }
+}
+
+impl<IO> HalfClose for TlsStream<IO>
+where
+    IO: AsyncRead + AsyncWrite,
+{
+    fn shutdown_state(&self) -> TlsState {
+        TlsStream::shutdown_state(self)
+    }
+
+    fn shutdown_write(&mut self) -> Poll<(), io::Error> {
+        TlsStream::shutdown_write(self)
+    }
 }
 
 impl<IO> Future for MidHandshake<IO>
@@ -51,9 +181,12 @@ where
     #[inline]
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         if let MidHandshake::Handshaking(stream) = self {
+            let _span = stream.span.enter();
             let state = stream.state;
-            let (io, session) = stream.get_mut();
-            let mut stream = Stream::new(io, session).set_eof(!state.readable());
+            let mut stream = Stream::new(&mut stream.io, &mut stream.session)
+                .set_eof(!state.readable())
+                .set_stats(&mut stream.stats)
+                .set_observer(&stream.observer);
 
             if stream.session.is_handshaking() {
                 match stream.complete_io() {
@@ -65,9 +198,11 @@ where
                 }
             }
 
+            // Only flushes what the handshake left to send: reading on
+            // would wait for the peer, which may be waiting for us.
             if stream.session.wants_write() {
-                match stream.complete_io() {
-                    Ok(t) => t,
+                match stream.flush() {
+                    Ok(()) => (),
                     Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => {
                         return Ok(::futures::Async::NotReady);
                     }
@@ -88,22 +223,30 @@ where
     IO: AsyncRead + AsyncWrite,
 {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut stream =
-            Stream::new(&mut self.io, &mut self.session).set_eof(!self.state.readable());
+        if let Some(err) = self.read_error.take() {
+            return Err(err);
+        }
+        let _span = self.span.enter();
+        let mut stream = Stream::new(&mut self.io, &mut self.session)
+            .set_eof(!self.state.readable())
+            .set_cork(&mut self.cork)
+            .set_stats(&mut self.stats)
+            .set_observer(&self.observer);
 
         match self.state {
             TlsState::Stream | TlsState::WriteShutdown => match stream.read(buf) {
+                Ok(0) if buf.is_empty() => Ok(0),
                 Ok(0) => {
                     self.state.shutdown_read();
+                    self.close.eof()?;
                     Ok(0)
                 }
                 Ok(n) => Ok(n),
                 Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => {
                     self.state.shutdown_read();
-                    if self.state.writeable() {
-                        stream.session.send_close_notify();
-                        self.state.shutdown_write();
-                    }
+                    self.close.close_notify();
+                    // The write side stays open until it is shut down, so
+                    // that a half-closed peer can still be answered.
                     Ok(0)
                 }
                 Err(e) => Err(e),
@@ -120,14 +263,32 @@ where
     IO: AsyncRead + AsyncWrite,
 {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _span = self.span.enter();
         Stream::new(&mut self.io, &mut self.session)
             .set_eof(!self.state.readable())
+            .set_cork(&mut self.cork)
+            .set_stats(&mut self.stats)
+            .set_observer(&self.observer)
             .write(buf)
     }
 
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        let _span = self.span.enter();
+        Stream::new(&mut self.io, &mut self.session)
+            .set_eof(!self.state.readable())
+            .set_cork(&mut self.cork)
+            .set_stats(&mut self.stats)
+            .set_observer(&self.observer)
+            .write_vectored(bufs)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
+        let _span = self.span.enter();
         Stream::new(&mut self.io, &mut self.session)
             .set_eof(!self.state.readable())
+            .set_cork(&mut self.cork)
+            .set_stats(&mut self.stats)
+            .set_observer(&self.observer)
             .flush()?;
         self.io.flush()
     }
@@ -137,23 +298,48 @@ impl<IO> AsyncRead for TlsStream<IO>
 where
     IO: AsyncRead + AsyncWrite,
 {
+    /// Reads only ever copy plaintext into the buffer and never look at
+    /// what it held before, so it is not zeroed up front. `read_buf` still
+    /// zeroes each chunk just before reading into it; see
+    /// `common::read_buf`.
     unsafe fn prepare_uninitialized_buffer(&self, _: &mut [u8]) -> bool {
         false
     }
+
+    fn read_buf<B: BufMut>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        if let Some(err) = self.read_error.take() {
+            return Err(err);
+        }
+        let mut read_error = None;
+        let read = common::read_buf(self, &mut read_error, buf);
+        self.read_error = read_error;
+        read
+    }
 }
 
 impl<IO> AsyncWrite for TlsStream<IO>
 where
     IO: AsyncRead + AsyncWrite,
 {
+    fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+        common::write_buf(self, buf)
+    }
+
     fn shutdown(&mut self) -> Poll<(), io::Error> {
+        let _span = self.span.enter();
+        let mut stream = Stream::new(&mut self.io, &mut self.session)
+            .set_eof(!self.state.readable())
+            .set_cork(&mut self.cork)
+            .set_stats(&mut self.stats)
+            .set_observer(&self.observer);
+
         if self.state.writeable() {
-            self.session.send_close_notify();
+            // Corked plaintext has to go out before close_notify.
+            try_nb!(stream.flush());
+            stream.send_close_notify();
             self.state.shutdown_write();
         }
 
-        let mut stream =
-            Stream::new(&mut self.io, &mut self.session).set_eof(!self.state.readable());
         try_nb!(stream.complete_io());
         stream.io.shutdown()
     }
@@ -0,0 +1,260 @@
+//! Independently usable read and write halves of a TLS stream.
+//!
+//! Both halves share the stream through a `BiLock`, so a reader task and a
+//! writer task can make progress without wrapping the stream in a `Mutex`.
+//! The lock is only held for the duration of a single read or write, and a
+//! half that finds it taken is woken as soon as the other half releases it.
+//!
+//! A read also flushes any ciphertext the session still has queued, so
+//! either half can end up waiting for the IO to become writable. The IO only
+//! remembers the last task to wait on each direction, so the halves poll it
+//! under a task that wakes both of them: otherwise a reader flushing would
+//! take the wakeup of a writer waiting for the same readiness.
+use futures::executor::{self, Notify};
+use futures::sync::BiLock;
+use futures::task::{self, AtomicTask};
+use futures::{Async, Poll};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// The readable half of a TLS stream returned from `split`.
+#[derive(Debug)]
+pub struct ReadHalf<T> {
+    handle: BiLock<T>,
+    tasks: Arc<Tasks>,
+}
+
+/// The writable half of a TLS stream returned from `split`.
+#[derive(Debug)]
+pub struct WriteHalf<T> {
+    handle: BiLock<T>,
+    tasks: Arc<Tasks>,
+}
+
+/// The tasks of the two halves, which the IO wakes together.
+#[derive(Debug, Default)]
+struct Tasks {
+    read: AtomicTask,
+    write: AtomicTask,
+}
+
+impl Notify for Tasks {
+    fn notify(&self, _: usize) {
+        self.read.notify();
+        self.write.notify();
+    }
+}
+
+pub(crate) fn split<T: AsyncRead + AsyncWrite>(stream: T) -> (ReadHalf<T>, WriteHalf<T>) {
+    let (read, write) = BiLock::new(stream);
+    let tasks = Arc::new(Tasks::default());
+    (
+        ReadHalf {
+            handle: read,
+            tasks: tasks.clone(),
+        },
+        WriteHalf {
+            handle: write,
+            tasks,
+        },
+    )
+}
+
+/// Runs `f` on the stream once the lock is ours, with the IO waking both
+/// halves. `task` is where the current task is kept for that.
+fn with_stream<T, R, F>(handle: &BiLock<T>, tasks: &Arc<Tasks>, task: &AtomicTask, f: F) -> Async<R>
+where
+    F: FnOnce(&mut T) -> R,
+{
+    let mut stream = match handle.poll_lock() {
+        Async::Ready(stream) => stream,
+        Async::NotReady => return Async::NotReady,
+    };
+    if !task::is_in_task() {
+        return Async::Ready(f(&mut stream));
+    }
+
+    task.register();
+    Async::Ready(executor::with_notify(tasks, 0, || f(&mut stream)))
+}
+
+impl<T: AsyncRead + AsyncWrite> ReadHalf<T> {
+    /// Reunite with the `WriteHalf` split off the same stream.
+    ///
+    /// # Panics
+    ///
+    /// If the halves do not originate from the same `split` call.
+    pub fn unsplit(self, write: WriteHalf<T>) -> T {
+        match self.handle.reunite(write.handle) {
+            Ok(stream) => stream,
+            Err(_) => panic!("Unrelated `WriteHalf` passed to `ReadHalf::unsplit`."),
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> WriteHalf<T> {
+    /// Reunite with the `ReadHalf` split off the same stream.
+    ///
+    /// # Panics
+    ///
+    /// If the halves do not originate from the same `split` call.
+    pub fn unsplit(self, read: ReadHalf<T>) -> T {
+        read.unsplit(self)
+    }
+}
+
+impl<T: AsyncRead> Read for ReadHalf<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match with_stream(&self.handle, &self.tasks, &self.tasks.read, |s| s.read(buf)) {
+            Async::Ready(result) => result,
+            Async::NotReady => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for ReadHalf<T> {
+    unsafe fn prepare_uninitialized_buffer(&self, _: &mut [u8]) -> bool {
+        false
+    }
+}
+
+impl<T: AsyncWrite> Write for WriteHalf<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match with_stream(&self.handle, &self.tasks, &self.tasks.write, |s| {
+            s.write(buf)
+        }) {
+            Async::Ready(result) => result,
+            Async::NotReady => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match with_stream(&self.handle, &self.tasks, &self.tasks.write, |s| s.flush()) {
+            Async::Ready(result) => result,
+            Async::NotReady => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for WriteHalf<T> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match with_stream(&self.handle, &self.tasks, &self.tasks.write, |s| {
+            s.shutdown()
+        }) {
+            Async::Ready(result) => result,
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_split {
+    use super::*;
+    use futures::executor::NotifyHandle;
+    use futures::future;
+    use futures::task::Task;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use tokio_io::try_nb;
+
+    /// IO that, like a reactor, remembers one task per direction. It never
+    /// has data, and a read first flushes, like a TLS stream's does.
+    #[derive(Clone, Default)]
+    struct Pipe(Arc<Mutex<PipeState>>);
+
+    #[derive(Default)]
+    struct PipeState {
+        writable: bool,
+        read_task: Option<Task>,
+        write_task: Option<Task>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            let mut state = self.0.lock().unwrap();
+            if state.writable {
+                state.read_task = Some(task::current());
+            } else {
+                state.write_task = Some(task::current());
+            }
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut state = self.0.lock().unwrap();
+            if state.writable {
+                return Ok(buf.len());
+            }
+            state.write_task = Some(task::current());
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Pipe {}
+
+    impl AsyncWrite for Pipe {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[derive(Default)]
+    struct Woken(AtomicBool);
+
+    impl Notify for Woken {
+        fn notify(&self, _: usize) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_both_halves_wait_for_writable() {
+        let pipe = Pipe::default();
+        let (mut read_half, mut write_half) = split(pipe.clone());
+
+        let writer_woken = Arc::new(Woken::default());
+        let mut writer = executor::spawn(future::poll_fn(move || {
+            Ok::<_, io::Error>(Async::Ready(try_nb!(write_half.write(b"data"))))
+        }));
+        let reader_woken = Arc::new(Woken::default());
+        let mut reader = executor::spawn(future::poll_fn(move || {
+            let mut buf = [0; 4];
+            Ok::<_, io::Error>(Async::Ready(try_nb!(read_half.read(&mut buf))))
+        }));
+
+        let notify = NotifyHandle::from(writer_woken.clone());
+        assert!(writer
+            .poll_future_notify(&notify, 0)
+            .unwrap()
+            .is_not_ready());
+        let notify = NotifyHandle::from(reader_woken.clone());
+        assert!(reader
+            .poll_future_notify(&notify, 0)
+            .unwrap()
+            .is_not_ready());
+
+        // The reader's flush was the last to wait for the pipe to become
+        // writable; the writer is woken all the same.
+        let task = {
+            let mut state = pipe.0.lock().unwrap();
+            state.writable = true;
+            state.write_task.take().unwrap()
+        };
+        task.notify();
+        assert!(writer_woken.0.load(Ordering::SeqCst));
+        assert!(reader_woken.0.load(Ordering::SeqCst));
+
+        let notify = NotifyHandle::from(writer_woken);
+        match writer.poll_future_notify(&notify, 0).unwrap() {
+            Async::Ready(n) => assert_eq!(n, 4),
+            Async::NotReady => panic!("writer still blocked once the pipe is writable"),
+        }
+    }
+}
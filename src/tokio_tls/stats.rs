@@ -0,0 +1,173 @@
+//! Per-connection traffic statistics.
+//!
+//! Plaintext is counted as it passes between the application and the
+//! session, ciphertext as it passes between the session and the IO, so the
+//! difference between the two is the TLS overhead: the handshake, record
+//! headers, padding and MACs, and alerts.
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// Traffic counters for a single TLS stream.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// Application data handed out by reads.
+    pub plaintext_read: u64,
+    /// Application data accepted by writes. Corked data is only counted once
+    /// it leaves the cork.
+    pub plaintext_written: u64,
+    /// Bytes read from the underlying IO.
+    pub ciphertext_read: u64,
+    /// Bytes written to the underlying IO.
+    pub ciphertext_written: u64,
+    /// TLS records read from the underlying IO, including handshake records.
+    pub records_read: u64,
+    /// TLS records written to the underlying IO, including handshake records.
+    pub records_written: u64,
+    /// How long the handshake took, measured from when the stream was
+    /// created. `None` while it is still in progress.
+    pub handshake_duration: Option<Duration>,
+    created: Instant,
+    last_activity: Instant,
+    read_records: RecordCounter,
+    written_records: RecordCounter,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        let now = Instant::now();
+        Stats {
+            plaintext_read: 0,
+            plaintext_written: 0,
+            ciphertext_read: 0,
+            ciphertext_written: 0,
+            records_read: 0,
+            records_written: 0,
+            handshake_duration: None,
+            created: now,
+            last_activity: now,
+            read_records: RecordCounter::default(),
+            written_records: RecordCounter::default(),
+        }
+    }
+
+    /// When ciphertext was last read from or written to the IO, or when the
+    /// stream was created if there has been no traffic yet.
+    #[inline]
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity
+    }
+
+    /// How long the stream has gone without any traffic on the IO.
+    pub fn idle_time(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    pub(crate) fn plaintext_read(&mut self, n: usize) {
+        self.plaintext_read += n as u64;
+    }
+
+    pub(crate) fn plaintext_written(&mut self, n: usize) {
+        self.plaintext_written += n as u64;
+    }
+
+    pub(crate) fn ciphertext_read(&mut self, data: &[u8]) {
+        if !data.is_empty() {
+            self.ciphertext_read += data.len() as u64;
+            self.records_read += self.read_records.feed(data);
+            self.last_activity = Instant::now();
+        }
+    }
+
+    pub(crate) fn ciphertext_written(&mut self, data: &[u8]) {
+        if !data.is_empty() {
+            self.ciphertext_written += data.len() as u64;
+            self.records_written += self.written_records.feed(data);
+            self.last_activity = Instant::now();
+        }
+    }
+
+    /// Records how long the handshake took.
+    pub(crate) fn handshake_complete(&mut self) -> Duration {
+        let duration = self.created.elapsed();
+        self.handshake_duration = Some(duration);
+        duration
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats::new()
+    }
+}
+
+/// Counts records in a stream of ciphertext that arrives in arbitrary
+/// pieces, by following the length in each record header.
+#[derive(Debug, Clone, Default)]
+struct RecordCounter {
+    header: [u8; 5],
+    header_len: usize,
+    body_left: usize,
+}
+
+impl RecordCounter {
+    /// Consumes `data`, returning how many new record headers it completed.
+    fn feed(&mut self, mut data: &[u8]) -> u64 {
+        let mut records = 0;
+
+        while !data.is_empty() {
+            if self.body_left > 0 {
+                let n = cmp::min(self.body_left, data.len());
+                self.body_left -= n;
+                data = &data[n..];
+                continue;
+            }
+
+            let n = cmp::min(self.header.len() - self.header_len, data.len());
+            self.header[self.header_len..self.header_len + n].copy_from_slice(&data[..n]);
+            self.header_len += n;
+            data = &data[n..];
+
+            if self.header_len == self.header.len() {
+                self.body_left = usize::from(self.header[3]) << 8 | usize::from(self.header[4]);
+                self.header_len = 0;
+                records += 1;
+            }
+        }
+
+        records
+    }
+}
+
+#[cfg(test)]
+mod test_stats {
+    use super::*;
+
+    #[test]
+    fn test_records_split_anywhere() {
+        let data = [
+            &[23, 3, 3, 0, 2, 0xaa, 0xbb][..],
+            &[21, 3, 3, 0, 0][..],
+            &[23, 3, 3, 0, 1, 0xcc][..],
+        ]
+        .concat();
+
+        for split in 0..=data.len() {
+            let mut stats = Stats::new();
+            stats.ciphertext_read(&data[..split]);
+            stats.ciphertext_read(&data[split..]);
+            assert_eq!(stats.records_read, 3);
+            assert_eq!(stats.ciphertext_read, data.len() as u64);
+        }
+    }
+
+    #[test]
+    fn test_partial_record() {
+        let mut stats = Stats::new();
+        stats.ciphertext_written(&[23, 3, 3]);
+        assert_eq!(stats.records_written, 0);
+        stats.ciphertext_written(&[0, 4, 1, 2]);
+        assert_eq!(stats.records_written, 1);
+        stats.ciphertext_written(&[3, 4]);
+        assert_eq!(stats.records_written, 1);
+    }
+}
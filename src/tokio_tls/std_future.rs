@@ -0,0 +1,928 @@
+//! The parts of the futures 0.3 and tokio 1.x flavours of the TLS streams
+//! that do not depend on which IO traits they are for.
+//!
+//! Each flavour implements `PollIo` for its IO under a marker type of its
+//! own, and `Compat` exposes such an IO, together with the task context of
+//! the current poll, through the `tokio_io` traits `common::Stream` is
+//! written against, turning `Poll::Pending` into `WouldBlock`.
+//!
+//! Deadlines, for the handshake and for corked data, sleep on the timer of
+//! the flavour's runtime, which it provides by implementing `Timer`.
+//!
+//! The connector and acceptor wrap the futures 0.1 ones from `entry`, so the
+//! streams they create have the same settings.
+use super::callback::{Verdict, Verification};
+use super::client;
+use super::close::close_timed_out;
+use super::compat::{self, would_block};
+use super::deadline::Deadline;
+use super::entry::{self, HandshakeFailure, TlsState};
+use super::error::handshake_timed_out;
+use super::expiry::CertExpiry;
+use super::observer::Observer;
+use super::pin::PinSet;
+use super::server;
+use super::tofu::KnownHosts;
+use futures::Async;
+use rustls::{Certificate, ClientConfig, ClientSession, ServerConfig, ServerSession};
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
+use webpki::DNSNameRef;
+
+/// IO of the flavour `F`, polled with the context of the current task.
+pub trait PollIo<F> {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>>;
+
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>>;
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<io::Result<()>>;
+
+    fn poll_shutdown(&mut self, cx: &mut Context) -> Poll<io::Result<()>>;
+}
+
+/// A future that resolves at a deadline, from `Timer::sleep_until`.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send + Sync>>;
+
+/// The timer of the runtime a flavour runs on.
+pub trait Timer {
+    /// A future that resolves at `at`, waking the task polling it.
+    fn sleep_until(at: Instant) -> Sleep;
+}
+
+/// Fails once `deadline` has passed; until then, the task of `cx` is woken
+/// when it does.
+fn poll_deadline<F: Timer>(deadline: &mut Option<Deadline>, cx: &mut Context) -> io::Result<()> {
+    if let Some(deadline) = deadline {
+        if deadline.poll_expired_in::<F>(cx) {
+            return Err(handshake_timed_out());
+        }
+    }
+    Ok(())
+}
+
+/// A `PollIo` together with the context of the task polling it.
+pub(crate) struct Compat<'a, 'b, IO, F> {
+    io: &'a mut IO,
+    cx: &'a mut Context<'b>,
+    flavour: PhantomData<F>,
+}
+
+impl<'a, 'b, IO, F> Compat<'a, 'b, IO, F> {
+    pub(crate) fn new(io: &'a mut IO, cx: &'a mut Context<'b>) -> Self {
+        Compat {
+            io,
+            cx,
+            flavour: PhantomData,
+        }
+    }
+}
+
+impl<'a, 'b, IO: PollIo<F>, F> Read for Compat<'a, 'b, IO, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        would_block(self.io.poll_read(self.cx, buf))
+    }
+}
+
+impl<'a, 'b, IO: PollIo<F>, F> Write for Compat<'a, 'b, IO, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        would_block(self.io.poll_write(self.cx, buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        would_block(self.io.poll_flush(self.cx))
+    }
+}
+
+impl<'a, 'b, IO: PollIo<F>, F> tokio_io::AsyncRead for Compat<'a, 'b, IO, F> {}
+
+impl<'a, 'b, IO: PollIo<F>, F> tokio_io::AsyncWrite for Compat<'a, 'b, IO, F> {
+    fn shutdown(&mut self) -> futures::Poll<(), io::Error> {
+        match self.io.poll_shutdown(self.cx) {
+            Poll::Ready(Ok(())) => Ok(Async::Ready(())),
+            Poll::Ready(Err(err)) => Err(err),
+            Poll::Pending => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// A wrapper around an `entry::TlsConnector`, providing a `connect` method
+/// returning a `std::future::Future`.
+///
+/// 0-RTT is not supported, so `entry::TlsConnector::early_data` is ignored.
+pub struct TlsConnector<F> {
+    inner: entry::TlsConnector,
+    flavour: PhantomData<fn() -> F>,
+}
+
+/// A wrapper around an `entry::TlsAcceptor`, providing an `accept` method
+/// returning a `std::future::Future`.
+pub struct TlsAcceptor<F> {
+    inner: entry::TlsAcceptor,
+    flavour: PhantomData<fn() -> F>,
+}
+
+impl<F> Clone for TlsConnector<F> {
+    fn clone(&self) -> Self {
+        TlsConnector::from(self.inner.clone())
+    }
+}
+
+impl<F> Clone for TlsAcceptor<F> {
+    fn clone(&self) -> Self {
+        TlsAcceptor::from(self.inner.clone())
+    }
+}
+
+impl<F> From<entry::TlsConnector> for TlsConnector<F> {
+    fn from(inner: entry::TlsConnector) -> TlsConnector<F> {
+        TlsConnector {
+            inner,
+            flavour: PhantomData,
+        }
+    }
+}
+
+impl<F> From<entry::TlsAcceptor> for TlsAcceptor<F> {
+    fn from(inner: entry::TlsAcceptor) -> TlsAcceptor<F> {
+        TlsAcceptor {
+            inner,
+            flavour: PhantomData,
+        }
+    }
+}
+
+impl<F> From<Arc<ClientConfig>> for TlsConnector<F> {
+    fn from(config: Arc<ClientConfig>) -> TlsConnector<F> {
+        TlsConnector::from(entry::TlsConnector::from(config))
+    }
+}
+
+impl<F> From<Arc<ServerConfig>> for TlsAcceptor<F> {
+    fn from(config: Arc<ServerConfig>) -> TlsAcceptor<F> {
+        TlsAcceptor::from(entry::TlsAcceptor::from(config))
+    }
+}
+
+impl<F> TlsConnector<F> {
+    /// The futures 0.1 connector holding the settings.
+    #[inline]
+    pub fn get_ref(&self) -> &entry::TlsConnector {
+        &self.inner
+    }
+
+    /// Limit how long the handshake may take; see
+    /// `entry::TlsConnector::handshake_timeout`.
+    ///
+    /// The task is woken at the deadline by the timer of the flavour's
+    /// runtime: tokio's under `tokio1`, which must be enabled on the runtime
+    /// (`Builder::enable_time`), and async-io's under `futures03`.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> TlsConnector<F> {
+        self.inner = self.inner.handshake_timeout(timeout);
+        self
+    }
+
+    /// Treat an EOF without close_notify as an error; see
+    /// `entry::TlsConnector::strict_close`.
+    pub fn strict_close(mut self, flag: bool) -> TlsConnector<F> {
+        self.inner = self.inner.strict_close(flag);
+        self
+    }
+
+    /// Cork the streams; see `entry::TlsConnector::cork`.
+    pub fn cork(mut self, limit: usize, max_delay: Option<Duration>) -> TlsConnector<F> {
+        self.inner = self.inner.cork(limit, max_delay);
+        self
+    }
+
+    /// Limit how much unsent data each stream buffers; see
+    /// `entry::TlsConnector::buffer_limit`.
+    pub fn buffer_limit(mut self, limit: usize) -> TlsConnector<F> {
+        self.inner = self.inner.buffer_limit(limit);
+        self
+    }
+
+    /// Report the lifecycle of each stream to `observer`; see
+    /// `entry::TlsConnector::observer`.
+    pub fn observer(mut self, observer: Arc<dyn Observer>) -> TlsConnector<F> {
+        self.inner = self.inner.observer(observer);
+        self
+    }
+
+    /// Read the expiry of the client certificate; see
+    /// `entry::TlsConnector::certificate`.
+    pub fn certificate(mut self, cert: &Certificate) -> io::Result<TlsConnector<F>> {
+        self.inner = self.inner.certificate(cert)?;
+        Ok(self)
+    }
+
+    /// Call `callback` once the client certificate is about to expire; see
+    /// `entry::TlsConnector::expiry_warning`.
+    pub fn expiry_warning<C>(mut self, threshold: Duration, callback: C) -> TlsConnector<F>
+    where
+        C: Fn(&CertExpiry) + Send + Sync + 'static,
+    {
+        self.inner = self.inner.expiry_warning(threshold, callback);
+        self
+    }
+
+    /// Verify server certificates against `pins`; see
+    /// `entry::TlsConnector::pins`.
+    pub fn pins(mut self, pins: PinSet) -> TlsConnector<F> {
+        self.inner = self.inner.pins(pins);
+        self
+    }
+
+    /// Trust each server's certificate on first use; see
+    /// `entry::TlsConnector::known_hosts`.
+    pub fn known_hosts(mut self, hosts: Arc<KnownHosts>) -> TlsConnector<F> {
+        self.inner = self.inner.known_hosts(hosts);
+        self
+    }
+
+    /// Give `callback` the final say on server certificates; see
+    /// `entry::TlsConnector::verify_callback`.
+    pub fn verify_callback<C>(mut self, callback: C) -> TlsConnector<F>
+    where
+        C: Fn(&Verification) -> Verdict + Send + Sync + 'static,
+    {
+        self.inner = self.inner.verify_callback(callback);
+        self
+    }
+
+    pub fn connect<IO>(&self, domain: DNSNameRef, stream: IO) -> Connect<IO, F>
+    where
+        IO: PollIo<F> + Unpin,
+    {
+        self.connect_with(domain, stream, |_| ())
+    }
+
+    #[inline]
+    pub fn connect_with<IO, C>(&self, domain: DNSNameRef, stream: IO, f: C) -> Connect<IO, F>
+    where
+        IO: PollIo<F> + Unpin,
+        C: FnOnce(&mut ClientSession),
+    {
+        let mut session = self.inner.session(domain);
+        f(&mut session);
+
+        Connect {
+            handshake: client::MidHandshake::Handshaking(self.inner.stream(
+                domain,
+                session,
+                stream,
+                TlsState::Stream,
+            )),
+            deadline: self.inner.handshake_deadline(),
+            flavour: PhantomData,
+        }
+    }
+}
+
+impl<F> TlsAcceptor<F> {
+    /// The futures 0.1 acceptor holding the settings.
+    #[inline]
+    pub fn get_ref(&self) -> &entry::TlsAcceptor {
+        &self.inner
+    }
+
+    /// Limit how long the handshake may take; see
+    /// `TlsConnector::handshake_timeout` for how it is enforced.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> TlsAcceptor<F> {
+        self.inner = self.inner.handshake_timeout(timeout);
+        self
+    }
+
+    /// Treat an EOF without close_notify as an error; see
+    /// `entry::TlsAcceptor::strict_close`.
+    pub fn strict_close(mut self, flag: bool) -> TlsAcceptor<F> {
+        self.inner = self.inner.strict_close(flag);
+        self
+    }
+
+    /// Cork the streams; see `entry::TlsAcceptor::cork`.
+    pub fn cork(mut self, limit: usize, max_delay: Option<Duration>) -> TlsAcceptor<F> {
+        self.inner = self.inner.cork(limit, max_delay);
+        self
+    }
+
+    /// Limit how much unsent data each stream buffers; see
+    /// `entry::TlsAcceptor::buffer_limit`.
+    pub fn buffer_limit(mut self, limit: usize) -> TlsAcceptor<F> {
+        self.inner = self.inner.buffer_limit(limit);
+        self
+    }
+
+    /// Report the lifecycle of each stream to `observer`; see
+    /// `entry::TlsAcceptor::observer`.
+    pub fn observer(mut self, observer: Arc<dyn Observer>) -> TlsAcceptor<F> {
+        self.inner = self.inner.observer(observer);
+        self
+    }
+
+    /// Give `callback` the final say on client certificates; see
+    /// `entry::TlsAcceptor::verify_callback`.
+    pub fn verify_callback<C>(mut self, callback: C) -> TlsAcceptor<F>
+    where
+        C: Fn(&Verification) -> Verdict + Send + Sync + 'static,
+    {
+        self.inner = self.inner.verify_callback(callback);
+        self
+    }
+
+    /// Read the expiry of the server certificate; see
+    /// `entry::TlsAcceptor::certificate`.
+    pub fn certificate(mut self, cert: &Certificate) -> io::Result<TlsAcceptor<F>> {
+        self.inner = self.inner.certificate(cert)?;
+        Ok(self)
+    }
+
+    /// Read the expiry of the certificate the configuration serves; see
+    /// `entry::TlsAcceptor::certificate_from_handshake`.
+    pub fn certificate_from_handshake(mut self) -> io::Result<TlsAcceptor<F>> {
+        self.inner = self.inner.certificate_from_handshake()?;
+        Ok(self)
+    }
+
+    /// Call `callback` once the server certificate is about to expire; see
+    /// `entry::TlsAcceptor::expiry_warning`.
+    pub fn expiry_warning<C>(mut self, threshold: Duration, callback: C) -> TlsAcceptor<F>
+    where
+        C: Fn(&CertExpiry) + Send + Sync + 'static,
+    {
+        self.inner = self.inner.expiry_warning(threshold, callback);
+        self
+    }
+
+    /// Fails if the server certificate has already expired; see
+    /// `entry::TlsAcceptor::check_expiry`.
+    pub fn check_expiry(&self) -> io::Result<()> {
+        self.inner.check_expiry()
+    }
+
+    pub fn accept<IO>(&self, stream: IO) -> Accept<IO, F>
+    where
+        IO: PollIo<F> + Unpin,
+    {
+        self.accept_with(stream, |_| ())
+    }
+
+    #[inline]
+    pub fn accept_with<IO, C>(&self, stream: IO, f: C) -> Accept<IO, F>
+    where
+        IO: PollIo<F> + Unpin,
+        C: FnOnce(&mut ServerSession),
+    {
+        let mut session = self.inner.session();
+        f(&mut session);
+
+        Accept {
+            handshake: server::MidHandshake::Handshaking(self.inner.stream(session, stream)),
+            deadline: self.inner.handshake_deadline(),
+            flavour: PhantomData,
+        }
+    }
+}
+
+/// Future returned from `TlsConnector::connect` which will resolve once the
+/// connection handshake has finished.
+pub struct Connect<IO, F> {
+    handshake: client::MidHandshake<IO>,
+    deadline: Option<Deadline>,
+    flavour: PhantomData<fn() -> F>,
+}
+
+/// Future returned from `TlsAcceptor::accept` which will resolve once the
+/// accept handshake has finished.
+pub struct Accept<IO, F> {
+    handshake: server::MidHandshake<IO>,
+    deadline: Option<Deadline>,
+    flavour: PhantomData<fn() -> F>,
+}
+
+impl<IO, F> Connect<IO, F> {
+    pub fn take_inner(&mut self) -> Option<IO> {
+        self.take_parts().map(|(io, _)| io)
+    }
+
+    /// Convert this future into one that hands back the IO and session when
+    /// the handshake fails.
+    pub fn into_failable(self) -> FailableConnect<IO, F> {
+        FailableConnect(self)
+    }
+
+    fn take_parts(&mut self) -> Option<(IO, ClientSession)> {
+        match mem::replace(&mut self.handshake, client::MidHandshake::End) {
+            client::MidHandshake::Handshaking(stream) => Some(stream.into_inner()),
+            _ => None,
+        }
+    }
+}
+
+impl<IO, F> Accept<IO, F> {
+    pub fn take_inner(&mut self) -> Option<IO> {
+        self.take_parts().map(|(io, _)| io)
+    }
+
+    /// Convert this future into one that hands back the IO and session when
+    /// the handshake fails.
+    pub fn into_failable(self) -> FailableAccept<IO, F> {
+        FailableAccept(self)
+    }
+
+    fn take_parts(&mut self) -> Option<(IO, ServerSession)> {
+        match mem::replace(&mut self.handshake, server::MidHandshake::End) {
+            server::MidHandshake::Handshaking(stream) => Some(stream.into_inner()),
+            server::MidHandshake::End => None,
+        }
+    }
+}
+
+/// Drives the handshake of `stream`, failing once `deadline` has passed.
+macro_rules! poll_handshake {
+    ($stream:expr, $deadline:expr, $cx:expr) => {{
+        let stream = $stream;
+        let _span = stream.span.enter();
+        let state = stream.state;
+        let result = compat::handshake(
+            &mut Compat::<_, F>::new(&mut stream.io, $cx),
+            &mut stream.session,
+            state,
+            &mut stream.stats,
+            &stream.observer,
+        );
+        match result {
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if let Err(err) = poll_deadline::<F>($deadline, $cx) {
+                    stream.handshake_failed(&err);
+                    return Poll::Ready(Err(err));
+                }
+                return Poll::Pending;
+            }
+            result => result,
+        }
+    }};
+}
+
+impl<IO: PollIo<F> + Unpin, F: Timer> Connect<IO, F> {
+    fn poll_handshake(&mut self, cx: &mut Context) -> Poll<io::Result<client::TlsStream<IO>>> {
+        if let client::MidHandshake::Handshaking(stream) = &mut self.handshake {
+            poll_handshake!(stream, &mut self.deadline, cx)?;
+        }
+
+        match mem::replace(&mut self.handshake, client::MidHandshake::End) {
+            client::MidHandshake::Handshaking(stream) => Poll::Ready(Ok(stream)),
+            _ => panic!("Connect polled after completion"),
+        }
+    }
+}
+
+impl<IO: PollIo<F> + Unpin, F: Timer> Accept<IO, F> {
+    fn poll_handshake(&mut self, cx: &mut Context) -> Poll<io::Result<server::TlsStream<IO>>> {
+        if let server::MidHandshake::Handshaking(stream) = &mut self.handshake {
+            poll_handshake!(stream, &mut self.deadline, cx)?;
+        }
+
+        match mem::replace(&mut self.handshake, server::MidHandshake::End) {
+            server::MidHandshake::Handshaking(stream) => Poll::Ready(Ok(stream)),
+            server::MidHandshake::End => panic!("Accept polled after completion"),
+        }
+    }
+}
+
+impl<IO: PollIo<F> + Unpin, F: Timer> Future for Connect<IO, F> {
+    type Output = io::Result<client::TlsStream<IO>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let result = this.poll_handshake(cx);
+        if let Poll::Ready(Err(_)) = result {
+            this.take_inner();
+        }
+        result
+    }
+}
+
+impl<IO: PollIo<F> + Unpin, F: Timer> Future for Accept<IO, F> {
+    type Output = io::Result<server::TlsStream<IO>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let result = this.poll_handshake(cx);
+        if let Poll::Ready(Err(_)) = result {
+            this.take_inner();
+        }
+        result
+    }
+}
+
+/// Like `Connect`, but fails with an `entry::HandshakeFailure` carrying the
+/// IO and session instead of dropping them.
+pub struct FailableConnect<IO, F>(Connect<IO, F>);
+
+/// Like `Accept`, but fails with an `entry::HandshakeFailure` carrying the
+/// IO and session instead of dropping them.
+pub struct FailableAccept<IO, F>(Accept<IO, F>);
+
+impl<IO: PollIo<F> + Unpin, F: Timer> Future for FailableConnect<IO, F> {
+    type Output = Result<client::TlsStream<IO>, HandshakeFailure<IO, ClientSession>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let inner = &mut self.get_mut().0;
+        match inner.poll_handshake(cx) {
+            Poll::Ready(Err(error)) => match inner.take_parts() {
+                Some((io, session)) => Poll::Ready(Err(HandshakeFailure { error, io, session })),
+                None => unreachable!("handshake failed after completion"),
+            },
+            Poll::Ready(Ok(stream)) => Poll::Ready(Ok(stream)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<IO: PollIo<F> + Unpin, F: Timer> Future for FailableAccept<IO, F> {
+    type Output = Result<server::TlsStream<IO>, HandshakeFailure<IO, ServerSession>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let inner = &mut self.get_mut().0;
+        match inner.poll_handshake(cx) {
+            Poll::Ready(Err(error)) => match inner.take_parts() {
+                Some((io, session)) => Poll::Ready(Err(HandshakeFailure { error, io, session })),
+                None => unreachable!("handshake failed after completion"),
+            },
+            Poll::Ready(Ok(stream)) => Poll::Ready(Ok(stream)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// The operations of a TLS stream over a `PollIo`, which each flavour's
+/// `AsyncRead`/`AsyncWrite` impls forward to.
+pub trait PollStream<F> {
+    fn poll_tls_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>>;
+
+    fn poll_tls_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>>;
+
+    fn poll_tls_write_vectored(
+        &mut self,
+        cx: &mut Context,
+        bufs: &[io::IoSlice],
+    ) -> Poll<io::Result<usize>>;
+
+    fn poll_tls_flush(&mut self, cx: &mut Context) -> Poll<io::Result<()>>;
+
+    /// Sends close_notify, flushes it and shuts the IO down.
+    fn poll_tls_close(&mut self, cx: &mut Context) -> Poll<io::Result<()>>;
+
+    /// Sends close_notify and flushes it, leaving the read side and the IO
+    /// open.
+    fn poll_tls_shutdown_write(&mut self, cx: &mut Context) -> Poll<io::Result<()>>;
+
+    /// The current shutdown state of the stream.
+    fn tls_shutdown_state(&self) -> TlsState;
+}
+
+macro_rules! poll_stream {
+    ($stream:ident) => {
+        impl<IO: PollIo<F>, F: Timer> PollStream<F> for $stream::TlsStream<IO> {
+            fn poll_tls_read(
+                &mut self,
+                cx: &mut Context,
+                buf: &mut [u8],
+            ) -> Poll<io::Result<usize>> {
+                let _span = self.span.enter();
+                let result = compat::read(
+                    &mut Compat::<_, F>::new(&mut self.io, cx),
+                    &mut self.session,
+                    &mut self.state,
+                    &mut self.close,
+                    &mut self.cork,
+                    &mut self.stats,
+                    &self.observer,
+                    buf,
+                );
+                self.cork.poll_timer::<F>(cx);
+                let n = try_pending!(result);
+                Poll::Ready(Ok(n))
+            }
+
+            fn poll_tls_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+                let _span = self.span.enter();
+                let result = compat::write(
+                    &mut Compat::<_, F>::new(&mut self.io, cx),
+                    &mut self.session,
+                    self.state,
+                    &mut self.cork,
+                    &mut self.stats,
+                    &self.observer,
+                    buf,
+                );
+                self.cork.poll_timer::<F>(cx);
+                let n = try_pending!(result);
+                Poll::Ready(Ok(n))
+            }
+
+            fn poll_tls_write_vectored(
+                &mut self,
+                cx: &mut Context,
+                bufs: &[io::IoSlice],
+            ) -> Poll<io::Result<usize>> {
+                let _span = self.span.enter();
+                let result = compat::write_vectored(
+                    &mut Compat::<_, F>::new(&mut self.io, cx),
+                    &mut self.session,
+                    self.state,
+                    &mut self.cork,
+                    &mut self.stats,
+                    &self.observer,
+                    bufs,
+                );
+                self.cork.poll_timer::<F>(cx);
+                let n = try_pending!(result);
+                Poll::Ready(Ok(n))
+            }
+
+            fn poll_tls_flush(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+                let _span = self.span.enter();
+                let result = compat::flush(
+                    &mut Compat::<_, F>::new(&mut self.io, cx),
+                    &mut self.session,
+                    self.state,
+                    &mut self.cork,
+                    &mut self.stats,
+                    &self.observer,
+                );
+                self.cork.poll_timer::<F>(cx);
+                try_pending!(result);
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_tls_close(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+                let _span = self.span.enter();
+                try_pending!(compat::close(
+                    &mut Compat::<_, F>::new(&mut self.io, cx),
+                    &mut self.session,
+                    &mut self.state,
+                    &mut self.cork,
+                    &mut self.stats,
+                    &self.observer
+                ));
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_tls_shutdown_write(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+                let _span = self.span.enter();
+                try_pending!(compat::shutdown_write(
+                    &mut Compat::<_, F>::new(&mut self.io, cx),
+                    &mut self.session,
+                    &mut self.state,
+                    &mut self.cork,
+                    &mut self.stats,
+                    &self.observer
+                ));
+                Poll::Ready(Ok(()))
+            }
+
+            fn tls_shutdown_state(&self) -> TlsState {
+                self.state
+            }
+        }
+    };
+}
+
+poll_stream!(client);
+poll_stream!(server);
+
+/// Future returned from `shutdown_write` in the flavour modules, which
+/// resolves once close_notify has been sent and flushed.
+pub struct ShutdownWrite<'a, S, F> {
+    stream: &'a mut S,
+    flavour: PhantomData<fn() -> F>,
+}
+
+impl<'a, S, F> ShutdownWrite<'a, S, F> {
+    pub(crate) fn new(stream: &'a mut S) -> ShutdownWrite<'a, S, F> {
+        ShutdownWrite {
+            stream,
+            flavour: PhantomData,
+        }
+    }
+}
+
+impl<'a, S: PollStream<F>, F> Future for ShutdownWrite<'a, S, F> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        self.get_mut().stream.poll_tls_shutdown_write(cx)
+    }
+}
+
+/// Future returned from `close` in the flavour modules, which resolves to
+/// the stream once the peer's close_notify has been received, or fails with
+/// a `TimedOut` error once the timeout elapses.
+///
+/// The task is woken at the deadline by the timer of the flavour's runtime;
+/// see `TlsConnector::handshake_timeout`.
+pub struct Close<S, F> {
+    stream: Option<S>,
+    deadline: Deadline,
+    flavour: PhantomData<fn() -> F>,
+}
+
+impl<S, F> Close<S, F> {
+    pub(crate) fn new(stream: S, timeout: Duration) -> Close<S, F> {
+        Close {
+            stream: Some(stream),
+            deadline: Deadline::after(timeout),
+            flavour: PhantomData,
+        }
+    }
+}
+
+impl<S: PollStream<F> + Unpin, F: Timer> Future for Close<S, F> {
+    type Output = io::Result<S>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        {
+            let stream = this.stream.as_mut().expect("Close polled after completion");
+            try_pending!(would_block(stream.poll_tls_shutdown_write(cx)));
+
+            // Application data still in flight from the peer is discarded;
+            // we only wait for its close_notify.
+            let mut buf = [0; 1024];
+            while stream.tls_shutdown_state().readable() {
+                match stream.poll_tls_read(cx, &mut buf) {
+                    Poll::Ready(Ok(0)) => break,
+                    Poll::Ready(Ok(_)) => (),
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => {
+                        if this.deadline.poll_expired_in::<F>(cx) {
+                            return Poll::Ready(Err(close_timed_out()));
+                        }
+                        return Poll::Pending;
+                    }
+                }
+            }
+        }
+
+        Poll::Ready(Ok(this.stream.take().unwrap()))
+    }
+}
+
+/// The readable half of a stream, from `split` in the flavour modules.
+///
+/// Like the futures 0.1 halves in `split`, the halves poll the stream with a
+/// waker that wakes both of them, as either may be waiting for the IO to
+/// become writable.
+pub struct ReadHalf<S, F> {
+    shared: Arc<Shared<S>>,
+    flavour: PhantomData<fn() -> F>,
+}
+
+/// The writable half of a stream, from `split` in the flavour modules.
+pub struct WriteHalf<S, F> {
+    shared: Arc<Shared<S>>,
+    flavour: PhantomData<fn() -> F>,
+}
+
+struct Shared<S> {
+    stream: Mutex<S>,
+    wakers: Arc<Wakers>,
+}
+
+/// The wakers of the two halves, which the IO wakes together.
+#[derive(Default)]
+struct Wakers {
+    read: Mutex<Option<Waker>>,
+    write: Mutex<Option<Waker>>,
+}
+
+impl Wake for Wakers {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        for waker in &[&self.read, &self.write] {
+            if let Some(waker) = waker.lock().unwrap().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<S> Shared<S> {
+    /// Runs `f` on the stream, with the IO waking both halves. `waker` is
+    /// where the waker of `cx` is kept for that.
+    fn poll<R, C>(&self, cx: &mut Context, waker: &Mutex<Option<Waker>>, f: C) -> Poll<R>
+    where
+        C: FnOnce(&mut S, &mut Context) -> Poll<R>,
+    {
+        *waker.lock().unwrap() = Some(cx.waker().clone());
+        let both = Waker::from(self.wakers.clone());
+        f(
+            &mut self.stream.lock().unwrap(),
+            &mut Context::from_waker(&both),
+        )
+    }
+}
+
+pub(crate) fn split<S, F>(stream: S) -> (ReadHalf<S, F>, WriteHalf<S, F>) {
+    let shared = Arc::new(Shared {
+        stream: Mutex::new(stream),
+        wakers: Arc::new(Wakers::default()),
+    });
+    (
+        ReadHalf {
+            shared: shared.clone(),
+            flavour: PhantomData,
+        },
+        WriteHalf {
+            shared,
+            flavour: PhantomData,
+        },
+    )
+}
+
+impl<S, F> ReadHalf<S, F> {
+    /// Reunite with the `WriteHalf` split off the same stream.
+    ///
+    /// # Panics
+    ///
+    /// If the halves do not originate from the same `split` call.
+    pub fn unsplit(self, write: WriteHalf<S, F>) -> S {
+        assert!(
+            Arc::ptr_eq(&self.shared, &write.shared),
+            "Unrelated `WriteHalf` passed to `ReadHalf::unsplit`."
+        );
+        drop(write);
+        match Arc::try_unwrap(self.shared) {
+            Ok(shared) => shared.stream.into_inner().unwrap(),
+            Err(_) => unreachable!("a half outlived unsplit"),
+        }
+    }
+}
+
+impl<S, F> WriteHalf<S, F> {
+    /// Reunite with the `ReadHalf` split off the same stream.
+    ///
+    /// # Panics
+    ///
+    /// If the halves do not originate from the same `split` call.
+    pub fn unsplit(self, read: ReadHalf<S, F>) -> S {
+        read.unsplit(self)
+    }
+}
+
+impl<S: PollStream<F>, F> ReadHalf<S, F> {
+    pub(crate) fn poll_read(
+        &mut self,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let shared = &*self.shared;
+        shared.poll(cx, &shared.wakers.read, |stream, cx| {
+            stream.poll_tls_read(cx, buf)
+        })
+    }
+}
+
+impl<S: PollStream<F>, F> WriteHalf<S, F> {
+    pub(crate) fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let shared = &*self.shared;
+        shared.poll(cx, &shared.wakers.write, |stream, cx| {
+            stream.poll_tls_write(cx, buf)
+        })
+    }
+
+    pub(crate) fn poll_write_vectored(
+        &mut self,
+        cx: &mut Context,
+        bufs: &[io::IoSlice],
+    ) -> Poll<io::Result<usize>> {
+        let shared = &*self.shared;
+        shared.poll(cx, &shared.wakers.write, |stream, cx| {
+            stream.poll_tls_write_vectored(cx, bufs)
+        })
+    }
+
+    pub(crate) fn poll_flush(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let shared = &*self.shared;
+        shared.poll(cx, &shared.wakers.write, |stream, cx| {
+            stream.poll_tls_flush(cx)
+        })
+    }
+
+    pub(crate) fn poll_close(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let shared = &*self.shared;
+        shared.poll(cx, &shared.wakers.write, |stream, cx| {
+            stream.poll_tls_close(cx)
+        })
+    }
+}
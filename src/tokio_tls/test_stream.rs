@@ -0,0 +1,1859 @@
+//! Loopback tests running a client and a server over a real TCP connection,
+//! one thread per side, against each async flavour.
+use rustls::internal::pemfile::{certs, rsa_private_keys};
+use rustls::{ClientConfig, NoClientAuth, ServerConfig, Session};
+use std::io::{self, BufReader, Cursor};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use webpki::DNSNameRef;
+
+const CHAIN: &[u8] = include_bytes!("../../tests/end.chain");
+const RSA: &[u8] = include_bytes!("../../tests/end.rsa");
+const CA: &[u8] = include_bytes!("../../tests/ca.cert");
+
+const MESSAGE: &[u8] = b"hello world";
+
+fn configs() -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+    let chain = certs(&mut BufReader::new(Cursor::new(CHAIN))).unwrap();
+    let mut keys = rsa_private_keys(&mut BufReader::new(Cursor::new(RSA))).unwrap();
+
+    let mut server = ServerConfig::new(NoClientAuth::new());
+    server.set_single_cert(chain, keys.remove(0)).unwrap();
+
+    let mut client = ClientConfig::new();
+    client
+        .root_store
+        .add_pem_file(&mut BufReader::new(Cursor::new(CA)))
+        .unwrap();
+
+    (Arc::new(server), Arc::new(client))
+}
+
+fn loopback() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    (client, server)
+}
+
+fn domain() -> DNSNameRef<'static> {
+    DNSNameRef::try_from_ascii_str("localhost").unwrap()
+}
+
+/// Records what is written to the socket and how many writes it took.
+/// While `stalled` is set, writes fail with `WouldBlock`, as if the peer had
+/// stopped reading.
+struct Recorder {
+    io: TcpStream,
+    written: Vec<u8>,
+    writes: usize,
+    stalled: bool,
+}
+
+impl Recorder {
+    fn new(io: TcpStream) -> Recorder {
+        Recorder {
+            io,
+            written: Vec::new(),
+            writes: 0,
+            stalled: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.written.clear();
+        self.writes = 0;
+    }
+}
+
+impl io::Read for Recorder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::Read::read(&mut self.io, buf)
+    }
+}
+
+impl io::Write for Recorder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.stalled {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = io::Write::write(&mut self.io, buf)?;
+        self.written.extend_from_slice(&buf[..n]);
+        self.writes += 1;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::Write::flush(&mut self.io)
+    }
+}
+
+/// Counts the TLS records in `data`.
+fn records(mut data: &[u8]) -> usize {
+    let mut count = 0;
+    while data.len() >= 5 {
+        let len = usize::from(data[3]) << 8 | usize::from(data[4]);
+        data = &data[5 + len..];
+        count += 1;
+    }
+    count
+}
+
+#[test]
+fn test_futures01_loopback() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use futures::Future;
+    use std::io::{Read, Write};
+    use tokio_io::io::AllowStdIo;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+
+        let mut buf = [0; MESSAGE.len()];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+        stream.flush().unwrap();
+    });
+
+    let connector = TlsConnector::from(client_config);
+    let mut stream = connector
+        .connect(domain(), AllowStdIo::new(client_io))
+        .wait()
+        .unwrap();
+
+    stream.write_all(MESSAGE).unwrap();
+    stream.flush().unwrap();
+
+    let mut buf = [0; MESSAGE.len()];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, MESSAGE);
+
+    server.join().unwrap();
+}
+
+/// Polls `future` every few milliseconds, without a runtime or anything
+/// else to wake it, until it resolves or `limit` has passed.
+fn poll_for<F: futures::Future>(
+    future: F,
+    limit: std::time::Duration,
+) -> Option<Result<F::Item, F::Error>> {
+    use futures::executor::{self, Notify, NotifyHandle};
+    use futures::Async;
+    use std::time::{Duration, Instant};
+
+    struct Noop;
+
+    impl Notify for Noop {
+        fn notify(&self, _: usize) {}
+    }
+
+    let notify = NotifyHandle::from(Arc::new(Noop));
+    let mut task = executor::spawn(future);
+    let start = Instant::now();
+    while start.elapsed() < limit {
+        match task.poll_future_notify(&notify, 0) {
+            Ok(Async::Ready(item)) => return Some(Ok(item)),
+            Ok(Async::NotReady) => thread::sleep(Duration::from_millis(5)),
+            Err(err) => return Some(Err(err)),
+        }
+    }
+    None
+}
+
+#[test]
+fn test_handshake_timeout_without_runtime() {
+    use super::entry::TlsConnector;
+    use std::time::{Duration, Instant};
+    use tokio_io::io::AllowStdIo;
+
+    let (_, client_config) = configs();
+
+    // The server never answers, and the client's reads give up quickly so
+    // that the handshake gets polled again.
+    let (client_io, _server_io) = loopback();
+    client_io
+        .set_read_timeout(Some(Duration::from_millis(5)))
+        .unwrap();
+
+    let start = Instant::now();
+    let connector =
+        TlsConnector::from(client_config.clone()).handshake_timeout(Duration::from_millis(100));
+    let connect = connector.connect(domain(), AllowStdIo::new(client_io));
+    let err = poll_for(connect, Duration::from_secs(5))
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    // Without a timeout the handshake keeps waiting.
+    let (client_io, _server_io) = loopback();
+    client_io
+        .set_read_timeout(Some(Duration::from_millis(5)))
+        .unwrap();
+    let connect = TlsConnector::from(client_config).connect(domain(), AllowStdIo::new(client_io));
+    assert!(poll_for(connect, Duration::from_millis(200)).is_none());
+}
+
+#[test]
+fn test_handshake_timeout_with_wait() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use futures::Future;
+    use std::time::Duration;
+    use tokio_io::io::AllowStdIo;
+
+    // A timeout that does not expire leaves handshakes driven by `wait`
+    // alone.
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config).handshake_timeout(Duration::from_secs(10));
+        acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+    });
+
+    let connector = TlsConnector::from(client_config).handshake_timeout(Duration::from_secs(10));
+    connector
+        .connect(domain(), AllowStdIo::new(client_io))
+        .wait()
+        .unwrap();
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_split_unsplit() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use futures::Future;
+    use std::io::{Read, Write};
+    use tokio_io::io::{read_exact, write_all, AllowStdIo};
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+
+        let mut buf = [0; MESSAGE.len()];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+        stream.flush().unwrap();
+    });
+
+    let connector = TlsConnector::from(client_config);
+    let stream = connector
+        .connect(domain(), AllowStdIo::new(client_io))
+        .wait()
+        .unwrap();
+    let (read_half, write_half) = stream.split();
+
+    let (write_half, _) = write_all(write_half, MESSAGE).wait().unwrap();
+    let (read_half, buf) = read_exact(read_half, [0; MESSAGE.len()]).wait().unwrap();
+    assert_eq!(buf, MESSAGE);
+
+    let stream = read_half.unsplit(write_half);
+    assert!(!stream.get_ref().1.is_handshaking());
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_half_close() {
+    use super::entry::{TlsAcceptor, TlsConnector, TlsState};
+    use futures::Future;
+    use std::io::{Read, Write};
+    use std::time::Duration;
+    use tokio_io::io::AllowStdIo;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, MESSAGE);
+        assert!(!stream.shutdown_state().readable());
+
+        // Reading the client's close_notify leaves our side open.
+        stream.write_all(&buf).unwrap();
+        stream.shutdown_write().unwrap();
+    });
+
+    let connector = TlsConnector::from(client_config);
+    let mut stream = connector
+        .connect(domain(), AllowStdIo::new(client_io))
+        .wait()
+        .unwrap();
+
+    stream.write_all(MESSAGE).unwrap();
+    stream.shutdown_write().unwrap();
+    assert_eq!(stream.shutdown_state(), TlsState::WriteShutdown);
+
+    let mut buf = [0; MESSAGE.len()];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, MESSAGE);
+
+    let stream = stream.close(Duration::from_secs(5)).wait().unwrap();
+    assert_eq!(stream.shutdown_state(), TlsState::FullyShutdown);
+    assert!(stream.is_close_authenticated());
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_close_times_out_on_silent_peer() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use futures::Future;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+    use tokio_io::io::AllowStdIo;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+    let (done, wait_done) = mpsc::channel();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let _stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+
+        // Never answer the client's close_notify.
+        wait_done.recv().unwrap();
+    });
+
+    let connector = TlsConnector::from(client_config);
+    let stream = connector
+        .connect(domain(), AllowStdIo::new(client_io))
+        .wait()
+        .unwrap();
+
+    // Without a read timeout, the blocking read would wait for the peer
+    // forever and the deadline would never be checked. Nothing wakes the
+    // task when the read times out either, so it is polled in a loop.
+    stream
+        .get_ref()
+        .0
+        .get_ref()
+        .set_read_timeout(Some(Duration::from_millis(10)))
+        .unwrap();
+
+    let start = Instant::now();
+    let err = match poll_for(
+        stream.close(Duration::from_millis(100)),
+        Duration::from_secs(5),
+    ) {
+        Some(Ok(_)) => panic!("close resolved without the peer's close_notify"),
+        Some(Err(err)) => err,
+        None => panic!("close did not time out"),
+    };
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    done.send(()).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn test_truncation_detected() {
+    use super::entry::{CloseStatus, TlsAcceptor, TlsConnector};
+    use futures::Future;
+    use std::io::{self, Read, Write};
+    use tokio_io::io::AllowStdIo;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+
+        // Drop the connection without sending close_notify.
+        stream.write_all(MESSAGE).unwrap();
+        stream.flush().unwrap();
+    });
+
+    let connector = TlsConnector::from(client_config).strict_close(true);
+    let mut stream = connector
+        .connect(domain(), AllowStdIo::new(client_io))
+        .wait()
+        .unwrap();
+    server.join().unwrap();
+
+    let mut buf = Vec::new();
+    let err = stream.read_to_end(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(buf, MESSAGE);
+    assert_eq!(stream.close_status(), CloseStatus::Truncated);
+    assert!(!stream.is_close_authenticated());
+}
+
+#[test]
+fn test_vectored_write_coalesces_records() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use futures::Future;
+    use std::io::{IoSlice, Read, Write};
+    use tokio_io::io::AllowStdIo;
+
+    const HEADER: &[u8] = b"\x00\x00\x00\x0b";
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+
+        let mut buf = [0; HEADER.len() + MESSAGE.len()];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..HEADER.len()], HEADER);
+        assert_eq!(&buf[HEADER.len()..], MESSAGE);
+    });
+
+    let connector = TlsConnector::from(client_config);
+    let mut stream = connector
+        .connect(domain(), AllowStdIo::new(Recorder::new(client_io)))
+        .wait()
+        .unwrap();
+
+    stream.get_mut().0.get_mut().reset();
+
+    let bufs = [IoSlice::new(HEADER), IoSlice::new(MESSAGE)];
+    let n = stream.write_vectored(&bufs).unwrap();
+    assert_eq!(n, HEADER.len() + MESSAGE.len());
+    stream.flush().unwrap();
+
+    let recorder = stream.get_ref().0.get_ref();
+    assert_eq!(records(&recorder.written), 1);
+    assert_eq!(recorder.writes, 1);
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_cork_coalesces_small_writes() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use futures::Future;
+    use std::io::{Read, Write};
+    use tokio_io::io::AllowStdIo;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+
+        let mut buf = [0; MESSAGE.len()];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, MESSAGE);
+    });
+
+    let connector = TlsConnector::from(client_config).cork(1024, None);
+    let mut stream = connector
+        .connect(domain(), AllowStdIo::new(Recorder::new(client_io)))
+        .wait()
+        .unwrap();
+    stream.get_mut().0.get_mut().reset();
+
+    for byte in MESSAGE.chunks(1) {
+        stream.write_all(byte).unwrap();
+    }
+    assert_eq!(stream.get_ref().0.get_ref().writes, 0);
+
+    stream.flush().unwrap();
+    let recorder = stream.get_ref().0.get_ref();
+    assert_eq!(records(&recorder.written), 1);
+    assert_eq!(recorder.writes, 1);
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_cork_delay_without_timer() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use futures::Future;
+    use std::io::{Read, Write};
+    use std::time::Duration;
+    use tokio_io::io::AllowStdIo;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, MESSAGE[..2]);
+    });
+
+    let connector = TlsConnector::from(client_config).cork(1024, Some(Duration::from_millis(20)));
+    let mut stream = connector
+        .connect(domain(), AllowStdIo::new(Recorder::new(client_io)))
+        .wait()
+        .unwrap();
+    stream.get_mut().0.get_mut().reset();
+
+    // Without a timer, the delay is still measured against the clock, and
+    // the held back byte goes out with the next write once it has passed.
+    stream.write_all(&MESSAGE[..1]).unwrap();
+    assert_eq!(stream.get_ref().0.get_ref().writes, 0);
+
+    thread::sleep(Duration::from_millis(30));
+    stream.write_all(&MESSAGE[1..2]).unwrap();
+    assert_eq!(stream.get_ref().0.get_ref().writes, 1);
+    assert_eq!(stream.cork.buffered(), 1);
+
+    stream.flush().unwrap();
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_stats_count_traffic() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use futures::Future;
+    use std::io::{Read, Write};
+    use tokio_io::io::AllowStdIo;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+
+        let mut buf = [0; MESSAGE.len()];
+        stream.read_exact(&mut buf).unwrap();
+
+        let stats = stream.stats();
+        assert_eq!(stats.plaintext_read, MESSAGE.len() as u64);
+        assert!(stats.ciphertext_read > stats.plaintext_read);
+        assert!(stats.handshake_duration.is_some());
+    });
+
+    let connector = TlsConnector::from(client_config);
+    let mut stream = connector
+        .connect(domain(), AllowStdIo::new(Recorder::new(client_io)))
+        .wait()
+        .unwrap();
+    assert!(stream.stats().handshake_duration.is_some());
+
+    stream.write_all(MESSAGE).unwrap();
+    stream.flush().unwrap();
+
+    let stats = stream.stats();
+    let recorder = stream.get_ref().0.get_ref();
+    assert_eq!(stats.plaintext_written, MESSAGE.len() as u64);
+    assert_eq!(stats.ciphertext_written, recorder.written.len() as u64);
+    assert_eq!(stats.records_written, records(&recorder.written) as u64);
+    assert!(stats.records_read > 0);
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_failable_accept_returns_io() {
+    use super::entry::TlsAcceptor;
+    use futures::Future;
+    use std::io::{Read, Write};
+    use tokio_io::io::AllowStdIo;
+
+    const RESPONSE: &[u8] = b"HTTP/1.1 400 Bad Request\r\n\r\n";
+
+    let (server_config, _) = configs();
+    let (mut client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let failure = acceptor
+            .accept(AllowStdIo::new(server_io))
+            .into_failable()
+            .wait()
+            .unwrap_err();
+        assert!(failure.session.is_handshaking());
+
+        // The connection is still there to answer in plaintext.
+        let (_, io, _) = failure.into_inner();
+        io.into_inner().write_all(RESPONSE).unwrap();
+    });
+
+    client_io.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    client_io.read_to_end(&mut response).unwrap();
+    assert!(response.ends_with(RESPONSE));
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_observer_sees_lifecycle() {
+    use super::entry::{CloseStatus, TlsAcceptor, TlsConnector};
+    use super::observer::{HandshakeInfo, Observer};
+    use futures::Future;
+    use rustls::{Certificate, SignatureScheme};
+    use std::io::{Read, Write};
+    use std::sync::Mutex;
+    use tokio_io::io::AllowStdIo;
+    use tokio_io::AsyncWrite;
+
+    #[derive(Default)]
+    struct Events(Mutex<Vec<String>>);
+
+    impl Events {
+        fn push(&self, event: String) {
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn take(&self) -> Vec<String> {
+            self.0.lock().unwrap().drain(..).collect()
+        }
+    }
+
+    impl Observer for Events {
+        fn handshake_start(&self) {
+            self.push("start".into());
+        }
+
+        fn client_hello(&self, server_name: Option<DNSNameRef>, _: &[SignatureScheme]) {
+            let name = server_name.map(<&str>::from);
+            self.push(format!("client_hello {:?}", name));
+        }
+
+        fn certificate_verified(&self, _: &[Certificate]) {
+            self.push("certificate_verified".into());
+        }
+
+        fn handshake_complete(&self, info: &HandshakeInfo) {
+            assert!(info.protocol_version.is_some());
+            assert!(info.cipher_suite.is_some());
+            self.push("complete".into());
+        }
+
+        fn handshake_failed(&self, err: &io::Error) {
+            self.push(format!("failed {:?}", err.kind()));
+        }
+
+        fn alert_sent(&self, code: u8) {
+            self.push(format!("alert_sent {}", code));
+        }
+
+        fn alert_received(&self, code: u8) {
+            self.push(format!("alert_received {}", code));
+        }
+
+        fn close(&self, status: CloseStatus) {
+            self.push(format!("close {:?}", status));
+        }
+    }
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+    let server_events = Arc::new(Events::default());
+    let client_events = Arc::new(Events::default());
+
+    let acceptor = TlsAcceptor::from(server_config).observer(server_events.clone());
+    let server = thread::spawn(move || {
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, MESSAGE);
+        stream.shutdown().unwrap();
+    });
+
+    let connector = TlsConnector::from(client_config).observer(client_events.clone());
+    let mut stream = connector
+        .connect(domain(), AllowStdIo::new(client_io))
+        .wait()
+        .unwrap();
+    stream.write_all(MESSAGE).unwrap();
+    stream.shutdown().unwrap();
+    server.join().unwrap();
+
+    assert_eq!(
+        client_events.take(),
+        ["start", "certificate_verified", "complete", "alert_sent 0"]
+    );
+    assert_eq!(
+        server_events.take(),
+        [
+            "start",
+            "client_hello Some(\"localhost\")",
+            "complete",
+            "alert_received 0",
+            "close Authenticated",
+            "alert_sent 0",
+        ]
+    );
+}
+
+#[test]
+fn test_observer_sees_rejection_alert() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use super::observer::Observer;
+    use futures::Future;
+    use std::sync::Mutex;
+    use tokio_io::io::AllowStdIo;
+
+    #[derive(Default)]
+    struct Alerts(Mutex<Vec<(bool, u8)>>);
+
+    impl Observer for Alerts {
+        fn alert_sent(&self, code: u8) {
+            self.0.lock().unwrap().push((true, code));
+        }
+
+        fn alert_received(&self, code: u8) {
+            self.0.lock().unwrap().push((false, code));
+        }
+    }
+
+    let (server_config, _) = configs();
+    let (client_io, server_io) = loopback();
+    let server_alerts = Arc::new(Alerts::default());
+    let client_alerts = Arc::new(Alerts::default());
+
+    let acceptor = TlsAcceptor::from(server_config).observer(server_alerts.clone());
+    let server = thread::spawn(move || {
+        assert!(acceptor.accept(AllowStdIo::new(server_io)).wait().is_err());
+    });
+
+    // Without the CA, the client rejects the server certificate.
+    let connector =
+        TlsConnector::from(Arc::new(ClientConfig::new())).observer(client_alerts.clone());
+    assert!(connector
+        .connect(domain(), AllowStdIo::new(client_io))
+        .wait()
+        .is_err());
+    server.join().unwrap();
+
+    // bad_certificate
+    assert_eq!(*client_alerts.0.lock().unwrap(), [(true, 42)]);
+    assert_eq!(*server_alerts.0.lock().unwrap(), [(false, 42)]);
+}
+
+#[test]
+fn test_observer_without_stats() {
+    use super::common::Stream;
+    use super::observer::{HandshakeInfo, Observer, SharedObserver};
+    use rustls::{ClientSession, ServerSession};
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio_io::io::AllowStdIo;
+
+    #[derive(Default)]
+    struct Completions(AtomicUsize);
+
+    impl Observer for Completions {
+        fn handshake_complete(&self, _: &HandshakeInfo) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+    let server_completions = Arc::new(Completions::default());
+    let client_completions = Arc::new(Completions::default());
+
+    // Drive the streams without `set_stats`, which used to be what
+    // remembered that the handshake had been reported.
+    let observer = SharedObserver::new_server(server_completions.clone()).start_stream();
+    let server = thread::spawn(move || {
+        let mut io = AllowStdIo::new(server_io);
+        let mut session = ServerSession::new(&server_config);
+        let mut stream = Stream::new(&mut io, &mut session).set_observer(&observer);
+        while stream.session.is_handshaking() {
+            stream.complete_io().unwrap();
+        }
+
+        let mut buf = [0; MESSAGE.len()];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+        stream.flush().unwrap();
+    });
+
+    let observer = SharedObserver::new(client_completions.clone()).start_stream();
+    let mut io = AllowStdIo::new(client_io);
+    let mut session = ClientSession::new(&client_config, domain());
+    let mut stream = Stream::new(&mut io, &mut session).set_observer(&observer);
+    while stream.session.is_handshaking() {
+        stream.complete_io().unwrap();
+    }
+
+    // Records processed after the handshake do not report it again.
+    stream.write_all(MESSAGE).unwrap();
+    stream.flush().unwrap();
+    let mut buf = [0; MESSAGE.len()];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, MESSAGE);
+    server.join().unwrap();
+
+    assert_eq!(client_completions.0.load(Ordering::Relaxed), 1);
+    assert_eq!(server_completions.0.load(Ordering::Relaxed), 1);
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing_spans_and_events() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use futures::Future;
+    use std::fmt::{self, Write as _};
+    use std::io::{Read, Write};
+    use std::sync::Mutex;
+    use tokio_io::io::AllowStdIo;
+    use tokio_io::AsyncWrite;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::subscriber::{self, Subscriber};
+    use tracing::{Event, Metadata};
+
+    /// Records spans, their entries and events as lines of text.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<String>>>);
+
+    impl Capture {
+        fn push(&self, line: String) {
+            self.0.lock().unwrap().push(line);
+        }
+
+        fn lines(&self) -> Vec<String> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    #[derive(Default)]
+    struct Fields(String);
+
+    impl Visit for Fields {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            write!(self.0, " {}={:?}", field.name(), value).unwrap();
+        }
+    }
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes) -> Id {
+            let mut fields = Fields::default();
+            span.record(&mut fields);
+            self.push(format!("span {}{}", span.metadata().name(), fields.0));
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _: &Id, _: &Record) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            self.push(format!("event{}", fields.0));
+        }
+
+        fn enter(&self, _: &Id) {
+            self.push("enter".into());
+        }
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, MESSAGE);
+        stream.shutdown().unwrap();
+    });
+
+    let capture = Capture::default();
+    let _stream = subscriber::with_default(capture.clone(), || {
+        let connector = TlsConnector::from(client_config);
+        let mut stream = connector
+            .connect(domain(), AllowStdIo::new(client_io))
+            .wait()
+            .unwrap();
+        stream.write_all(MESSAGE).unwrap();
+        stream.shutdown().unwrap();
+        // Kept open until the server has sent its close_notify.
+        stream
+    });
+    server.join().unwrap();
+
+    let lines = capture.lines();
+    let has = |prefix: &str| lines.iter().any(|line| line.starts_with(prefix));
+    assert!(has("span tls side=\"client\" server_name=\"localhost\""));
+    assert!(has("enter"));
+    assert!(has("event message=handshake complete"));
+    assert!(has("event message=TLS state transition"));
+
+    // Only metadata is recorded, never plaintext.
+    let message = String::from_utf8_lossy(MESSAGE);
+    assert!(lines.iter().all(|line| !line.contains(&*message)));
+}
+
+#[test]
+fn test_observer_sees_resumption() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use super::observer::{HandshakeInfo, Observer};
+    use futures::Future;
+    use rustls::SignatureScheme;
+    use std::io::{Read, Write};
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio_io::io::AllowStdIo;
+    use tokio_io::AsyncWrite;
+
+    #[derive(Default)]
+    struct Events(Mutex<Vec<&'static str>>);
+
+    impl Events {
+        fn take(&self) -> Vec<&'static str> {
+            self.0.lock().unwrap().drain(..).collect()
+        }
+    }
+
+    impl Observer for Events {
+        fn client_hello(&self, _: Option<DNSNameRef>, _: &[SignatureScheme]) {
+            self.0.lock().unwrap().push("client_hello");
+        }
+
+        fn session_resumed(&self) {
+            self.0.lock().unwrap().push("resumed");
+        }
+
+        fn handshake_complete(&self, _: &HandshakeInfo) {
+            self.0.lock().unwrap().push("complete");
+        }
+    }
+
+    let (server_config, client_config) = configs();
+    let unused = Arc::new(Events::default());
+    let events = Arc::new(Events::default());
+
+    // Replacing the observer keeps the configuration, and the clone the
+    // first one was set on keeps its own.
+    let first = TlsAcceptor::from(server_config).observer(unused.clone());
+    let acceptor = first.clone().observer(events.clone());
+    assert!(Arc::ptr_eq(&first.inner, &acceptor.inner));
+
+    let connector = TlsConnector::from(client_config);
+    let mut handshakes = Vec::new();
+    for _ in 0..2 {
+        let (client_io, server_io) = loopback();
+        let acceptor = acceptor.clone();
+        let server = thread::spawn(move || {
+            let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).unwrap();
+            assert_eq!(buf, MESSAGE);
+            stream.shutdown().unwrap();
+        });
+
+        // Waiting for the server's close_notify also reads the session
+        // ticket sent before it.
+        let mut stream = connector
+            .connect(domain(), AllowStdIo::new(client_io))
+            .wait()
+            .unwrap();
+        stream.write_all(MESSAGE).unwrap();
+        stream.close(Duration::from_secs(5)).wait().unwrap();
+        server.join().unwrap();
+
+        handshakes.push(events.take());
+    }
+
+    assert_eq!(
+        handshakes,
+        [
+            vec!["client_hello", "complete"],
+            vec!["client_hello", "resumed", "complete"],
+        ]
+    );
+    assert!(unused.take().is_empty());
+}
+
+#[test]
+fn test_metrics_count_streams() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use super::metrics::Metrics;
+    use futures::Future;
+    use std::io::{Read, Write};
+    use tokio_io::io::AllowStdIo;
+    use tokio_io::AsyncWrite;
+
+    let has = |metrics: &Metrics, line: &str| metrics.render().lines().any(|l| l == line);
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+    let metrics = Arc::new(Metrics::new());
+
+    let acceptor = TlsAcceptor::from(server_config).observer(metrics.clone());
+    let server = thread::spawn(move || {
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+    });
+
+    let connector = TlsConnector::from(client_config).observer(metrics.clone());
+    let mut stream = connector
+        .connect(domain(), AllowStdIo::new(client_io))
+        .wait()
+        .unwrap();
+    stream.write_all(MESSAGE).unwrap();
+    stream.shutdown().unwrap();
+    server.join().unwrap();
+
+    assert!(has(&metrics, "tls_handshakes_total 2"));
+    assert!(has(&metrics, "tls_handshakes_completed_total 2"));
+    assert!(has(&metrics, "tls_handshake_duration_seconds_count 2"));
+    assert!(has(&metrics, "tls_active_streams 1"));
+    drop(stream);
+    assert!(has(&metrics, "tls_active_streams 0"));
+}
+
+#[test]
+fn test_verify_callback_rejects() {
+    use super::callback::Verdict;
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use super::verify::VerifyError;
+    use futures::Future;
+    use tokio_io::io::AllowStdIo;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let acceptor = TlsAcceptor::from(server_config);
+    let server = thread::spawn(move || {
+        assert!(acceptor.accept(AllowStdIo::new(server_io)).wait().is_err());
+    });
+
+    let connector = TlsConnector::from(client_config).verify_callback(|verification| {
+        assert_eq!(verification.server_name, Some("localhost"));
+        assert_eq!(*verification.default_result, Ok(()));
+        Verdict::Reject("not today".to_owned())
+    });
+    let err = connector
+        .connect(domain(), AllowStdIo::new(client_io))
+        .wait()
+        .err()
+        .unwrap();
+    let err = err
+        .get_ref()
+        .and_then(|err| err.downcast_ref::<VerifyError>());
+    assert_eq!(err, Some(&VerifyError::Rejected("not today".to_owned())));
+    server.join().unwrap();
+}
+
+#[test]
+fn test_buffer_limit_with_stalled_peer() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use futures::Future;
+    use std::io::{Read, Write};
+    use tokio_io::io::AllowStdIo;
+    use tokio_io::AsyncWrite;
+
+    const LIMIT: usize = 16 * 1024;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        buf.len()
+    });
+
+    let connector = TlsConnector::from(client_config).buffer_limit(LIMIT);
+    let mut stream = connector
+        .connect(domain(), AllowStdIo::new(Recorder::new(client_io)))
+        .wait()
+        .unwrap();
+    stream.get_mut().0.get_mut().stalled = true;
+
+    // Nothing reaches the peer, so writes are only accepted until the limit
+    // is reached.
+    let chunk = [0; 1024];
+    let mut accepted = 0;
+    loop {
+        match stream.write(&chunk) {
+            Ok(n) => accepted += n,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) => panic!("{}", err),
+        }
+        assert!(accepted <= LIMIT);
+    }
+    assert!(accepted > 0);
+
+    // Once the peer reads again, everything accepted is delivered.
+    stream.get_mut().0.get_mut().stalled = false;
+    stream.flush().unwrap();
+    stream.shutdown().unwrap();
+
+    // Closing the socket with the server's session ticket still unread
+    // would reset the connection.
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    drop(stream);
+
+    assert_eq!(server.join().unwrap(), accepted);
+}
+
+#[test]
+fn test_read_buf_into_spare_capacity() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use bytes::BytesMut;
+    use futures::{Async, Future};
+    use std::io::Write;
+    use tokio_io::io::AllowStdIo;
+    use tokio_io::AsyncRead;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+        stream.write_all(MESSAGE).unwrap();
+        stream.flush().unwrap();
+    });
+
+    let connector = TlsConnector::from(client_config);
+    let mut stream = connector
+        .connect(domain(), AllowStdIo::new(client_io))
+        .wait()
+        .unwrap();
+
+    let mut buf = BytesMut::with_capacity(MESSAGE.len());
+    while buf.len() < MESSAGE.len() {
+        match stream.read_buf(&mut buf).unwrap() {
+            Async::Ready(n) => assert!(n > 0),
+            Async::NotReady => unreachable!(),
+        }
+    }
+    assert_eq!(&buf[..], MESSAGE);
+
+    server.join().unwrap();
+}
+
+#[test]
+fn test_engine_pump() {
+    use super::engine::{Engine, Event};
+    use super::entry::{CloseStatus, TlsAcceptor, TlsConnector};
+
+    fn pump<A: Session, B: Session>(from: &mut Engine<A>, to: &mut Engine<B>) -> bool {
+        let data = from.take_ciphertext();
+        assert_eq!(to.feed_ciphertext(&data).unwrap(), data.len());
+        !data.is_empty()
+    }
+
+    let (server_config, client_config) = configs();
+    let mut client = TlsConnector::from(client_config).engine(domain());
+    let mut server = TlsAcceptor::from(server_config).engine();
+
+    while pump(&mut client, &mut server) | pump(&mut server, &mut client) {}
+    assert_eq!(client.poll_event(), Some(Event::HandshakeComplete));
+    assert_eq!(server.poll_event(), Some(Event::HandshakeComplete));
+
+    client.write_plaintext(MESSAGE).unwrap();
+    client.close();
+    pump(&mut client, &mut server);
+
+    let mut buf = [0; MESSAGE.len()];
+    assert_eq!(server.read_plaintext(&mut buf).unwrap(), MESSAGE.len());
+    assert_eq!(buf, MESSAGE);
+    assert_eq!(server.read_plaintext(&mut buf).unwrap(), 0);
+    assert_eq!(server.poll_event(), Some(Event::PeerClosed));
+    assert_eq!(server.close_status(), CloseStatus::Authenticated);
+    assert_eq!(client.poll_event(), None);
+}
+
+#[cfg(target_os = "linux")]
+mod thread_driver {
+    use super::super::completion::CompletionIo;
+    use bytes::BytesMut;
+    use futures::sync::oneshot;
+    use futures::Future;
+    use std::io::{self, Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    /// Simulated completion driver: each operation owns its buffer and
+    /// completes on a thread of its own using blocking socket I/O.
+    pub(super) struct ThreadDriver(pub(super) TcpStream);
+
+    fn complete<T: Send + 'static>(
+        op: impl FnOnce() -> io::Result<T> + Send + 'static,
+    ) -> Box<dyn Future<Item = T, Error = io::Error> + Send> {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(op());
+        });
+        Box::new(rx.then(|result| match result {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "operation canceled")),
+        }))
+    }
+
+    impl CompletionIo for ThreadDriver {
+        type Read = Box<dyn Future<Item = (BytesMut, usize), Error = io::Error> + Send>;
+        type Write = Box<dyn Future<Item = (Vec<u8>, usize), Error = io::Error> + Send>;
+
+        fn read(&mut self, mut buf: BytesMut) -> Self::Read {
+            let mut socket = self.0.try_clone().unwrap();
+            complete(move || {
+                let mut chunk = vec![0; buf.capacity() - buf.len()];
+                let n = socket.read(&mut chunk)?;
+                buf.extend_from_slice(&chunk[..n]);
+                Ok((buf, n))
+            })
+        }
+
+        fn write(&mut self, buf: Vec<u8>) -> Self::Write {
+            let mut socket = self.0.try_clone().unwrap();
+            complete(move || {
+                let n = socket.write(&buf)?;
+                Ok((buf, n))
+            })
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_completion_loopback() {
+    use self::thread_driver::ThreadDriver;
+    use super::completion::CompletionStream;
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use bytes::BytesMut;
+    use futures::Future;
+    use std::io::{Read, Write};
+    use tokio_io::io::AllowStdIo;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+
+        let mut buf = [0; MESSAGE.len()];
+        stream.read_exact(&mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+        stream.flush().unwrap();
+    });
+
+    let engine = TlsConnector::from(client_config).engine(domain());
+    let stream = CompletionStream::new(ThreadDriver(client_io), engine)
+        .handshake()
+        .wait()
+        .unwrap();
+
+    let (stream, _) = stream.write(MESSAGE.to_vec()).wait().unwrap();
+    let (_, buf, n) = stream.read(BytesMut::new()).wait().unwrap();
+    assert_eq!(n, MESSAGE.len());
+    assert_eq!(&buf[..], MESSAGE);
+
+    server.join().unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_completion_write_waits_for_handshake() {
+    use self::thread_driver::ThreadDriver;
+    use super::completion::CompletionStream;
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use futures::Future;
+    use std::io::Read;
+    use tokio_io::io::AllowStdIo;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+
+    let server = thread::spawn(move || {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(AllowStdIo::new(server_io)).wait().unwrap();
+
+        let mut buf = [0; MESSAGE.len()];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, MESSAGE);
+    });
+
+    // The limit fills up before the handshake is done, so the write has to
+    // drive the handshake rather than retry the session.
+    let engine = TlsConnector::from(client_config)
+        .buffer_limit(4)
+        .engine(domain());
+    let stream = CompletionStream::new(ThreadDriver(client_io), engine);
+    let (stream, _) = stream.write(MESSAGE.to_vec()).wait().unwrap();
+    assert!(!stream.get_ref().1.is_handshaking());
+
+    server.join().unwrap();
+}
+
+#[cfg(feature = "mio")]
+#[test]
+fn test_mio_echo() {
+    use super::entry::{TlsAcceptor, TlsConnector};
+    use super::evented::TlsStream;
+    use mio::{Events, Poll, Token};
+    use std::io::{self, Read, Write};
+    use std::time::Duration;
+
+    const CLIENT: Token = Token(0);
+    const SERVER: Token = Token(1);
+
+    fn nonblocking<T>(result: io::Result<T>) -> Option<T> {
+        match result {
+            Ok(t) => Some(t),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => None,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = loopback();
+    let client_io = mio::net::TcpStream::from_stream(client_io).unwrap();
+    let server_io = mio::net::TcpStream::from_stream(server_io).unwrap();
+
+    let mut client = TlsStream::connect(&TlsConnector::from(client_config), domain(), client_io);
+    let mut server = TlsStream::accept(&TlsAcceptor::from(server_config), server_io);
+
+    let poll = Poll::new().unwrap();
+    client.register(&poll, CLIENT).unwrap();
+    server.register(&poll, SERVER).unwrap();
+
+    let mut events = Events::with_capacity(16);
+    let mut sent = false;
+    let mut received = Vec::new();
+    let mut buf = [0; 1024];
+
+    while received.len() < MESSAGE.len() {
+        poll.poll(&mut events, Some(Duration::from_secs(5)))
+            .unwrap();
+        assert!(!events.is_empty(), "timed out waiting for readiness");
+
+        for event in &events {
+            if event.token() == SERVER {
+                if nonblocking(server.handshake()).is_none() {
+                    continue;
+                }
+                while let Some(n) = nonblocking(server.read(&mut buf)) {
+                    if n == 0 {
+                        break;
+                    }
+                    server.write_all(&buf[..n]).unwrap();
+                    nonblocking(server.flush());
+                }
+            } else {
+                if nonblocking(client.handshake()).is_none() {
+                    continue;
+                }
+                while let Some(n) = nonblocking(client.read(&mut buf)) {
+                    if n == 0 {
+                        break;
+                    }
+                    received.extend_from_slice(&buf[..n]);
+                }
+            }
+        }
+
+        if !sent && !client.is_handshaking() {
+            client.write_all(MESSAGE).unwrap();
+            nonblocking(client.flush());
+            sent = true;
+        }
+
+        client.reregister(&poll, CLIENT).unwrap();
+        server.reregister(&poll, SERVER).unwrap();
+    }
+
+    assert_eq!(received, MESSAGE);
+}
+
+/// The server and client futures of an echo over `server_io`/`client_io`
+/// with the `std::future` flavour `$flavour`, whose streams are closed with
+/// `$close`, which has to be a path: the streams have a `close` method of
+/// their own. The IO extension traits of the flavour have to be in scope.
+///
+/// The client half-closes after sending, and both sides check that the
+/// settings of the connector and acceptor reached their streams.
+#[cfg(any(feature = "futures03", feature = "tokio1"))]
+macro_rules! std_future_echo {
+    ($flavour:ident, $close:path, $server_io:expr, $client_io:expr) => {{
+        use super::$flavour::{TlsAcceptor, TlsConnector};
+        use std::time::Duration;
+
+        let (server_config, client_config) = configs();
+        let (server_io, client_io) = ($server_io, $client_io);
+
+        let server = async move {
+            let acceptor = TlsAcceptor::from(server_config)
+                .strict_close(true)
+                .handshake_timeout(Duration::from_secs(5));
+            let mut stream = acceptor.accept(server_io).await.unwrap();
+
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, MESSAGE);
+            assert!(stream.is_close_authenticated());
+
+            stream.write_all(&buf).await.unwrap();
+            $close(&mut stream).await.unwrap();
+        };
+
+        let client = async move {
+            let connector = TlsConnector::from(client_config)
+                .strict_close(true)
+                .handshake_timeout(Duration::from_secs(5));
+            let mut stream = connector.connect(domain(), client_io).await.unwrap();
+
+            stream.write_all(MESSAGE).await.unwrap();
+            $close(&mut stream).await.unwrap();
+
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, MESSAGE);
+            assert!(stream.is_close_authenticated());
+        };
+
+        (server, client)
+    }};
+}
+
+#[cfg(feature = "futures03")]
+#[test]
+fn test_futures03_loopback() {
+    use futures_executor::block_on;
+    use futures_util::io::{AllowStdIo, AsyncReadExt, AsyncWriteExt};
+
+    let (client_io, server_io) = loopback();
+    let (server, client) = std_future_echo!(
+        futures03,
+        AsyncWriteExt::close,
+        AllowStdIo::new(server_io),
+        AllowStdIo::new(client_io)
+    );
+
+    let server = thread::spawn(move || block_on(server));
+    block_on(client);
+    server.join().unwrap();
+}
+
+#[cfg(feature = "futures03")]
+#[test]
+fn test_futures03_handshake_timeout() {
+    use super::futures03::TlsConnector;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task;
+    use std::time::Duration;
+
+    let (_, client_config) = configs();
+
+    task::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let io = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        // Accepted, but never answered.
+        let _peer = listener.accept().await.unwrap();
+
+        let err = TlsConnector::from(client_config)
+            .handshake_timeout(Duration::from_millis(100))
+            .connect(domain(), io)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    });
+}
+
+#[cfg(feature = "tokio1")]
+#[test]
+fn test_tokio1_duplex() {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::runtime::Builder;
+
+    let (client_io, server_io) = duplex(4096);
+    let (server, client) = std_future_echo!(tokio1, AsyncWriteExt::shutdown, server_io, client_io);
+
+    let runtime = Builder::new_current_thread().enable_time().build().unwrap();
+    runtime.block_on(async { tokio::join!(server, client) });
+}
+
+#[cfg(feature = "tokio1")]
+#[test]
+fn test_tokio1_handshake_timeout() {
+    use super::error::{self, ErrorKind};
+    use super::tokio1::TlsConnector;
+    use std::time::{Duration, Instant};
+    use tokio::io::duplex;
+    use tokio::runtime::Builder;
+
+    let (_, client_config) = configs();
+    // The peer end is kept open but never read from or written to.
+    let (client_io, _server_io) = duplex(4096);
+
+    let runtime = Builder::new_current_thread().enable_time().build().unwrap();
+    let start = Instant::now();
+    let err = runtime
+        .block_on(
+            TlsConnector::from(client_config)
+                .handshake_timeout(Duration::from_millis(100))
+                .connect(domain(), client_io),
+        )
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(error::kind(&err), ErrorKind::HandshakeTimeout);
+    assert!(start.elapsed() >= Duration::from_millis(100));
+}
+
+#[cfg(feature = "tokio1")]
+#[test]
+fn test_tokio1_failable_connect() {
+    use super::error::{self, ErrorKind};
+    use super::tokio1::{TlsAcceptor, TlsConnector};
+    use tokio::io::{duplex, AsyncWriteExt};
+    use tokio::runtime::Builder;
+
+    let (server_config, _) = configs();
+    let (client_io, server_io) = duplex(4096);
+
+    let server = async move {
+        let acceptor = TlsAcceptor::from(server_config);
+        assert!(acceptor.accept(server_io).await.is_err());
+    };
+
+    let client = async move {
+        // Without roots, the server's certificate can not be verified.
+        let connector = TlsConnector::from(Arc::new(ClientConfig::new()));
+        let failure = connector
+            .connect(domain(), client_io)
+            .into_failable()
+            .await
+            .unwrap_err();
+        assert_eq!(error::kind(&failure.error), ErrorKind::UnknownIssuer);
+        assert!(failure.session.is_handshaking());
+
+        let (_, mut io, _) = failure.into_inner();
+        io.shutdown().await.unwrap();
+    };
+
+    let runtime = Builder::new_current_thread().enable_time().build().unwrap();
+    runtime.block_on(async { tokio::join!(server, client) });
+}
+
+#[cfg(feature = "tokio1")]
+#[test]
+fn test_tokio1_truncation_detected() {
+    use super::entry::CloseStatus;
+    use super::tokio1::{TlsAcceptor, TlsConnector};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::runtime::Builder;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = duplex(4096);
+
+    let server = async move {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(server_io).await.unwrap();
+
+        // Drop the connection without sending close_notify.
+        stream.write_all(MESSAGE).await.unwrap();
+        stream.flush().await.unwrap();
+    };
+
+    let client = async move {
+        let connector = TlsConnector::from(client_config).strict_close(true);
+        let mut stream = connector.connect(domain(), client_io).await.unwrap();
+
+        let mut buf = Vec::new();
+        let err = stream.read_to_end(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(buf, MESSAGE);
+        assert_eq!(stream.close_status(), CloseStatus::Truncated);
+    };
+
+    let runtime = Builder::new_current_thread().enable_time().build().unwrap();
+    runtime.block_on(async { tokio::join!(server, client) });
+}
+
+#[cfg(feature = "tokio1")]
+#[test]
+fn test_tokio1_cork() {
+    use super::tokio1::{TlsAcceptor, TlsConnector};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::runtime::Builder;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = duplex(4096);
+
+    let server = async move {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(server_io).await.unwrap();
+
+        let mut buf = [0; MESSAGE.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, MESSAGE);
+        stream.shutdown().await.unwrap();
+    };
+
+    let client = async move {
+        let connector = TlsConnector::from(client_config).cork(1024, None);
+        let mut stream = connector.connect(domain(), client_io).await.unwrap();
+
+        for byte in MESSAGE.chunks(1) {
+            stream.write_all(byte).await.unwrap();
+        }
+        assert_eq!(stream.cork.buffered(), MESSAGE.len());
+
+        stream.flush().await.unwrap();
+        assert_eq!(stream.cork.buffered(), 0);
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+    };
+
+    let runtime = Builder::new_current_thread().enable_time().build().unwrap();
+    runtime.block_on(async { tokio::join!(server, client) });
+}
+
+#[cfg(feature = "tokio1")]
+#[test]
+fn test_tokio1_cork_max_delay() {
+    use super::tokio1::{TlsAcceptor, TlsConnector};
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::runtime::Builder;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = duplex(4096);
+
+    let server = async move {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(server_io).await.unwrap();
+
+        let mut buf = [0; MESSAGE.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+        stream.flush().await.unwrap();
+    };
+
+    let client = async move {
+        let connector =
+            TlsConnector::from(client_config).cork(1024, Some(Duration::from_millis(50)));
+        let mut stream = connector.connect(domain(), client_io).await.unwrap();
+
+        stream.write_all(MESSAGE).await.unwrap();
+        assert_eq!(stream.cork.buffered(), MESSAGE.len());
+
+        // Never flushed: the timer wakes the reading task, which sends the
+        // message once it has been held back for `max_delay`.
+        let mut buf = [0; MESSAGE.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, MESSAGE);
+    };
+
+    let runtime = Builder::new_current_thread().enable_time().build().unwrap();
+    runtime.block_on(async {
+        let echo = async { tokio::join!(server, client) };
+        tokio::time::timeout(Duration::from_secs(5), echo)
+            .await
+            .expect("the corked message was never sent");
+    });
+}
+
+#[cfg(feature = "tokio1")]
+#[test]
+fn test_tokio1_half_close() {
+    use super::entry::TlsState;
+    use super::tokio1::{self, TlsAcceptor, TlsConnector};
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::runtime::Builder;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = duplex(4096);
+
+    let server = async move {
+        let acceptor = TlsAcceptor::from(server_config);
+        let mut stream = acceptor.accept(server_io).await.unwrap();
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, MESSAGE);
+
+        // Reading the client's close_notify leaves our side open.
+        stream.write_all(&buf).await.unwrap();
+        tokio1::shutdown_write(&mut stream).await.unwrap();
+    };
+
+    let client = async move {
+        let connector = TlsConnector::from(client_config);
+        let mut stream = connector.connect(domain(), client_io).await.unwrap();
+
+        stream.write_all(MESSAGE).await.unwrap();
+        tokio1::shutdown_write(&mut stream).await.unwrap();
+        assert_eq!(stream.shutdown_state(), TlsState::WriteShutdown);
+
+        let mut buf = [0; MESSAGE.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, MESSAGE);
+
+        let stream = tokio1::close(stream, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(stream.shutdown_state(), TlsState::FullyShutdown);
+        assert!(stream.is_close_authenticated());
+    };
+
+    let runtime = Builder::new_current_thread().enable_time().build().unwrap();
+    runtime.block_on(async { tokio::join!(server, client) });
+}
+
+#[cfg(feature = "tokio1")]
+#[test]
+fn test_tokio1_close_times_out_on_silent_peer() {
+    use super::tokio1::{self, TlsAcceptor, TlsConnector};
+    use std::time::{Duration, Instant};
+    use tokio::io::duplex;
+    use tokio::runtime::Builder;
+
+    let (server_config, client_config) = configs();
+    let (client_io, server_io) = duplex(4096);
+
+    let server = async move {
+        let acceptor = TlsAcceptor::from(server_config);
+        // Kept open until the client is done, but never answers its
+        // close_notify.
+        acceptor.accept(server_io).await.unwrap()
+    };
+
+    let client = async move {
+        let connector = TlsConnector::from(client_config);
+        let stream = connector.connect(domain(), client_io).await.unwrap();
+
+        let start = Instant::now();
+        let err = match tokio1::close(stream, Duration::from_millis(100)).await {
+            Ok(_) => panic!("close resolved without the peer's close_notify"),
+            Err(err) => err,
+        };
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    };
+
+    let runtime = Builder::new_current_thread().enable_time().build().unwrap();
+    runtime.block_on(async { tokio::join!(server, client) });
+}
+
+#[cfg(feature = "tokio1")]
+#[test]
+fn test_tokio1_split_both_halves_blocked() {
+    use super::tokio1::{self, TlsAcceptor, TlsConnector};
+    use std::time::Duration;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use tokio::runtime::Builder;
+
+    let (server_config, client_config) = configs();
+    // Far less than is sent, so that the client's reader and writer both
+    // keep waiting on the IO at the same time.
+    let (client_io, server_io) = duplex(1024);
+    let data = vec![0x5a; 256 * 1024];
+    let expected = data.clone();
+
+    let server = async move {
+        let acceptor = TlsAcceptor::from(server_config);
+        let stream = acceptor.accept(server_io).await.unwrap();
+        let (mut read_half, mut write_half) = tokio1::split(stream);
+        tokio::io::copy(&mut read_half, &mut write_half)
+            .await
+            .unwrap();
+        write_half.shutdown().await.unwrap();
+    };
+
+    let client = async move {
+        let connector = TlsConnector::from(client_config);
+        let stream = connector.connect(domain(), client_io).await.unwrap();
+        let (mut read_half, mut write_half) = tokio1::split(stream);
+
+        // Tasks of their own, so that waking one does not poll the other.
+        let writer = tokio::spawn(async move {
+            write_half.write_all(&data).await.unwrap();
+            write_half.shutdown().await.unwrap();
+            write_half
+        });
+        let reader = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            read_half.read_to_end(&mut buf).await.unwrap();
+            assert!(buf == expected);
+            read_half
+        });
+
+        let write_half = writer.await.unwrap();
+        let stream = reader.await.unwrap().unsplit(write_half);
+        assert!(stream.is_close_authenticated());
+    };
+
+    let runtime = Builder::new_current_thread().enable_time().build().unwrap();
+    runtime.block_on(async {
+        let echo = async {
+            let server = tokio::spawn(server);
+            client.await;
+            server.await.unwrap();
+        };
+        tokio::time::timeout(Duration::from_secs(10), echo)
+            .await
+            .expect("a half was never woken");
+    });
+}
+
+#[cfg(feature = "async-std")]
+#[test]
+fn test_async_std_loopback() {
+    use super::async_std::{accept, connect};
+    use async_std::io::prelude::{ReadExt, WriteExt};
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task;
+
+    let (server_config, client_config) = configs();
+
+    task::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = task::spawn(async move {
+            let (io, _) = listener.accept().await.unwrap();
+            let mut stream = accept(server_config, io).await.unwrap();
+
+            let mut buf = [0; MESSAGE.len()];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let io = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connect(client_config, domain(), io).await.unwrap();
+
+        stream.write_all(MESSAGE).await.unwrap();
+        stream.flush().await.unwrap();
+
+        let mut buf = [0; MESSAGE.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, MESSAGE);
+
+        server.await;
+    });
+}
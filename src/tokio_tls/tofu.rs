@@ -0,0 +1,358 @@
+//! Trust on first use, as SSH does it.
+//!
+//! `KnownHosts` maps server names to the `pin::Pin` of their certificate's
+//! public key. A connector set up with `TlsConnector::known_hosts` accepts
+//! any certificate from a server it has not seen before and records it;
+//! after that, it only accepts certificates with the same public key, so
+//! re-issuing a certificate for the same key does not trip it. There is no
+//! CA or hostname validation.
+//!
+//! The store is kept in a file of `<server name> sha256/<base64>` lines, in
+//! which blank lines and lines starting with `#` are ignored. The file is
+//! rewritten on every change, which drops such lines.
+//!
+//! A connection to a changed host fails with `VerifyError::KnownHostChanged`.
+//! Failing to save a host recorded on first use does not fail the
+//! connection: the host stays recorded in memory and the error is logged.
+use super::pin::Pin;
+use super::verify::{self, VerifyError};
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use webpki::DNSNameRef;
+
+const PREFIX: &str = "sha256/";
+
+/// Tells apart the temporary files of concurrent saves in this process.
+static SAVES: AtomicUsize = AtomicUsize::new(0);
+
+/// The known hosts and their public keys, optionally backed by a file.
+#[derive(Debug)]
+pub struct KnownHosts {
+    path: Option<PathBuf>,
+    hosts: Mutex<BTreeMap<String, Pin>>,
+    /// Held while saving, so that a save never overwrites a later one.
+    saving: Mutex<()>,
+}
+
+impl KnownHosts {
+    /// A store that is not saved anywhere.
+    pub fn in_memory() -> KnownHosts {
+        KnownHosts {
+            path: None,
+            hosts: Mutex::new(BTreeMap::new()),
+            saving: Mutex::new(()),
+        }
+    }
+
+    /// Loads the store from `path`, starting out empty if it does not exist
+    /// yet. Fails with `InvalidData` if a line can not be parsed.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<KnownHosts> {
+        let path = path.as_ref().to_owned();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(KnownHosts {
+            path: Some(path),
+            hosts: Mutex::new(parse(&contents)?),
+            saving: Mutex::new(()),
+        })
+    }
+
+    /// The public key recorded for `host`, if any.
+    pub fn get(&self, host: &str) -> Option<Pin> {
+        self.hosts.lock().unwrap().get(host).copied()
+    }
+
+    /// All known hosts and their public keys, sorted by name.
+    pub fn entries(&self) -> Vec<(String, Pin)> {
+        let hosts = self.hosts.lock().unwrap();
+        hosts
+            .iter()
+            .map(|(host, pin)| (host.clone(), *pin))
+            .collect()
+    }
+
+    /// Records `pin` for `host`, replacing any previous entry. Seeding a host
+    /// this way has its first connection checked too.
+    pub fn insert(&self, host: &str, pin: Pin) -> io::Result<()> {
+        self.hosts.lock().unwrap().insert(host.to_owned(), pin);
+        self.save()
+    }
+
+    /// Forgets `host`, so that the next certificate it presents is trusted
+    /// again. Returns whether it was known.
+    pub fn revoke(&self, host: &str) -> io::Result<bool> {
+        if self.hosts.lock().unwrap().remove(host).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Checks `pin` against the entry for `host`, recording it if there is
+    /// none.
+    fn check(&self, host: &str, pin: Pin) -> Result<(), TLSError> {
+        {
+            let mut hosts = self.hosts.lock().unwrap();
+            match hosts.get(host) {
+                Some(known) if *known == pin => return Ok(()),
+                Some(known) => {
+                    tls_event!(
+                        error,
+                        server_name = host,
+                        known = %known,
+                        presented = %pin,
+                        "TLS certificate of a known host changed"
+                    );
+                    return Err(verify::fail(VerifyError::KnownHostChanged {
+                        host: host.to_owned(),
+                        known: *known,
+                        presented: pin,
+                    }));
+                }
+                None => {}
+            }
+
+            tls_event!(info, server_name = host, pin = %pin, "TLS host recorded on first use");
+            hosts.insert(host.to_owned(), pin);
+        }
+
+        // The host is trusted either way; only the next process would not
+        // know it.
+        if let Err(_err) = self.save() {
+            tls_event!(
+                error,
+                server_name = host,
+                error = %_err,
+                "failed to save the known hosts"
+            );
+        }
+        Ok(())
+    }
+
+    /// Writes the hosts to a temporary file next to the store's, and renames
+    /// it over the store's so that readers never see half a file.
+    ///
+    /// The hosts are copied first, so that handshakes checking them do not
+    /// wait for the file to be written.
+    fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let _saving = self.saving.lock().unwrap();
+        let hosts = self.hosts.lock().unwrap().clone();
+
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(format!(
+            ".{}.{}.tmp",
+            process::id(),
+            SAVES.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let result = write_hosts(Path::new(&tmp), &hosts).and_then(|()| fs::rename(&tmp, path));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+}
+
+fn write_hosts(path: &Path, hosts: &BTreeMap<String, Pin>) -> io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    for (host, pin) in hosts {
+        writeln!(file, "{} {}{}", host, PREFIX, pin)?;
+    }
+    file.sync_all()
+}
+
+fn parse(contents: &str) -> io::Result<BTreeMap<String, Pin>> {
+    let mut hosts = BTreeMap::new();
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid known hosts entry on line {}", n + 1),
+            )
+        };
+
+        let mut fields = line.split_whitespace();
+        let (host, pin) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(host), Some(pin), None) if pin.starts_with(PREFIX) => {
+                (host, &pin[PREFIX.len()..])
+            }
+            _ => return Err(invalid()),
+        };
+        let pin = Pin::from_base64(pin).map_err(|_| invalid())?;
+        hosts.insert(host.to_owned(), pin);
+    }
+    Ok(hosts)
+}
+
+struct TofuVerifier {
+    hosts: Arc<KnownHosts>,
+}
+
+impl ServerCertVerifier for TofuVerifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented: &[Certificate],
+        dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        verify::clear();
+        let leaf = presented.first().ok_or(TLSError::NoCertificatesPresented)?;
+        let pin =
+            Pin::of_certificate(leaf).map_err(|_| TLSError::WebPKIError(webpki::Error::BadDER))?;
+        self.hosts.check(dns_name.into(), pin)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// A copy of `config` that verifies server certificates against `hosts`.
+pub(crate) fn tofu_config(config: &ClientConfig, hosts: Arc<KnownHosts>) -> Arc<ClientConfig> {
+    let mut config = config.clone();
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(TofuVerifier { hosts }));
+    Arc::new(config)
+}
+
+#[cfg(test)]
+mod test_tofu {
+    use super::*;
+    use rustls::internal::pemfile::certs;
+    use std::env;
+    use std::io::{BufReader, Cursor};
+
+    const CHAIN: &[u8] = include_bytes!("../../tests/end.chain");
+    const EXPIRED: &[u8] = include_bytes!("../../tests/expired.cert");
+    const END_PIN: &str = "Sec6OpkdaOfkjoMsVZMroPq9ZiT3yl/RVZNBOs7dE6E=";
+
+    fn verify(hosts: &Arc<KnownHosts>, chain: &[u8], name: &str) -> Result<(), TLSError> {
+        let chain = certs(&mut BufReader::new(Cursor::new(chain))).unwrap();
+        let name = DNSNameRef::try_from_ascii_str(name).unwrap();
+        TofuVerifier {
+            hosts: hosts.clone(),
+        }
+        .verify_server_cert(&RootCertStore::empty(), &chain, name, &[])
+        .map(|_| ())
+    }
+
+    #[test]
+    fn test_first_use() {
+        let hosts = Arc::new(KnownHosts::in_memory());
+        assert_eq!(verify(&hosts, CHAIN, "localhost"), Ok(()));
+        assert_eq!(
+            hosts.entries(),
+            vec![("localhost".to_owned(), Pin::from_base64(END_PIN).unwrap())]
+        );
+        assert_eq!(verify(&hosts, CHAIN, "localhost"), Ok(()));
+
+        let err = verify(&hosts, EXPIRED, "localhost").unwrap_err();
+        match verify::failure(&err) {
+            Some(VerifyError::KnownHostChanged {
+                host,
+                known,
+                presented,
+            }) => {
+                assert_eq!(host, "localhost");
+                assert_eq!(known, Pin::from_base64(END_PIN).unwrap());
+                assert_ne!(presented, known);
+            }
+            failure => panic!("unexpected failure {:?}", failure),
+        }
+        // Other hosts are trusted on their own first use.
+        assert_eq!(verify(&hosts, EXPIRED, "example.com"), Ok(()));
+
+        assert!(hosts.revoke("localhost").unwrap());
+        assert!(!hosts.revoke("localhost").unwrap());
+        assert_eq!(verify(&hosts, EXPIRED, "localhost"), Ok(()));
+    }
+
+    #[test]
+    fn test_seeded() {
+        let hosts = Arc::new(KnownHosts::in_memory());
+        let other = Pin::sha256([7; 32]);
+        hosts.insert("localhost", other).unwrap();
+        let err = verify(&hosts, CHAIN, "localhost").unwrap_err();
+        assert_eq!(
+            verify::failure(&err),
+            Some(VerifyError::KnownHostChanged {
+                host: "localhost".to_owned(),
+                known: other,
+                presented: Pin::from_base64(END_PIN).unwrap(),
+            })
+        );
+        assert_eq!(hosts.get("localhost"), Some(other));
+    }
+
+    #[test]
+    fn test_file() {
+        let path = env::temp_dir().join(format!("known_hosts_test_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let hosts = Arc::new(KnownHosts::open(&path).unwrap());
+        assert!(hosts.entries().is_empty());
+        assert_eq!(verify(&hosts, CHAIN, "localhost"), Ok(()));
+        hosts.insert("example.com", Pin::sha256([0; 32])).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!(
+                "example.com sha256/{}\nlocalhost sha256/{}\n",
+                Pin::sha256([0; 32]),
+                END_PIN
+            )
+        );
+
+        let reopened = KnownHosts::open(&path).unwrap();
+        assert_eq!(reopened.entries(), hosts.entries());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_failure_keeps_host() {
+        let dir = env::temp_dir().join(format!("known_hosts_missing_{}", process::id()));
+        let hosts = Arc::new(KnownHosts::open(dir.join("known_hosts")).unwrap());
+
+        // The directory does not exist, so saving fails, but the host is
+        // still trusted for this process.
+        assert_eq!(verify(&hosts, CHAIN, "localhost"), Ok(()));
+        assert_eq!(
+            hosts.get("localhost"),
+            Some(Pin::from_base64(END_PIN).unwrap())
+        );
+        assert!(hosts.insert("example.com", Pin::sha256([0; 32])).is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let hosts = parse(&format!("# comment\n\n  localhost sha256/{}  \n", END_PIN)).unwrap();
+        assert_eq!(hosts.len(), 1);
+
+        for line in &[
+            "localhost",
+            "localhost sha1/AAAA",
+            "localhost sha256/AAAA",
+            "localhost sha256/AAAA extra",
+        ] {
+            let err = parse(line).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Tokio 1.x flavour of the TLS streams, for IO implementing
+//! `tokio::io::AsyncRead`/`AsyncWrite`.
+//!
+//! Like the futures 0.3 flavour, this is driven by the same `common::Stream`
+//! I/O loop as the futures 0.1 streams; see `std_future` for how the IO is
+//! adapted to it.
+use super::client;
+use super::common::MAX_FRAGMENT_LEN;
+use super::server;
+use super::std_future::{self, PollIo, PollStream, Sleep, Timer};
+use std::cmp;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Marks the tokio 1.x flavour of the `std_future` types.
+pub enum Tokio1 {}
+
+/// Deadlines sleep on the tokio timer, so the runtime must have it enabled.
+impl Timer for Tokio1 {
+    fn sleep_until(at: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(at.into()))
+    }
+}
+
+/// A wrapper around an `entry::TlsConnector`, providing a `connect` method
+/// returning a future that runs on tokio 1.x.
+pub type TlsConnector = std_future::TlsConnector<Tokio1>;
+
+/// A wrapper around an `entry::TlsAcceptor`, providing an `accept` method
+/// returning a future that runs on tokio 1.x.
+pub type TlsAcceptor = std_future::TlsAcceptor<Tokio1>;
+
+/// Future returned from `TlsConnector::connect` which will resolve once the
+/// connection handshake has finished.
+pub type Connect<IO> = std_future::Connect<IO, Tokio1>;
+
+/// Future returned from `TlsAcceptor::accept` which will resolve once the
+/// accept handshake has finished.
+pub type Accept<IO> = std_future::Accept<IO, Tokio1>;
+
+/// Like `Connect`, but fails with an `entry::HandshakeFailure` carrying the
+/// IO and session; see `Connect::into_failable`.
+pub type FailableConnect<IO> = std_future::FailableConnect<IO, Tokio1>;
+
+/// Like `Accept`, but fails with an `entry::HandshakeFailure` carrying the
+/// IO and session; see `Accept::into_failable`.
+pub type FailableAccept<IO> = std_future::FailableAccept<IO, Tokio1>;
+
+/// Sends close_notify and flushes it, but keeps the read side open so that
+/// the peer can finish sending.
+///
+/// Unlike closing the stream, this does not shut down the underlying IO.
+pub fn shutdown_write<S>(stream: &mut S) -> std_future::ShutdownWrite<S, Tokio1>
+where
+    S: PollStream<Tokio1>,
+{
+    std_future::ShutdownWrite::new(stream)
+}
+
+/// Sends close_notify and waits for the peer's close_notify, resolving to
+/// the stream, or failing with a `TimedOut` error once `timeout` elapses.
+///
+/// Application data the peer sends in the meantime is discarded.
+pub fn close<S>(stream: S, timeout: Duration) -> std_future::Close<S, Tokio1>
+where
+    S: PollStream<Tokio1> + Unpin,
+{
+    std_future::Close::new(stream, timeout)
+}
+
+/// The readable half of a stream, from `split`.
+pub type ReadHalf<S> = std_future::ReadHalf<S, Tokio1>;
+
+/// The writable half of a stream, from `split`.
+pub type WriteHalf<S> = std_future::WriteHalf<S, Tokio1>;
+
+/// Splits a stream into halves that can be used from different tasks.
+/// `ReadHalf::unsplit` puts them back together.
+pub fn split<S>(stream: S) -> (ReadHalf<S>, WriteHalf<S>)
+where
+    S: PollStream<Tokio1>,
+{
+    std_future::split(stream)
+}
+
+impl<IO: AsyncRead + AsyncWrite + Unpin> PollIo<Tokio1> for IO {
+    fn poll_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut buf = ReadBuf::new(buf);
+        match Pin::new(self).poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) => Poll::Ready(Ok(buf.filled().len())),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(self).poll_write(cx, buf)
+    }
+
+    fn poll_flush(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(self).poll_flush(cx)
+    }
+
+    fn poll_shutdown(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(self).poll_shutdown(cx)
+    }
+}
+
+/// The part of `buf` a read goes into.
+///
+/// A fresh `ReadBuf` over spare capacity has to be zeroed before it can be
+/// handed out as `&mut [u8]`. A single read returns at most one record's
+/// worth of plaintext in the common case, so only that much is zeroed rather
+/// than all of the capacity.
+fn unfilled<'a>(buf: &'a mut ReadBuf) -> &'a mut [u8] {
+    let len = cmp::min(buf.remaining(), MAX_FRAGMENT_LEN);
+    buf.initialize_unfilled_to(len)
+}
+
+macro_rules! tokio1_stream {
+    ($stream:ident) => {
+        impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncRead for $stream::TlsStream<IO> {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &mut ReadBuf,
+            ) -> Poll<io::Result<()>> {
+                let this = self.get_mut();
+                match PollStream::<Tokio1>::poll_tls_read(this, cx, unfilled(buf)) {
+                    Poll::Ready(Ok(n)) => {
+                        buf.advance(n);
+                        Poll::Ready(Ok(()))
+                    }
+                    Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+        }
+
+        impl<IO: AsyncRead + AsyncWrite + Unpin> AsyncWrite for $stream::TlsStream<IO> {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                cx: &mut Context,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                PollStream::<Tokio1>::poll_tls_write(self.get_mut(), cx, buf)
+            }
+
+            fn poll_write_vectored(
+                self: Pin<&mut Self>,
+                cx: &mut Context,
+                bufs: &[io::IoSlice],
+            ) -> Poll<io::Result<usize>> {
+                PollStream::<Tokio1>::poll_tls_write_vectored(self.get_mut(), cx, bufs)
+            }
+
+            fn is_write_vectored(&self) -> bool {
+                true
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                PollStream::<Tokio1>::poll_tls_flush(self.get_mut(), cx)
+            }
+
+            fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                PollStream::<Tokio1>::poll_tls_close(self.get_mut(), cx)
+            }
+        }
+    };
+}
+
+tokio1_stream!(client);
+tokio1_stream!(server);
+
+impl<S: PollStream<Tokio1>> AsyncRead for ReadHalf<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut().poll_read(cx, unfilled(buf)) {
+            Poll::Ready(Ok(n)) => {
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: PollStream<Tokio1>> AsyncWrite for WriteHalf<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        bufs: &[io::IoSlice],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.get_mut().poll_close(cx)
+    }
+}
@@ -0,0 +1,62 @@
+//! Optional `tracing` instrumentation.
+//!
+//! With the `tracing` feature enabled, each stream gets a span that is
+//! entered whenever it does any work, and the I/O loop emits events for
+//! state transitions, `WouldBlock` and TLS errors. Only metadata is ever
+//! recorded: never plaintext, ciphertext, keys or certificates. Without the
+//! feature, spans are zero-sized and the macros expand to nothing.
+
+/// Emits a `tracing` event at the given level when the `tracing` feature is
+/// enabled.
+macro_rules! tls_event {
+    ($level:ident, $($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)*);
+    };
+}
+
+use webpki::DNSNameRef;
+
+/// The span of a single TLS stream.
+#[derive(Debug, Clone)]
+pub struct ConnSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+/// Keeps a `ConnSpan` entered until dropped.
+#[cfg(feature = "tracing")]
+pub(crate) type Entered = tracing::span::EnteredSpan;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Entered;
+
+impl ConnSpan {
+    #[allow(unused_variables)]
+    pub(crate) fn client(domain: DNSNameRef) -> ConnSpan {
+        ConnSpan {
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!("tls", side = "client", server_name = <&str>::from(domain)),
+        }
+    }
+
+    pub(crate) fn server() -> ConnSpan {
+        ConnSpan {
+            #[cfg(feature = "tracing")]
+            span: tracing::debug_span!("tls", side = "server"),
+        }
+    }
+
+    /// Enters the span; it is exited when the returned guard is dropped.
+    #[inline]
+    pub(crate) fn enter(&self) -> Entered {
+        #[cfg(feature = "tracing")]
+        {
+            self.span.clone().entered()
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            Entered
+        }
+    }
+}
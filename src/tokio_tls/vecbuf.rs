@@ -1,79 +1,91 @@
+//! A `Buf` over a list of byte slices, such as the records rustls hands to
+//! `WriteV::writev`.
 use bytes::Buf;
 use iovec::IoVec;
-use std::cmp::{self, Ordering};
 
 pub struct VecBuf<'a, 'b: 'a> {
     pos: usize,
     cur: usize,
+    remaining: usize,
     inner: &'a [&'b [u8]],
 }
 
 impl<'a, 'b> VecBuf<'a, 'b> {
     pub fn new(vbytes: &'a [&'b [u8]]) -> Self {
-        VecBuf {
+        let mut buf = VecBuf {
             pos: 0,
             cur: 0,
+            remaining: vbytes.iter().map(|bytes| bytes.len()).sum(),
             inner: vbytes,
+        };
+        buf.skip_empty();
+        buf
+    }
+
+    /// Moves past exhausted and empty slices, so that `pos` always points at
+    /// a slice with bytes left, or past the end.
+    fn skip_empty(&mut self) {
+        while self.pos < self.inner.len() && self.cur == self.inner[self.pos].len() {
+            self.pos += 1;
+            self.cur = 0;
         }
     }
 }
 
 impl<'a, 'b> Buf for VecBuf<'a, 'b> {
+    #[inline]
     fn remaining(&self) -> usize {
-        let sum = self
-            .inner
-            .iter()
-            .skip(self.pos)
-            .map(|bytes| bytes.len())
-            .sum::<usize>();
-        sum - self.cur
+        self.remaining
     }
 
     fn bytes(&self) -> &[u8] {
-        &self.inner[self.pos][self.cur..]
-    }
-
-    fn advance(&mut self, cnt: usize) {
-        let current = self.inner[self.pos].len();
-        match (self.cur + cnt).cmp(&current) {
-            Ordering::Equal => {
-                if self.pos + 1 < self.inner.len() {
-                    self.pos += 1;
-                    self.cur = 0;
-                } else {
-                    self.cur += cnt;
-                }
-            }
-            Ordering::Greater => {
-                if self.pos + 1 < self.inner.len() {
-                    self.pos += 1;
-                }
-                let remaining = self.cur + cnt - current;
-                self.advance(remaining);
-            }
-            Ordering::Less => self.cur += cnt,
+        match self.inner.get(self.pos) {
+            Some(bytes) => &bytes[self.cur..],
+            None => &[],
         }
     }
 
-    #[allow(clippy::needless_range_loop)]
-    fn bytes_vec<'c>(&'c self, dst: &mut [&'c IoVec]) -> usize {
-        let len = cmp::min(self.inner.len() - self.pos, dst.len());
+    fn advance(&mut self, mut cnt: usize) {
+        assert!(cnt <= self.remaining, "advance past the end of VecBuf");
+        self.remaining -= cnt;
 
-        if len > 0 {
-            dst[0] = self.bytes().into();
+        while cnt > 0 {
+            let left = self.inner[self.pos].len() - self.cur;
+            if cnt < left {
+                self.cur += cnt;
+                break;
+            }
+            cnt -= left;
+            self.pos += 1;
+            self.cur = 0;
         }
+        self.skip_empty();
+    }
 
-        for i in 1..len {
-            dst[i] = self.inner[self.pos + i].into();
+    fn bytes_vec<'c>(&'c self, dst: &mut [&'c IoVec]) -> usize {
+        if dst.is_empty() || self.pos == self.inner.len() {
+            return 0;
         }
 
-        len
+        dst[0] = self.bytes().into();
+        let mut n = 1;
+
+        // `IoVec` can not be empty, so empty slices are left out.
+        let rest = self.inner[self.pos + 1..]
+            .iter()
+            .filter(|bytes| !bytes.is_empty());
+        for (iovec, bytes) in dst[1..].iter_mut().zip(rest) {
+            *iovec = (*bytes).into();
+            n += 1;
+        }
+        n
     }
 }
 
 #[cfg(test)]
 mod test_vecbuf {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_fresh_cursor_vec() {
@@ -125,4 +137,97 @@ mod test_vecbuf {
 
         assert_eq!(2, buf.bytes_vec(&mut dst[..]));
     }
+
+    #[test]
+    fn test_bytes_vec_all_segments() {
+        let mut buf = VecBuf::new(&[b"he", b"", b"ll", b"o"]);
+        buf.advance(1);
+
+        let b: &[u8] = &[0];
+        let mut dst: [&IoVec; 4] = [b.into(); 4];
+
+        assert_eq!(3, buf.bytes_vec(&mut dst[..]));
+        assert_eq!(&dst[0][..], b"e");
+        assert_eq!(&dst[1][..], b"ll");
+        assert_eq!(&dst[2][..], b"o");
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut buf = VecBuf::new(&[b"", b""]);
+        assert_eq!(buf.remaining(), 0);
+        assert_eq!(buf.bytes(), b"");
+        buf.advance(0);
+
+        let b: &[u8] = &[0];
+        let mut dst: [&IoVec; 1] = [b.into()];
+        assert_eq!(0, buf.bytes_vec(&mut dst[..]));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_advance_past_end() {
+        let mut buf = VecBuf::new(&[b"he", b"llo"]);
+        buf.advance(6);
+    }
+
+    /// The simplest possible `Buf` over the same bytes, to check against.
+    struct Reference {
+        data: Vec<u8>,
+        pos: usize,
+    }
+
+    impl Reference {
+        fn new(vbytes: &[&[u8]]) -> Reference {
+            Reference {
+                data: vbytes.concat(),
+                pos: 0,
+            }
+        }
+    }
+
+    impl Buf for Reference {
+        fn remaining(&self) -> usize {
+            self.data.len() - self.pos
+        }
+
+        fn bytes(&self) -> &[u8] {
+            &self.data[self.pos..]
+        }
+
+        fn advance(&mut self, cnt: usize) {
+            assert!(cnt <= self.remaining());
+            self.pos += cnt;
+        }
+    }
+
+    fn segments() -> impl Strategy<Value = Vec<Vec<u8>>> {
+        prop::collection::vec(prop::collection::vec(any::<u8>(), 0..8), 0..8)
+    }
+
+    proptest! {
+        #[test]
+        fn prop_matches_reference(segments in segments(), steps in prop::collection::vec(0..10usize, 0..16)) {
+            let vbytes = segments.iter().map(|bytes| &bytes[..]).collect::<Vec<_>>();
+            let mut buf = VecBuf::new(&vbytes);
+            let mut reference = Reference::new(&vbytes);
+
+            for step in steps {
+                let cnt = step.min(reference.remaining());
+                buf.advance(cnt);
+                reference.advance(cnt);
+
+                prop_assert_eq!(buf.remaining(), reference.remaining());
+                prop_assert_eq!(buf.bytes().is_empty(), buf.remaining() == 0);
+                prop_assert!(reference.bytes().starts_with(buf.bytes()));
+            }
+
+            let b: &[u8] = &[0];
+            let mut dst: [&IoVec; 16] = [b.into(); 16];
+            let n = buf.bytes_vec(&mut dst[..]);
+            let gathered = dst[..n].iter().flat_map(|iovec| iovec.iter().cloned()).collect::<Vec<_>>();
+            prop_assert!(dst[..n].iter().all(|iovec| !iovec.is_empty()));
+            prop_assert_eq!(&gathered[..], reference.bytes());
+        }
+    }
 }
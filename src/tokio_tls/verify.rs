@@ -0,0 +1,198 @@
+//! Certificate verification shared by this crate's verifiers.
+//!
+//! rustls does not export its webpki-based verifier, so verifiers that add to
+//! the default checks rather than replace them repeat those checks here.
+//!
+//! rustls only lets a verifier fail with a `TLSError`, so the verifiers in
+//! this crate hand it a `TLSError::General` and record the `VerifyError`
+//! behind it on the current thread. rustls calls verifiers while it processes
+//! a session's records, which streams do through `process`, so the record
+//! belongs to that session; the stream picks it up once rustls returns the
+//! error, and fails with an `InvalidData` error wrapping the `VerifyError`
+//! rather than the `TLSError`.
+use super::pin::Pin;
+use rustls::{Certificate, RootCertStore, ServerCertVerified, TLSError};
+use std::cell::RefCell;
+use std::error;
+use std::fmt;
+use std::io;
+use std::time::SystemTime;
+use webpki::DNSNameRef;
+
+/// Why one of this crate's verifiers rejected a certificate chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// No pin of a `pin::PinSet` matches the chain.
+    PinMismatch,
+    /// A verification callback rejected the chain, for the given reason; see
+    /// `callback::Verdict::Reject`.
+    Rejected(String),
+    /// `host` presented a different public key from the one recorded in
+    /// `tofu::KnownHosts`.
+    KnownHostChanged {
+        host: String,
+        known: Pin,
+        presented: Pin,
+    },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VerifyError::PinMismatch => f.write_str("no certificate matches a pinned public key"),
+            VerifyError::KnownHostChanged {
+                ref host,
+                known,
+                presented,
+            } => write!(
+                f,
+                "the certificate of a known host changed: {} was sha256/{}, now sha256/{}",
+                host, known, presented
+            ),
+            VerifyError::Rejected(ref reason) => write!(
+                f,
+                "certificate rejected by the verification callback: {}",
+                reason
+            ),
+        }
+    }
+}
+
+impl error::Error for VerifyError {}
+
+thread_local! {
+    /// The failure of one of this crate's verifiers in the session whose
+    /// records are being processed on this thread.
+    static LAST_FAILURE: RefCell<Option<VerifyError>> = RefCell::new(None);
+}
+
+/// Records `err` as the reason for the `TLSError` this returns, for a
+/// verifier to fail with.
+pub(crate) fn fail(err: VerifyError) -> TLSError {
+    let tls_error = TLSError::General(err.to_string());
+    LAST_FAILURE.with(|last| *last.borrow_mut() = Some(err));
+    tls_error
+}
+
+/// Forgets the recorded failure. Verifiers call this when they start, so
+/// that what is recorded is about the chain being verified, and callbacks
+/// when they accept a chain a verifier failed.
+pub(crate) fn clear() {
+    LAST_FAILURE.with(|last| *last.borrow_mut() = None);
+}
+
+/// Runs `f`, which has rustls process the records of one session, so that a
+/// failure recorded by a verifier it calls is about that session. A failure
+/// left over from elsewhere on this thread is forgotten first, and one
+/// recorded during `f` is kept only if `f` fails.
+pub(crate) fn process<T, F: FnOnce() -> Result<T, TLSError>>(f: F) -> Result<T, TLSError> {
+    clear();
+    let result = f();
+    if result.is_ok() {
+        clear();
+    }
+    result
+}
+
+/// The `VerifyError` behind `err`, if one of this crate's verifiers failed
+/// with it in the session being processed on this thread.
+pub(crate) fn failure(err: &TLSError) -> Option<VerifyError> {
+    match *err {
+        TLSError::General(_) => LAST_FAILURE.with(|last| last.borrow().clone()),
+        _ => None,
+    }
+}
+
+/// Turns an error from `process_new_packets`, run through `process`, into an
+/// `InvalidData` error, wrapping the `VerifyError` behind it if there is one.
+pub(crate) fn io_error(err: TLSError) -> io::Error {
+    let err = peek_io_error(err);
+    clear();
+    err
+}
+
+/// Like `io_error`, but for an error rustls has yet to return, so the
+/// `VerifyError` stays recorded.
+pub(crate) fn peek_io_error(err: TLSError) -> io::Error {
+    match failure(&err) {
+        Some(failure) => io::Error::new(io::ErrorKind::InvalidData, failure),
+        None => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+/// The signature algorithms rustls accepts in certificates.
+static SUPPORTED_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// Checks that `presented` chains up to one of `roots` and that its leaf is
+/// valid for `dns_name`, as rustls's default server certificate verifier
+/// does.
+pub(crate) fn verify_server_cert(
+    roots: &RootCertStore,
+    presented: &[Certificate],
+    dns_name: DNSNameRef,
+) -> Result<ServerCertVerified, TLSError> {
+    let anchors: Vec<webpki::TrustAnchor> = roots
+        .roots
+        .iter()
+        .map(|root| root.to_trust_anchor())
+        .collect();
+    verify_with_anchors(&anchors, presented, dns_name)?;
+    Ok(ServerCertVerified::assertion())
+}
+
+/// Like `verify_server_cert`, but with the given trust anchors.
+pub(crate) fn verify_with_anchors(
+    anchors: &[webpki::TrustAnchor],
+    presented: &[Certificate],
+    dns_name: DNSNameRef,
+) -> Result<(), TLSError> {
+    let (leaf, chain) = match presented.split_first() {
+        Some((leaf, chain)) => (leaf, chain),
+        None => return Err(TLSError::NoCertificatesPresented),
+    };
+    let cert = webpki::EndEntityCert::from(&leaf.0).map_err(TLSError::WebPKIError)?;
+    let chain: Vec<&[u8]> = chain.iter().map(|cert| cert.0.as_ref()).collect();
+    let now =
+        webpki::Time::try_from(SystemTime::now()).map_err(|_| TLSError::FailedToGetCurrentTime)?;
+
+    cert.verify_is_valid_tls_server_cert(
+        SUPPORTED_SIG_ALGS,
+        &webpki::TLSServerTrustAnchors(anchors),
+        &chain,
+        now,
+    )
+    .map_err(TLSError::WebPKIError)?;
+    cert.verify_is_valid_for_dns_name(dns_name)
+        .map_err(TLSError::WebPKIError)
+}
+
+#[cfg(test)]
+mod test_verify {
+    use super::*;
+
+    #[test]
+    fn test_process_forgets_stale_failure() {
+        let stale = fail(VerifyError::PinMismatch);
+        assert_eq!(failure(&stale), Some(VerifyError::PinMismatch));
+
+        let err = process::<(), _>(|| Err(TLSError::General("unrelated".to_owned()))).unwrap_err();
+        assert_eq!(failure(&err), None);
+
+        let err = process::<(), _>(|| Err(fail(VerifyError::PinMismatch))).unwrap_err();
+        assert_eq!(failure(&err), Some(VerifyError::PinMismatch));
+        assert!(process(|| Ok(())).is_ok());
+        assert_eq!(failure(&err), None);
+    }
+}
@@ -0,0 +1,214 @@
+//! Just enough DER to read the validity period of an X.509 certificate,
+//! which webpki checks but does not expose.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SEQUENCE: u8 = 0x30;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+/// The `[0] EXPLICIT` tag of the optional version field.
+const VERSION: u8 = 0xa0;
+
+/// When the DER-encoded certificate `der` expires, or `None` if it can not
+/// be parsed.
+pub(crate) fn not_after(der: &[u8]) -> Option<SystemTime> {
+    // signature, issuer, validity
+    let rest = after_serial(der)?;
+    let rest = expect(SEQUENCE, rest)?.1;
+    let rest = expect(SEQUENCE, rest)?.1;
+    let (validity, _) = expect(SEQUENCE, rest)?;
+
+    let (_, _, validity) = tlv(validity)?;
+    let (tag, time, _) = tlv(validity)?;
+    parse_time(tag, time)
+}
+
+/// The fields of the TBSCertificate of `der` that follow the serial number.
+fn after_serial(der: &[u8]) -> Option<&[u8]> {
+    let (cert, _) = expect(SEQUENCE, der)?;
+    let (tbs, _) = expect(SEQUENCE, cert)?;
+
+    let (tag, _, rest) = tlv(tbs)?;
+    if tag == VERSION {
+        // The serial number follows the version.
+        return Some(tlv(rest)?.2);
+    }
+    Some(rest)
+}
+
+/// Splits one element off `input`, returning its tag, contents and the
+/// rest of the input.
+fn tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, input) = input.split_first()?;
+
+    let (len, input) = if first < 0x80 {
+        (usize::from(first), input)
+    } else {
+        let n = usize::from(first & 0x7f);
+        if n == 0 || n > 4 || input.len() < n {
+            return None;
+        }
+        let len = input[..n]
+            .iter()
+            .fold(0, |len, &byte| len << 8 | usize::from(byte));
+        (len, &input[n..])
+    };
+
+    if input.len() < len {
+        return None;
+    }
+    Some((tag, &input[..len], &input[len..]))
+}
+
+/// Like `tlv`, but only for an element with the given tag.
+fn expect(tag: u8, input: &[u8]) -> Option<(&[u8], &[u8])> {
+    match tlv(input)? {
+        (actual, contents, rest) if actual == tag => Some((contents, rest)),
+        _ => None,
+    }
+}
+
+/// Parses a UTCTime or GeneralizedTime, which certificates always give in
+/// UTC with whole seconds.
+fn parse_time(tag: u8, time: &[u8]) -> Option<SystemTime> {
+    let (year, rest) = match tag {
+        UTC_TIME => {
+            let (year, rest) = digits(time, 2)?;
+            // RFC 5280: two-digit years from 50 on are in the 20th century.
+            (if year < 50 { 2000 + year } else { 1900 + year }, rest)
+        }
+        GENERALIZED_TIME => digits(time, 4)?,
+        _ => return None,
+    };
+    let (month, rest) = digits(rest, 2)?;
+    let (day, rest) = digits(rest, 2)?;
+    let (hour, rest) = digits(rest, 2)?;
+    let (minute, rest) = digits(rest, 2)?;
+    let (second, rest) = digits(rest, 2)?;
+    if rest != b"Z" || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year as i64, month, day);
+    let secs = days * 86400 + i64::from(hour * 3600 + minute * 60 + second);
+    if secs >= 0 {
+        Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+    } else {
+        Some(UNIX_EPOCH - Duration::from_secs(-secs as u64))
+    }
+}
+
+/// Parses `n` ASCII digits off the front of `input`.
+fn digits(input: &[u8], n: usize) -> Option<(u32, &[u8])> {
+    if input.len() < n {
+        return None;
+    }
+    let mut value = 0;
+    for &c in &input[..n] {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        value = value * 10 + u32::from(c - b'0');
+    }
+    Some((value, &input[n..]))
+}
+
+/// Days since 1970-01-01 of the given date in the proleptic Gregorian
+/// calendar.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod test_x509 {
+    use super::*;
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+    use rustls::internal::pemfile::certs;
+    use std::io::{BufReader, Cursor};
+
+    const CHAIN: &[u8] = include_bytes!("../../tests/end.chain");
+
+    fn leaf() -> Vec<u8> {
+        certs(&mut BufReader::new(Cursor::new(CHAIN)))
+            .unwrap()
+            .remove(0)
+            .0
+    }
+
+    #[test]
+    fn test_not_after() {
+        let chain = certs(&mut BufReader::new(Cursor::new(CHAIN))).unwrap();
+        // notAfter=Oct 14 05:49:28 2046 GMT
+        assert_eq!(
+            not_after(&chain[0].0),
+            Some(UNIX_EPOCH + Duration::from_secs(2423108968))
+        );
+    }
+
+    #[test]
+    fn test_times() {
+        let utc = parse_time(UTC_TIME, b"491231235959Z").unwrap();
+        assert_eq!(utc, UNIX_EPOCH + Duration::from_secs(2524607999));
+        let utc = parse_time(UTC_TIME, b"700101000000Z").unwrap();
+        assert_eq!(utc, UNIX_EPOCH);
+        let generalized = parse_time(GENERALIZED_TIME, b"20000229120000Z").unwrap();
+        assert_eq!(generalized, UNIX_EPOCH + Duration::from_secs(951825600));
+
+        assert_eq!(parse_time(UTC_TIME, b"491231235959"), None);
+        assert_eq!(parse_time(UTC_TIME, b"491331235959Z"), None);
+        assert_eq!(parse_time(SEQUENCE, b"491231235959Z"), None);
+    }
+
+    #[test]
+    fn test_garbage() {
+        assert_eq!(not_after(b""), None);
+        assert_eq!(not_after(&[0x30, 0x82, 0xff]), None);
+        assert_eq!(not_after(&[0x30, 0x03, 0x30, 0x01, 0x00]), None);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_garbage(der in prop::collection::vec(any::<u8>(), 0..512)) {
+            not_after(&der);
+        }
+
+        #[test]
+        fn prop_truncated(cut in any::<Index>()) {
+            let der = leaf();
+            prop_assert_eq!(not_after(&der[..cut.index(der.len())]), None);
+        }
+
+        #[test]
+        fn prop_corrupted(pos in any::<Index>(), byte in any::<u8>()) {
+            let mut der = leaf();
+            let pos = pos.index(der.len());
+            der[pos] = byte;
+            not_after(&der);
+        }
+
+        #[test]
+        fn prop_utc_time(time in "[0-9]{0,14}Z?") {
+            // Two-digit years cover 1950 to 2049.
+            if let Some(time) = parse_time(UTC_TIME, time.as_bytes()) {
+                prop_assert!(time >= UNIX_EPOCH - Duration::from_secs(631152000));
+                prop_assert!(time <= UNIX_EPOCH + Duration::from_secs(2524608000));
+            }
+        }
+
+        #[test]
+        fn prop_generalized_time(time in "[0-9]{0,16}Z?") {
+            parse_time(GENERALIZED_TIME, time.as_bytes());
+        }
+    }
+}